@@ -0,0 +1,32 @@
+fn main() {
+    #[cfg(feature = "ffi")]
+    check_ffi_header();
+}
+
+/// Generates the C header for the functions exported by src/ffi.rs into OUT_DIR and warns if the
+/// checked-in include/cfspeedtest.h differs from it
+#[cfg(feature = "ffi")]
+fn check_ffi_header() {
+    use std::{env, fs, path::PathBuf};
+
+    println!("cargo:rerun-if-changed=src/ffi.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    println!("cargo:rerun-if-changed=include/cfspeedtest.h");
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let generated = PathBuf::from(env::var("OUT_DIR").unwrap()).join("cfspeedtest.h");
+    let config = cbindgen::Config::from_file(manifest_dir.join("cbindgen.toml"))
+        .expect("invalid cbindgen.toml");
+    cbindgen::Builder::new()
+        .with_config(config)
+        .with_src(manifest_dir.join("src/ffi.rs"))
+        .generate()
+        .expect("failed to generate the C header")
+        .write_to_file(&generated);
+    let checked_in = fs::read(manifest_dir.join("include/cfspeedtest.h")).unwrap_or_default();
+    if fs::read(&generated).unwrap_or_default() != checked_in {
+        println!(
+            "cargo:warning=include/cfspeedtest.h is out of date, update it with: cp {} include/cfspeedtest.h",
+            generated.display()
+        );
+    }
+}
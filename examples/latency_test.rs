@@ -1,19 +1,21 @@
 use cfspeedtest::speedtest::run_latency_test;
 use cfspeedtest::OutputFormat;
+use cfspeedtest::SpeedTestCLIOptions;
 
 fn main() {
     println!("Testing latency");
 
-    let (latency_results, avg_latency) = run_latency_test(
-        &reqwest::blocking::Client::new(),
-        25,
-        OutputFormat::None, // don't write to stdout while running the test
-    );
+    let options = SpeedTestCLIOptions {
+        nr_latency_tests: 25,
+        output_format: OutputFormat::None, // don't write to stdout while running the test
+        ..Default::default()
+    };
+    let latency = run_latency_test(&reqwest::blocking::Client::new(), &options);
 
-    println!("average latancy in ms: {avg_latency}");
+    println!("average latancy in ms: {}", latency.avg_latency);
 
     println!("all latency test results");
-    for latency_result in latency_results {
-        println!("latency in ms: {latency_result}");
+    for sample in latency.measurements {
+        println!("latency in ms: {}", sample.latency_ms);
     }
 }
@@ -0,0 +1,31 @@
+use cfspeedtest::mock::{MockBackend, MockConfig};
+use cfspeedtest::speedtest::speed_test;
+use cfspeedtest::speedtest::PayloadSize;
+use cfspeedtest::OutputFormat;
+use cfspeedtest::SpeedTestCLIOptions;
+
+fn main() {
+    // simulate a 200/20 mbit link, no network access needed
+    let backend = MockBackend::start(MockConfig {
+        download_mbit: 200.0,
+        upload_mbit: 20.0,
+        ..Default::default()
+    })
+    .expect("failed to start the mock backend");
+
+    let mut options = SpeedTestCLIOptions {
+        output_format: OutputFormat::None, // don't write to stdout
+        nr_tests: 4,
+        nr_latency_tests: 10,
+        max_payload_size: PayloadSize::M1,
+        ..Default::default()
+    };
+    backend.apply(&mut options);
+
+    let result = speed_test(reqwest::blocking::Client::new(), options);
+    println!("latency in ms: {:.2}", result.latency.avg_latency);
+    result
+        .measurements
+        .iter()
+        .for_each(|measurement| println!("{measurement}"));
+}
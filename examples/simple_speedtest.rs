@@ -16,10 +16,12 @@ fn main() {
         nr_latency_tests: 20,
         max_payload_size: PayloadSize::M10,
         disable_dynamic_max_payload_size: false,
+        ..Default::default()
     };
 
-    let measurements = speed_test(reqwest::blocking::Client::new(), options);
-    measurements
+    let result = speed_test(reqwest::blocking::Client::new(), options);
+    result
+        .measurements
         .iter()
         .for_each(|measurement| println!("{measurement}"));
 }
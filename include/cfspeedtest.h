@@ -0,0 +1,51 @@
+/* C API of cfspeedtest, built with:
+ * cargo rustc --release --lib --features ffi --crate-type cdylib
+ *
+ * Generated by cbindgen from src/ffi.rs, don't edit by hand. */
+
+#ifndef CFSPEEDTEST_H
+#define CFSPEEDTEST_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/*
+ Receives every progress event as a JSON object, the string is only valid during the call.
+ Called from the thread running the test.
+ */
+typedef void (*cfspeedtest_event_callback)(const char *event_json, void *user_data);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/*
+ Runs one speed test configured by `config_json`, an object with the fields accepted by
+ `POST /run` of the HTTP API, or NULL for the defaults. `callback` may be NULL.
+
+ Returns `{"type": "result", "result": {…}}` or `{"type": "failed", "error": "…"}` as a JSON
+ string that has to be released with `cfspeedtest_free_string`.
+
+ # Safety
+
+ `config_json` has to be NULL or a valid NUL terminated string.
+ */
+char *cfspeedtest_run(const char *config_json, cfspeedtest_event_callback callback, void *user_data);
+
+/*
+ Releases a string returned by `cfspeedtest_run`
+
+ # Safety
+
+ `s` has to be NULL or a pointer returned by `cfspeedtest_run` that wasn't released yet.
+ */
+void cfspeedtest_free_string(char *s);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* CFSPEEDTEST_H */
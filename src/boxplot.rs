@@ -46,3 +46,21 @@ pub(crate) fn render_plot(minima: f64, q1: f64, median: f64, q3: f64, maxima: f6
 
     plot
 }
+
+/// One block character per sample scaled between the smallest and largest sample, in the order they were taken
+pub(crate) fn render_sparkline(values: &[f64]) -> String {
+    const BLOCKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    values
+        .iter()
+        .map(|value| {
+            if max > min {
+                let level = (value - min) / (max - min) * (BLOCKS.len() - 1) as f64;
+                BLOCKS[level.round() as usize]
+            } else {
+                BLOCKS[BLOCKS.len() / 2]
+            }
+        })
+        .collect()
+}
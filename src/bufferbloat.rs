@@ -0,0 +1,134 @@
+use crate::retry::RetryPolicy;
+use crate::speedtest::{measure_latency, Endpoint};
+use reqwest::blocking::Client;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const PROBE_INTERVAL: Duration = Duration::from_millis(100);
+/// Idle latencies below this are rounded up, otherwise a few ms under load already look bad
+const MIN_IDLE_MS: f64 = 10.0;
+
+/// Takes latency samples in the background while a transfer phase is running
+pub struct LatencyProbe {
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<Vec<f64>>,
+}
+
+impl LatencyProbe {
+    pub fn start(client: &Client, endpoint: &Endpoint) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let (client, endpoint, stopped) = (client.clone(), endpoint.clone(), stop.clone());
+        let handle = thread::spawn(move || {
+            let mut latencies = Vec::new();
+            while !stopped.load(Ordering::SeqCst) {
+                if let Ok((sample, _)) =
+                    measure_latency(&client, &endpoint, &RetryPolicy::none(), None)
+                {
+                    if !sample.suspect {
+                        latencies.push(sample.latency_ms);
+                    }
+                }
+                thread::sleep(PROBE_INTERVAL);
+            }
+            latencies
+        });
+        Self { stop, handle }
+    }
+
+    /// Stops probing and returns the latencies measured so far
+    pub fn finish(self) -> Vec<f64> {
+        self.stop.store(true, Ordering::SeqCst);
+        self.handle.join().unwrap_or_default()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Grade {
+    A,
+    B,
+    C,
+    D,
+    F,
+}
+
+impl Grade {
+    /// Grades how many times higher the latency under load is than the idle latency
+    fn from_ratio(ratio: f64) -> Self {
+        match ratio {
+            r if r < 1.5 => Self::A,
+            r if r < 2.5 => Self::B,
+            r if r < 4.0 => Self::C,
+            r if r < 8.0 => Self::D,
+            _ => Self::F,
+        }
+    }
+}
+
+impl Display for Grade {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // pad so the grade lines up in tables
+        f.pad(&format!("{self:?}"))
+    }
+}
+
+/// Increase of the latency while the link is saturated, graded by the worse direction
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Bufferbloat {
+    pub grade: Grade,
+    pub idle_ms: f64,
+    /// Median latency during the download phases
+    pub download_ms: Option<f64>,
+    /// Median latency during the upload phases
+    pub upload_ms: Option<f64>,
+}
+
+impl Bufferbloat {
+    /// `None` without an idle latency or without enough samples under load
+    pub fn grade(idle_ms: f64, download: Vec<f64>, upload: Vec<f64>) -> Option<Self> {
+        if !idle_ms.is_finite() {
+            return None;
+        }
+        let (download_ms, upload_ms) = (median(download), median(upload));
+        let loaded_ms = download_ms.into_iter().chain(upload_ms).reduce(f64::max)?;
+        Some(Self {
+            grade: Grade::from_ratio(loaded_ms / idle_ms.max(MIN_IDLE_MS)),
+            idle_ms,
+            download_ms,
+            upload_ms,
+        })
+    }
+}
+
+impl Display for Bufferbloat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let loaded =
+            |latency: Option<f64>| latency.map_or("-".to_string(), |l| format!("{l:.1} ms"));
+        write!(
+            f,
+            "{} (idle {:.1} ms, loaded {} down / {} up)",
+            self.grade,
+            self.idle_ms,
+            loaded(self.download_ms),
+            loaded(self.upload_ms)
+        )
+    }
+}
+
+/// Short phases may only get a few samples, so unlike `calc_stats` this works with any number
+#[allow(unknown_lints, clippy::manual_is_multiple_of)]
+fn median(mut latencies: Vec<f64>) -> Option<f64> {
+    if latencies.is_empty() {
+        return None;
+    }
+    latencies.sort_by(f64::total_cmp);
+    let middle = latencies.len() / 2;
+    Some(if latencies.len() % 2 == 0 {
+        (latencies[middle - 1] + latencies[middle]) / 2.0
+    } else {
+        latencies[middle]
+    })
+}
@@ -0,0 +1,93 @@
+use crate::measurements::Measurement;
+use crate::speedtest::TestType;
+use indexmap::IndexMap;
+use serde::Serialize;
+use std::fmt::Display;
+
+/// Link capacity extrapolated from the averages of the payload sizes.
+///
+/// Small payloads are dominated by per-request overhead, so the throughput follows a saturation
+/// curve `mbit = capacity * size / (size + k)`. Fitting `1 / mbit` linearly against `1 / size`
+/// gives `1 / capacity` as the intercept.
+#[derive(Clone, Debug, Serialize)]
+pub struct Capacity {
+    pub test_type: TestType,
+    pub mbit: f64,
+    /// Standard error of the estimate
+    pub uncertainty_mbit: f64,
+    /// Share of the capacity reached by the largest payload size
+    pub saturation_percent: f64,
+}
+
+impl Capacity {
+    /// `None` with fewer than three payload sizes or if the averages don't level off
+    pub fn estimate(measurements: &[Measurement], test_type: TestType) -> Option<Self> {
+        let mut by_size: IndexMap<usize, Vec<f64>> = IndexMap::new();
+        for m in measurements
+            .iter()
+            .filter(|m| m.test_type == test_type && m.is_valid())
+        {
+            by_size.entry(m.payload_size).or_default().push(m.mbit);
+        }
+        if by_size.len() < 3 {
+            return None;
+        }
+        let points: Vec<(f64, f64)> = by_size
+            .iter()
+            .map(|(size, mbits)| {
+                let avg = mbits.iter().sum::<f64>() / mbits.len() as f64;
+                (1.0 / *size as f64, 1.0 / avg)
+            })
+            .collect();
+        let (intercept, intercept_error) = fit_intercept(&points)?;
+        if intercept <= 0.0 {
+            return None;
+        }
+        let mbit = 1.0 / intercept;
+        let (_, largest) = by_size.iter().max_by_key(|(size, _)| **size)?;
+        let largest_avg = largest.iter().sum::<f64>() / largest.len() as f64;
+        Some(Self {
+            test_type,
+            mbit,
+            uncertainty_mbit: intercept_error / intercept.powi(2),
+            saturation_percent: (largest_avg / mbit * 100.0).min(100.0),
+        })
+    }
+}
+
+impl Display for Capacity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Estimated {} capacity {:.2} ± {:.2} mbit/s, the largest payload reaches {:.0}% of it",
+            format!("{:?}", self.test_type).to_lowercase(),
+            self.mbit,
+            self.uncertainty_mbit,
+            self.saturation_percent
+        )
+    }
+}
+
+/// Least squares line through `points`, returns the intercept and its standard error
+fn fit_intercept(points: &[(f64, f64)]) -> Option<(f64, f64)> {
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let sxx: f64 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+    if sxx == 0.0 {
+        return None;
+    }
+    let sxy: f64 = points
+        .iter()
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum();
+    let slope = sxy / sxx;
+    let intercept = mean_y - slope * mean_x;
+    let residuals: f64 = points
+        .iter()
+        .map(|(x, y)| (y - intercept - slope * x).powi(2))
+        .sum();
+    let variance = residuals / (n - 2.0);
+    let intercept_error = (variance * (1.0 / n + mean_x.powi(2) / sxx)).sqrt();
+    Some((intercept, intercept_error))
+}
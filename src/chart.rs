@@ -0,0 +1,157 @@
+use crate::speedtest::{SpeedTestResult, TestType};
+use std::fmt::Write;
+use std::path::Path;
+
+const WIDTH: f64 = 800.0;
+const PANEL_HEIGHT: f64 = 260.0;
+const MARGIN_LEFT: f64 = 70.0;
+const MARGIN_RIGHT: f64 = 20.0;
+const MARGIN_TOP: f64 = 40.0;
+const MARGIN_BOTTOM: f64 = 40.0;
+const GRID_LINES: usize = 5;
+
+const DOWNLOAD_COLOR: &str = "#1f77b4";
+const UPLOAD_COLOR: &str = "#ff7f0e";
+const LATENCY_COLOR: &str = "#2ca02c";
+
+struct Series<'a> {
+    name: &'a str,
+    color: &'a str,
+    values: Vec<f64>,
+}
+
+/// Writes throughput and latency charts of `result` as an SVG image.
+/// Samples are plotted in the order they were taken, invalid samples are left out.
+pub fn write_chart(path: &Path, result: &SpeedTestResult) -> Result<(), String> {
+    let throughput = [
+        (TestType::Download, "Download", DOWNLOAD_COLOR),
+        (TestType::Upload, "Upload", UPLOAD_COLOR),
+    ]
+    .map(|(test_type, name, color)| Series {
+        name,
+        color,
+        values: result
+            .measurements
+            .iter()
+            .filter(|m| m.test_type == test_type && m.is_valid())
+            .map(|m| m.mbit)
+            .collect(),
+    });
+    let latency = [Series {
+        name: "Latency",
+        color: LATENCY_COLOR,
+        values: result.latency.latencies(),
+    }];
+
+    let mut svg = String::new();
+    writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{WIDTH}" height="{}" font-family="sans-serif" font-size="12">"#,
+        2.0 * PANEL_HEIGHT
+    )
+    .unwrap();
+    writeln!(svg, r#"<rect width="100%" height="100%" fill="white"/>"#).unwrap();
+    render_panel(&mut svg, 0.0, "Throughput", "Mbit/s", &throughput);
+    render_panel(&mut svg, PANEL_HEIGHT, "Latency", "ms", &latency);
+    svg.push_str("</svg>\n");
+    std::fs::write(path, svg).map_err(|e| format!("failed to write {}: {e}", path.display()))
+}
+
+fn render_panel(svg: &mut String, top: f64, title: &str, unit: &str, series: &[Series]) {
+    let plot_width = WIDTH - MARGIN_LEFT - MARGIN_RIGHT;
+    let plot_height = PANEL_HEIGHT - MARGIN_TOP - MARGIN_BOTTOM;
+    let plot_top = top + MARGIN_TOP;
+    let plot_bottom = plot_top + plot_height;
+    let max_value = series
+        .iter()
+        .flat_map(|s| s.values.iter().copied())
+        .fold(0.0, f64::max);
+    let max_value = if max_value > 0.0 {
+        max_value * 1.1
+    } else {
+        1.0
+    };
+    let max_samples = series.iter().map(|s| s.values.len()).max().unwrap_or(0);
+
+    writeln!(
+        svg,
+        r#"<text x="{MARGIN_LEFT}" y="{}" font-size="14" font-weight="bold">{title}</text>"#,
+        top + MARGIN_TOP - 15.0
+    )
+    .unwrap();
+    for i in 0..=GRID_LINES {
+        let value = max_value * i as f64 / GRID_LINES as f64;
+        let y = plot_bottom - plot_height * i as f64 / GRID_LINES as f64;
+        writeln!(
+            svg,
+            r##"<line x1="{MARGIN_LEFT}" y1="{y:.1}" x2="{:.1}" y2="{y:.1}" stroke="#ddd"/>"##,
+            MARGIN_LEFT + plot_width
+        )
+        .unwrap();
+        writeln!(
+            svg,
+            r#"<text x="{:.1}" y="{:.1}" text-anchor="end">{value:.1}</text>"#,
+            MARGIN_LEFT - 8.0,
+            y + 4.0
+        )
+        .unwrap();
+    }
+    writeln!(
+        svg,
+        r#"<text x="15" y="{:.1}" transform="rotate(-90 15 {:.1})" text-anchor="middle">{unit}</text>"#,
+        plot_top + plot_height / 2.0,
+        plot_top + plot_height / 2.0
+    )
+    .unwrap();
+    writeln!(
+        svg,
+        r#"<text x="{:.1}" y="{:.1}" text-anchor="middle">sample</text>"#,
+        MARGIN_LEFT + plot_width / 2.0,
+        plot_bottom + 30.0
+    )
+    .unwrap();
+
+    let x = |i: usize| {
+        if max_samples > 1 {
+            MARGIN_LEFT + plot_width * i as f64 / (max_samples - 1) as f64
+        } else {
+            MARGIN_LEFT + plot_width / 2.0
+        }
+    };
+    let y = |value: f64| plot_bottom - plot_height * value / max_value;
+    for (n, s) in series.iter().filter(|s| !s.values.is_empty()).enumerate() {
+        let points: Vec<String> = s
+            .values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| format!("{:.1},{:.1}", x(i), y(*value)))
+            .collect();
+        writeln!(
+            svg,
+            r#"<polyline points="{}" fill="none" stroke="{}" stroke-width="2"/>"#,
+            points.join(" "),
+            s.color
+        )
+        .unwrap();
+        for point in &points {
+            let (cx, cy) = point.split_once(',').unwrap();
+            writeln!(
+                svg,
+                r#"<circle cx="{cx}" cy="{cy}" r="2.5" fill="{}"/>"#,
+                s.color
+            )
+            .unwrap();
+        }
+        let legend_x = WIDTH - MARGIN_RIGHT - 100.0 * (n + 1) as f64;
+        writeln!(
+            svg,
+            r#"<rect x="{legend_x:.1}" y="{:.1}" width="12" height="12" fill="{}"/><text x="{:.1}" y="{:.1}">{}</text>"#,
+            top + MARGIN_TOP - 26.0,
+            s.color,
+            legend_x + 16.0,
+            top + MARGIN_TOP - 16.0,
+            s.name
+        )
+        .unwrap();
+    }
+}
@@ -0,0 +1,141 @@
+use crate::history::{HistoryRecord, HistoryStore};
+use crate::server::{error_response, json_response};
+use chrono::{DateTime, Utc};
+use indexmap::IndexMap;
+use log;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use tiny_http::{Method, Request, Response, Server};
+
+pub const DEFAULT_COLLECT_ADDR: &str = "0.0.0.0:9797";
+/// Meta key naming the instance a pushed result came from, set to the sender's IP if missing
+const INSTANCE_KEY: &str = "instance";
+
+/// Latest state and averages of the results pushed by one instance
+#[derive(Serialize)]
+struct InstanceSummary {
+    instance: String,
+    runs: usize,
+    last_run: DateTime<Utc>,
+    download_mbit: Option<f64>,
+    upload_mbit: Option<f64>,
+    latency_ms: Option<f64>,
+}
+
+/// Accepts results pushed by the webhooks of other instances on `addr` and stores them in the
+/// history, until the process is stopped
+pub fn run_collector(history: HistoryStore, addr: SocketAddr) -> Result<(), String> {
+    let server = Server::http(addr).map_err(|e| format!("Failed to listen on {addr}: {e}"))?;
+    println!(
+        "Collecting results on http://{addr}/results into {}",
+        history.path().display()
+    );
+    // appends are serialized so concurrent pushes don't get the same id
+    let history = Mutex::new(history);
+    for request in server.incoming_requests() {
+        handle_request(&history, request);
+    }
+    Ok(())
+}
+
+fn handle_request(history: &Mutex<HistoryStore>, mut request: Request) {
+    let method = request.method().clone();
+    let path = request
+        .url()
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    log::debug!("{method} {path}");
+    let response = match (&method, path.trim_matches('/')) {
+        (Method::Post, "results") => {
+            let mut body = String::new();
+            let sender = request.remote_addr().map(|addr| addr.ip().to_string());
+            match request.as_reader().read_to_string(&mut body) {
+                Ok(_) => store(history, &body, sender),
+                Err(e) => error_response(400, &e.to_string()),
+            }
+        }
+        (Method::Get, "results") => match history.lock().unwrap().load() {
+            Ok(records) => json_response(200, &records),
+            Err(e) => error_response(500, &e.to_string()),
+        },
+        (Method::Get, "summary") => match history.lock().unwrap().load() {
+            Ok(records) => json_response(200, &summarize(&records)),
+            Err(e) => error_response(500, &e.to_string()),
+        },
+        _ => error_response(404, "not found"),
+    };
+    if let Err(e) = request.respond(response) {
+        log::warn!("failed to send response: {e}");
+    }
+}
+
+/// Stores a result in the body format of the default webhook, failed runs are only logged
+fn store(
+    history: &Mutex<HistoryStore>,
+    body: &str,
+    sender: Option<String>,
+) -> Response<Cursor<Vec<u8>>> {
+    let value: Value = match serde_json::from_str(body) {
+        Ok(value) => value,
+        Err(e) => return error_response(400, &format!("invalid result: {e}")),
+    };
+    if value["status"] == "failed" {
+        log::info!(
+            "run of {} failed: {}",
+            sender.as_deref().unwrap_or("unknown"),
+            value["error"]
+        );
+        return json_response(200, &json!({ "stored": false }));
+    }
+    let mut record: HistoryRecord = match serde_json::from_value(value) {
+        Ok(record) => record,
+        Err(e) => return error_response(400, &format!("invalid result: {e}")),
+    };
+    if let Some(sender) = sender {
+        record
+            .meta
+            .entry(INSTANCE_KEY.to_string())
+            .or_insert(sender);
+    }
+    match history.lock().unwrap().append(vec![record]) {
+        Ok(stored) => json_response(201, &json!({ "stored": true, "id": stored[0].id })),
+        Err(e) => error_response(500, &format!("failed to store result: {e}")),
+    }
+}
+
+fn summarize(records: &[HistoryRecord]) -> Vec<InstanceSummary> {
+    let mut instances: IndexMap<String, Vec<&HistoryRecord>> = IndexMap::new();
+    for record in records {
+        let instance = record
+            .meta
+            .get(INSTANCE_KEY)
+            .cloned()
+            .unwrap_or_else(|| "local".to_string());
+        instances.entry(instance).or_default().push(record);
+    }
+    instances
+        .into_iter()
+        .map(|(instance, records)| InstanceSummary {
+            runs: records.len(),
+            last_run: records
+                .iter()
+                .map(|r| r.timestamp)
+                .max()
+                .unwrap_or_default(),
+            download_mbit: average(records.iter().map(|r| r.download_mbit)),
+            upload_mbit: average(records.iter().map(|r| r.upload_mbit)),
+            latency_ms: average(records.iter().map(|r| r.latency_ms)),
+            instance,
+        })
+        .collect()
+}
+
+fn average(values: impl Iterator<Item = Option<f64>>) -> Option<f64> {
+    let values: Vec<f64> = values.flatten().collect();
+    (!values.is_empty()).then(|| values.iter().sum::<f64>() / values.len() as f64)
+}
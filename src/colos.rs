@@ -0,0 +1,355 @@
+use crate::metadata::{fetch_metadata, Metadata};
+use crate::retry::RetryPolicy;
+use crate::speedtest::test_latency;
+use crate::ListColosOptions;
+use reqwest::blocking::Client;
+use serde::Serialize;
+
+/// Distance light travels through fiber in one ms
+const FIBER_KM_PER_MS: f64 = 200.0;
+
+/// Location of a Cloudflare colo, identified by the IATA code of the nearest airport
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct ColoLocation {
+    pub iata: &'static str,
+    pub city: &'static str,
+    /// ISO 3166-1 alpha-2 country code
+    pub country: &'static str,
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+const fn colo(
+    iata: &'static str,
+    city: &'static str,
+    country: &'static str,
+    latitude: f64,
+    longitude: f64,
+) -> ColoLocation {
+    ColoLocation {
+        iata,
+        city,
+        country,
+        latitude,
+        longitude,
+    }
+}
+
+/// Bundled locations of the larger Cloudflare colos, coordinates are the ones of the airport
+pub const COLOS: &[ColoLocation] = &[
+    colo("ACC", "Accra", "GH", 5.61, -0.17),
+    colo("ADL", "Adelaide", "AU", -34.95, 138.53),
+    colo("AKL", "Auckland", "NZ", -37.01, 174.79),
+    colo("ALA", "Almaty", "KZ", 43.35, 77.04),
+    colo("ALG", "Algiers", "DZ", 36.69, 3.22),
+    colo("AMM", "Amman", "JO", 31.72, 35.99),
+    colo("AMS", "Amsterdam", "NL", 52.31, 4.76),
+    colo("ANC", "Anchorage", "US", 61.17, -149.99),
+    colo("ARN", "Stockholm", "SE", 59.65, 17.92),
+    colo("ATH", "Athens", "GR", 37.94, 23.94),
+    colo("ATL", "Atlanta", "US", 33.64, -84.43),
+    colo("BAH", "Manama", "BH", 26.27, 50.63),
+    colo("BCN", "Barcelona", "ES", 41.30, 2.08),
+    colo("BEG", "Belgrade", "RS", 44.82, 20.31),
+    colo("BER", "Berlin", "DE", 52.37, 13.52),
+    colo("BKK", "Bangkok", "TH", 13.69, 100.75),
+    colo("BLR", "Bangalore", "IN", 13.20, 77.71),
+    colo("BNA", "Nashville", "US", 36.12, -86.68),
+    colo("BNE", "Brisbane", "AU", -27.38, 153.12),
+    colo("BOG", "Bogota", "CO", 4.70, -74.15),
+    colo("BOM", "Mumbai", "IN", 19.09, 72.87),
+    colo("BOS", "Boston", "US", 42.36, -71.01),
+    colo("BRU", "Brussels", "BE", 50.90, 4.48),
+    colo("BUD", "Budapest", "HU", 47.44, 19.26),
+    colo("CAI", "Cairo", "EG", 30.12, 31.41),
+    colo("CBR", "Canberra", "AU", -35.31, 149.19),
+    colo("CCU", "Kolkata", "IN", 22.65, 88.45),
+    colo("CDG", "Paris", "FR", 49.01, 2.55),
+    colo("CEB", "Cebu", "PH", 10.31, 123.98),
+    colo("CGK", "Jakarta", "ID", -6.13, 106.66),
+    colo("CHC", "Christchurch", "NZ", -43.49, 172.53),
+    colo("CLT", "Charlotte", "US", 35.21, -80.94),
+    colo("CMB", "Colombo", "LK", 7.18, 79.88),
+    colo("CMH", "Columbus", "US", 40.00, -82.89),
+    colo("CMN", "Casablanca", "MA", 33.37, -7.59),
+    colo("CNX", "Chiang Mai", "TH", 18.77, 98.96),
+    colo("CPH", "Copenhagen", "DK", 55.62, 12.66),
+    colo("CPT", "Cape Town", "ZA", -33.97, 18.60),
+    colo("CWB", "Curitiba", "BR", -25.53, -49.18),
+    colo("DAC", "Dhaka", "BD", 23.84, 90.40),
+    colo("DEL", "New Delhi", "IN", 28.56, 77.10),
+    colo("DEN", "Denver", "US", 39.86, -104.67),
+    colo("DFW", "Dallas", "US", 32.90, -97.04),
+    colo("DKR", "Dakar", "SN", 14.74, -17.49),
+    colo("DOH", "Doha", "QA", 25.27, 51.61),
+    colo("DPS", "Denpasar", "ID", -8.75, 115.17),
+    colo("DTW", "Detroit", "US", 42.21, -83.35),
+    colo("DUB", "Dublin", "IE", 53.42, -6.27),
+    colo("DUR", "Durban", "ZA", -29.61, 31.12),
+    colo("DUS", "Dusseldorf", "DE", 51.29, 6.77),
+    colo("DXB", "Dubai", "AE", 25.25, 55.36),
+    colo("EDI", "Edinburgh", "GB", 55.95, -3.37),
+    colo("EVN", "Yerevan", "AM", 40.15, 44.40),
+    colo("EWR", "Newark", "US", 40.69, -74.17),
+    colo("EZE", "Buenos Aires", "AR", -34.82, -58.54),
+    colo("FCO", "Rome", "IT", 41.80, 12.25),
+    colo("FOR", "Fortaleza", "BR", -3.78, -38.53),
+    colo("FRA", "Frankfurt", "DE", 50.04, 8.56),
+    colo("FUK", "Fukuoka", "JP", 33.59, 130.45),
+    colo("GIG", "Rio de Janeiro", "BR", -22.81, -43.25),
+    colo("GRU", "Sao Paulo", "BR", -23.43, -46.47),
+    colo("GUM", "Hagatna", "GU", 13.48, 144.80),
+    colo("GVA", "Geneva", "CH", 46.24, 6.11),
+    colo("GYD", "Baku", "AZ", 40.47, 50.05),
+    colo("HAM", "Hamburg", "DE", 53.63, 9.99),
+    colo("HAN", "Hanoi", "VN", 21.22, 105.81),
+    colo("HEL", "Helsinki", "FI", 60.32, 24.96),
+    colo("HKG", "Hong Kong", "HK", 22.31, 113.91),
+    colo("HNL", "Honolulu", "US", 21.32, -157.92),
+    colo("HYD", "Hyderabad", "IN", 17.24, 78.43),
+    colo("IAD", "Ashburn", "US", 38.95, -77.46),
+    colo("IAH", "Houston", "US", 29.98, -95.34),
+    colo("ICN", "Seoul", "KR", 37.46, 126.44),
+    colo("IND", "Indianapolis", "US", 39.72, -86.29),
+    colo("ISB", "Islamabad", "PK", 33.55, 72.83),
+    colo("IST", "Istanbul", "TR", 41.26, 28.74),
+    colo("JAX", "Jacksonville", "US", 30.49, -81.69),
+    colo("JED", "Jeddah", "SA", 21.68, 39.16),
+    colo("JNB", "Johannesburg", "ZA", -26.14, 28.24),
+    colo("KBP", "Kyiv", "UA", 50.35, 30.89),
+    colo("KEF", "Reykjavik", "IS", 63.99, -22.62),
+    colo("KGL", "Kigali", "RW", -1.97, 30.14),
+    colo("KHI", "Karachi", "PK", 24.91, 67.16),
+    colo("KIX", "Osaka", "JP", 34.43, 135.24),
+    colo("KTM", "Kathmandu", "NP", 27.70, 85.36),
+    colo("KUL", "Kuala Lumpur", "MY", 2.75, 101.71),
+    colo("KWI", "Kuwait City", "KW", 29.24, 47.97),
+    colo("LAS", "Las Vegas", "US", 36.08, -115.15),
+    colo("LAX", "Los Angeles", "US", 33.94, -118.41),
+    colo("LCA", "Larnaca", "CY", 34.88, 33.62),
+    colo("LHE", "Lahore", "PK", 31.52, 74.40),
+    colo("LHR", "London", "GB", 51.47, -0.45),
+    colo("LIM", "Lima", "PE", -12.02, -77.11),
+    colo("LIS", "Lisbon", "PT", 38.77, -9.13),
+    colo("LOS", "Lagos", "NG", 6.58, 3.32),
+    colo("LUX", "Luxembourg", "LU", 49.63, 6.21),
+    colo("LYS", "Lyon", "FR", 45.73, 5.08),
+    colo("MAA", "Chennai", "IN", 12.99, 80.17),
+    colo("MAD", "Madrid", "ES", 40.49, -3.57),
+    colo("MAN", "Manchester", "GB", 53.35, -2.28),
+    colo("MBA", "Mombasa", "KE", -4.03, 39.59),
+    colo("MCI", "Kansas City", "US", 39.30, -94.71),
+    colo("MCT", "Muscat", "OM", 23.59, 58.28),
+    colo("MEL", "Melbourne", "AU", -37.67, 144.84),
+    colo("MEX", "Mexico City", "MX", 19.44, -99.07),
+    colo("MFE", "McAllen", "US", 26.18, -98.24),
+    colo("MIA", "Miami", "US", 25.79, -80.29),
+    colo("MNL", "Manila", "PH", 14.51, 121.02),
+    colo("MRS", "Marseille", "FR", 43.44, 5.22),
+    colo("MSP", "Minneapolis", "US", 44.88, -93.22),
+    colo("MUC", "Munich", "DE", 48.35, 11.79),
+    colo("MXP", "Milan", "IT", 45.63, 8.72),
+    colo("NBO", "Nairobi", "KE", -1.32, 36.93),
+    colo("NOU", "Noumea", "NC", -22.01, 166.21),
+    colo("NRT", "Tokyo", "JP", 35.77, 140.39),
+    colo("ORD", "Chicago", "US", 41.98, -87.90),
+    colo("OSL", "Oslo", "NO", 60.19, 11.10),
+    colo("OTP", "Bucharest", "RO", 44.57, 26.09),
+    colo("PDX", "Portland", "US", 45.59, -122.60),
+    colo("PER", "Perth", "AU", -31.94, 115.97),
+    colo("PHL", "Philadelphia", "US", 39.87, -75.24),
+    colo("PHX", "Phoenix", "US", 33.43, -112.01),
+    colo("PIT", "Pittsburgh", "US", 40.49, -80.23),
+    colo("PMO", "Palermo", "IT", 38.18, 13.09),
+    colo("PNH", "Phnom Penh", "KH", 11.55, 104.84),
+    colo("POA", "Porto Alegre", "BR", -29.99, -51.17),
+    colo("PPT", "Papeete", "PF", -17.55, -149.61),
+    colo("PRG", "Prague", "CZ", 50.10, 14.26),
+    colo("PTY", "Panama City", "PA", 9.07, -79.38),
+    colo("QRO", "Queretaro", "MX", 20.62, -100.19),
+    colo("RDU", "Raleigh", "US", 35.88, -78.79),
+    colo("RGN", "Yangon", "MM", 16.91, 96.13),
+    colo("RIX", "Riga", "LV", 56.92, 23.97),
+    colo("RUH", "Riyadh", "SA", 24.96, 46.70),
+    colo("SAN", "San Diego", "US", 32.73, -117.19),
+    colo("SCL", "Santiago", "CL", -33.39, -70.79),
+    colo("SEA", "Seattle", "US", 47.45, -122.31),
+    colo("SFO", "San Francisco", "US", 37.62, -122.38),
+    colo("SGN", "Ho Chi Minh City", "VN", 10.82, 106.66),
+    colo("SIN", "Singapore", "SG", 1.36, 103.99),
+    colo("SJC", "San Jose", "US", 37.36, -121.93),
+    colo("SKG", "Thessaloniki", "GR", 40.52, 22.97),
+    colo("SLC", "Salt Lake City", "US", 40.79, -111.98),
+    colo("SMF", "Sacramento", "US", 38.70, -121.59),
+    colo("SOF", "Sofia", "BG", 42.70, 23.41),
+    colo("STL", "St. Louis", "US", 38.75, -90.37),
+    colo("STR", "Stuttgart", "DE", 48.69, 9.22),
+    colo("SUB", "Surabaya", "ID", -7.38, 112.79),
+    colo("SYD", "Sydney", "AU", -33.95, 151.18),
+    colo("TAS", "Tashkent", "UZ", 41.26, 69.28),
+    colo("TBS", "Tbilisi", "GE", 41.67, 44.95),
+    colo("TLL", "Tallinn", "EE", 59.41, 24.83),
+    colo("TLV", "Tel Aviv", "IL", 32.01, 34.89),
+    colo("TPA", "Tampa", "US", 27.98, -82.53),
+    colo("TPE", "Taipei", "TW", 25.08, 121.23),
+    colo("TUN", "Tunis", "TN", 36.85, 10.23),
+    colo("UIO", "Quito", "EC", -0.13, -78.36),
+    colo("ULN", "Ulaanbaatar", "MN", 47.85, 106.77),
+    colo("VIE", "Vienna", "AT", 48.11, 16.57),
+    colo("VNO", "Vilnius", "LT", 54.63, 25.29),
+    colo("VTE", "Vientiane", "LA", 17.99, 102.56),
+    colo("WAW", "Warsaw", "PL", 52.17, 20.97),
+    colo("YUL", "Montreal", "CA", 45.47, -73.74),
+    colo("YVR", "Vancouver", "CA", 49.19, -123.18),
+    colo("YWG", "Winnipeg", "CA", 49.91, -97.24),
+    colo("YYC", "Calgary", "CA", 51.13, -114.01),
+    colo("YYZ", "Toronto", "CA", 43.68, -79.63),
+    colo("ZAG", "Zagreb", "HR", 45.74, 16.07),
+    colo("ZRH", "Zurich", "CH", 47.46, 8.55),
+];
+
+/// Looks up a colo by its IATA code e.g. `ZRH`
+pub fn lookup(iata: &str) -> Option<&'static ColoLocation> {
+    COLOS
+        .iter()
+        .find(|colo| colo.iata.eq_ignore_ascii_case(iata))
+}
+
+/// Great circle distance between two coordinates in km
+pub fn distance_km(from: (f64, f64), to: (f64, f64)) -> f64 {
+    const EARTH_RADIUS_KM: f64 = 6371.0;
+    let (lat1, lon1) = (from.0.to_radians(), from.1.to_radians());
+    let (lat2, lon2) = (to.0.to_radians(), to.1.to_radians());
+    let a = ((lat2 - lat1) / 2.0).sin().powi(2)
+        + lat1.cos() * lat2.cos() * ((lon2 - lon1) / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+}
+
+/// Lower bound of the RTT to a colo `distance_km` away, given by the speed of light in fiber
+pub fn min_rtt_ms(distance_km: f64) -> f64 {
+    2.0 * distance_km / FIBER_KM_PER_MS
+}
+
+pub fn run_list_colos(
+    client: &Client,
+    options: &ListColosOptions,
+    retry_policy: &RetryPolicy,
+) -> Result<(), String> {
+    let metadata = fetch_metadata(client, retry_policy)
+        .map_err(|e| log::warn!("client location unknown, listing colos by code: {e}"))
+        .ok();
+    let client_location = metadata
+        .as_ref()
+        .and_then(|m| Some((m.latitude?, m.longitude?)));
+    if options.nearest.is_some() && client_location.is_none() {
+        return Err("--nearest needs the client location, which is not available".to_string());
+    }
+
+    let mut colos: Vec<(&ColoLocation, Option<f64>)> = COLOS
+        .iter()
+        .filter(|colo| {
+            options
+                .country
+                .as_ref()
+                .is_none_or(|country| colo.country.eq_ignore_ascii_case(country))
+        })
+        .map(|colo| {
+            let distance =
+                client_location.map(|from| distance_km(from, (colo.latitude, colo.longitude)));
+            (colo, distance)
+        })
+        .collect();
+    if client_location.is_some() {
+        colos.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+    }
+    if let Some(nearest) = options.nearest {
+        colos.truncate(nearest);
+    }
+    if colos.is_empty() {
+        return Err("no colos match the given filters".to_string());
+    }
+
+    let serving = metadata.as_ref().map(|m| m.colo.as_str());
+    print_colos(&colos, serving);
+    if let Some(metadata) = metadata.as_ref().filter(|_| options.probe) {
+        probe_serving_colo(client, metadata, options.probe_samples, retry_policy)?;
+    }
+    Ok(())
+}
+
+fn print_colos(colos: &[(&ColoLocation, Option<f64>)], serving: Option<&str>) {
+    println!(
+        "{:<5} {:<18} {:<8} {:>10} {:>9}",
+        "Code", "City", "Country", "Distance", "Min RTT"
+    );
+    for (colo, distance) in colos {
+        let (distance, min_rtt) = match distance {
+            Some(distance) => (
+                format!("{distance:.0} km"),
+                format!("{:.1} ms", min_rtt_ms(*distance)),
+            ),
+            None => ("-".to_string(), "-".to_string()),
+        };
+        let marker = if serving == Some(colo.iata) {
+            "  <- serving this client"
+        } else {
+            ""
+        };
+        println!(
+            "{:<5} {:<18} {:<8} {:>10} {:>9}{marker}",
+            colo.iata, colo.city, colo.country, distance, min_rtt
+        );
+    }
+}
+
+/// Cloudflare routes every request to the colo chosen by anycast, so only that colo can be probed.
+/// Its RTT is compared to the distance of the nearest bundled colo to spot detours.
+fn probe_serving_colo(
+    client: &Client,
+    metadata: &Metadata,
+    samples: u32,
+    retry_policy: &RetryPolicy,
+) -> Result<(), String> {
+    let mut latencies: Vec<f64> = Vec::new();
+    for _ in 0..samples {
+        match test_latency(client, retry_policy) {
+            Ok((sample, _)) if !sample.suspect => latencies.push(sample.latency_ms),
+            Ok(_) => {}
+            Err(e) => log::warn!("latency probe failed: {e}"),
+        }
+    }
+    let Some(best) = latencies.iter().copied().reduce(f64::min) else {
+        return Err(format!("could not measure the RTT to {}", metadata.colo));
+    };
+    let avg = latencies.iter().sum::<f64>() / latencies.len() as f64;
+    println!(
+        "\nRTT to {}: min {best:.1} ms avg {avg:.1} ms ({} samples)",
+        metadata.colo,
+        latencies.len()
+    );
+
+    let client_location = metadata.latitude.zip(metadata.longitude);
+    let nearest = client_location.and_then(|from| {
+        COLOS
+            .iter()
+            .map(|colo| (colo, distance_km(from, (colo.latitude, colo.longitude))))
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+    });
+    if let Some((nearest, distance)) = nearest {
+        if nearest.iata != metadata.colo {
+            println!(
+                "Nearest colo is {} ({}, ~{distance:.0} km away), routing takes a detour via {}",
+                nearest.iata, nearest.city, metadata.colo
+            );
+        }
+        if let Some(serving_distance) = metadata.colo_distance_km {
+            println!(
+                "Fiber minimum to {} is {:.1} ms, measured RTT adds {:.1} ms",
+                metadata.colo,
+                min_rtt_ms(serving_distance),
+                (best - min_rtt_ms(serving_distance)).max(0.0)
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,161 @@
+use crate::history::{HistoryRecord, HistoryStore};
+use crate::preflight::check_connection;
+use crate::speedtest::{
+    sleep_unless_stopped, speed_test_with_events, stop_requested, SpeedTestEvent, SpeedTestResult,
+};
+use crate::{DaemonOptions, SpeedTestCLIOptions};
+use chrono::Utc;
+use log;
+use reqwest::blocking::Client;
+use std::{
+    any::Any,
+    fs::OpenOptions,
+    io::Write,
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Runs speed tests every `interval` until the process is stopped or a stop is requested.
+///
+/// Every successful run is appended to the history store and, if configured, to the
+/// output file. Failed runs are retried with exponential backoff before giving up
+/// until the next scheduled run. `on_run` is called with the outcome of every run.
+pub fn run_daemon<F>(
+    client: Client,
+    options: SpeedTestCLIOptions,
+    daemon_options: &DaemonOptions,
+    mut on_run: F,
+) -> Result<(), String>
+where
+    F: FnMut(&Result<HistoryRecord, String>),
+{
+    let history = HistoryStore::open(options.history_file.clone())?;
+    let interval = daemon_options.interval;
+    loop {
+        let started = Instant::now();
+        let outcome = match run_with_retries(&client, &options, daemon_options) {
+            Ok(result) => {
+                let record = HistoryRecord::from_result(&result, Utc::now());
+                Ok(store_record(
+                    &history,
+                    daemon_options.output_file.as_deref(),
+                    record,
+                ))
+            }
+            Err(e) => {
+                log::error!("giving up on this run: {e}");
+                Err(e)
+            }
+        };
+        on_run(&outcome);
+        if stop_requested() {
+            return Ok(());
+        }
+
+        let remaining = interval.saturating_sub(started.elapsed());
+        if options.output_format.is_human() {
+            println!(
+                "Next run in {}",
+                humantime::format_duration(Duration::from_secs(remaining.as_secs()))
+            );
+        }
+        if !sleep_unless_stopped(remaining) {
+            return Ok(());
+        }
+    }
+}
+
+fn run_with_retries(
+    client: &Client,
+    options: &SpeedTestCLIOptions,
+    daemon_options: &DaemonOptions,
+) -> Result<SpeedTestResult, String> {
+    let mut delay = daemon_options.retry_delay;
+    let mut attempt = 0;
+    loop {
+        match run_once(client, options, |_| {}) {
+            Ok(result) => return Ok(result),
+            Err(e) if attempt < daemon_options.run_retries && !stop_requested() => {
+                attempt += 1;
+                log::warn!(
+                    "run failed ({e}), retry {attempt}/{} in {}",
+                    daemon_options.run_retries,
+                    humantime::format_duration(delay)
+                );
+                if !sleep_unless_stopped(delay) {
+                    return Err(e);
+                }
+                delay *= 2;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Runs a single speed test on a separate thread so that a failing request
+/// does not take the whole process down with it.
+pub(crate) fn run_once<F>(
+    client: &Client,
+    options: &SpeedTestCLIOptions,
+    on_event: F,
+) -> Result<SpeedTestResult, String>
+where
+    F: Fn(SpeedTestEvent) + Send + 'static,
+{
+    if !options.skip_connection_check && options.uses_cloudflare() {
+        check_connection(client)?;
+    }
+    let client = client.clone();
+    let options = options.clone();
+    thread::spawn(move || speed_test_with_events(client, options, &on_event))
+        .join()
+        .map_err(panic_message)
+        .and_then(|result| {
+            if result.has_samples() {
+                Ok(result)
+            } else {
+                Err("all test requests failed".to_string())
+            }
+        })
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "speed test aborted".to_string()
+    }
+}
+
+/// Appends `record` to the history and output file, returning the stored record with its id.
+/// Storage failures are logged but do not invalidate the measurement itself.
+fn store_record(
+    history: &HistoryStore,
+    output_file: Option<&Path>,
+    record: HistoryRecord,
+) -> HistoryRecord {
+    let stored = match history.append(vec![record.clone()]) {
+        Ok(mut stored) => stored.remove(0),
+        Err(e) => {
+            log::error!(
+                "failed to store result in {}: {e}",
+                history.path().display()
+            );
+            record
+        }
+    };
+    if let Some(path) = output_file {
+        if let Err(e) = append_json_line(path, &stored) {
+            log::error!("failed to write result to {}: {e}", path.display());
+        }
+    }
+    stored
+}
+
+fn append_json_line(path: &Path, record: &HistoryRecord) -> std::io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", serde_json::to_string(record)?)
+}
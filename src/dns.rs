@@ -0,0 +1,187 @@
+use crate::measurements::{calc_stats, print_rows};
+use crate::progress::{finish_progress, print_progress};
+use crate::DnsOptions;
+use crate::OutputFormat;
+use serde::Serialize;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Name used for the resolver configured in the operating system
+pub const SYSTEM_RESOLVER: &str = "system";
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+
+static NEXT_ID: AtomicU16 = AtomicU16::new(0);
+
+/// Lookup latency stats of one resolver over all test names
+#[derive(Serialize)]
+struct ResolverStats {
+    resolver: String,
+    min: Option<f64>,
+    median: Option<f64>,
+    max: Option<f64>,
+    avg: Option<f64>,
+    samples: usize,
+    failed: usize,
+}
+
+/// Measures the lookup latency of every resolver for every name and prints the stats per resolver
+pub fn run_dns_benchmark(options: &DnsOptions, output_format: OutputFormat) -> Result<(), String> {
+    let resolvers: Vec<(String, Option<IpAddr>)> = options
+        .resolver
+        .iter()
+        .map(|resolver| parse_resolver(resolver).map(|addr| (resolver.clone(), addr)))
+        .collect::<Result<_, _>>()?;
+
+    let mut stats = Vec::new();
+    for (name, addr) in &resolvers {
+        let progress_name = format!("{name:<15}");
+        let total = options.name.len() as u32 * options.samples;
+        let start = Instant::now();
+        let mut latencies = Vec::new();
+        let mut failed = 0;
+        for (i, test_name) in options
+            .name
+            .iter()
+            .flat_map(|test_name| std::iter::repeat_n(test_name, options.samples as usize))
+            .enumerate()
+        {
+            if output_format.is_human() {
+                print_progress(&progress_name, i as u32, total, start.elapsed());
+            }
+            let result = match addr {
+                Some(addr) => query_udp(*addr, test_name),
+                None => query_system(test_name),
+            };
+            match result {
+                Ok(latency) => latencies.push(latency.as_secs_f64() * 1000.0),
+                Err(e) => {
+                    log::debug!("lookup of {test_name} via {name} failed: {e}");
+                    failed += 1;
+                }
+            }
+        }
+        if output_format.is_human() {
+            print_progress(&progress_name, total, total, start.elapsed());
+            finish_progress();
+        }
+        let samples = latencies.len();
+        let (min, median, max, avg) = match calc_stats(latencies) {
+            Some((min, _, median, _, max, avg)) => (Some(min), Some(median), Some(max), Some(avg)),
+            None => (None, None, None, None),
+        };
+        stats.push(ResolverStats {
+            resolver: name.clone(),
+            min,
+            median,
+            max,
+            avg,
+            samples,
+            failed,
+        });
+    }
+
+    if output_format.is_human() {
+        print_stats(&stats);
+    }
+    print_rows(&stats, "resolvers", output_format);
+    Ok(())
+}
+
+fn parse_resolver(resolver: &str) -> Result<Option<IpAddr>, String> {
+    if resolver == SYSTEM_RESOLVER {
+        return Ok(None);
+    }
+    resolver
+        .parse()
+        .map(Some)
+        .map_err(|_| format!("invalid resolver {resolver}, expected an IP address or `system`"))
+}
+
+fn print_stats(stats: &[ResolverStats]) {
+    println!("\nDNS lookup latency");
+    println!("Resolver        |  min/median/max/avg in ms");
+    for stat in stats {
+        match (stat.min, stat.median, stat.max, stat.avg) {
+            (Some(min), Some(median), Some(max), Some(avg)) => print!(
+                "{:<15} |  min {min:<6.2} median {median:<6.2} max {max:<6.2} avg {avg:<6.2}",
+                stat.resolver
+            ),
+            _ => print!("{:<15} |  too few successful lookups", stat.resolver),
+        }
+        if stat.failed > 0 {
+            print!(" ({} failed)", stat.failed);
+        }
+        println!();
+    }
+}
+
+/// Goes through the resolver of the operating system, which may answer from a local cache
+fn query_system(name: &str) -> Result<Duration, String> {
+    let start = Instant::now();
+    (name, 0)
+        .to_socket_addrs()
+        .map_err(|e| e.to_string())?
+        .next()
+        .ok_or_else(|| format!("no address for {name}"))?;
+    Ok(start.elapsed())
+}
+
+/// Sends a single recursive A query and waits for the matching answer
+fn query_udp(resolver: IpAddr, name: &str) -> Result<Duration, String> {
+    let local: SocketAddr = match resolver {
+        IpAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+        IpAddr::V6(_) => "[::]:0".parse().unwrap(),
+    };
+    let socket = UdpSocket::bind(local).map_err(|e| e.to_string())?;
+    socket
+        .set_read_timeout(Some(QUERY_TIMEOUT))
+        .map_err(|e| e.to_string())?;
+    socket.connect((resolver, 53)).map_err(|e| e.to_string())?;
+
+    let id = query_id();
+    let query = build_query(id, name)?;
+    let start = Instant::now();
+    socket.send(&query).map_err(|e| e.to_string())?;
+    let mut response = [0u8; 512];
+    loop {
+        let len = socket.recv(&mut response).map_err(|e| e.to_string())?;
+        // late answers to an earlier query that timed out are skipped
+        if len < 12 || u16::from_be_bytes([response[0], response[1]]) != id {
+            continue;
+        }
+        let elapsed = start.elapsed();
+        return match response[3] & 0x0f {
+            0 => Ok(elapsed),
+            rcode => Err(format!("resolver answered with rcode {rcode}")),
+        };
+    }
+}
+
+fn query_id() -> u16 {
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |t| t.subsec_nanos() as u16);
+    seed.wrapping_add(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+fn build_query(id: u16, name: &str) -> Result<Vec<u8>, String> {
+    let mut query = Vec::with_capacity(name.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    // recursion desired, one question
+    query.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("invalid name {name}"));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&TYPE_A.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
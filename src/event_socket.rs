@@ -0,0 +1,98 @@
+use crate::speedtest::SpeedTestEvent;
+use std::path::Path;
+#[cfg(unix)]
+use std::{
+    io::Write,
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    sync::{Arc, Mutex, OnceLock},
+    thread,
+    time::Duration,
+};
+
+/// A client that doesn't read its events in time is dropped instead of stalling the test
+#[cfg(unix)]
+const WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Socket shared by all runs of the process, `None` if it could not be created
+#[cfg(unix)]
+static EVENT_SOCKET: OnceLock<Option<EventSocket>> = OnceLock::new();
+
+/// Unix domain socket that broadcasts events to every connected client as JSON lines
+#[cfg(unix)]
+pub struct EventSocket {
+    clients: Arc<Mutex<Vec<UnixStream>>>,
+}
+
+#[cfg(unix)]
+impl EventSocket {
+    /// Creates the socket at `path` and accepts clients in the background.
+    /// A stale socket left behind by an earlier run is replaced, any other existing file is an error.
+    pub fn bind(path: &Path) -> Result<Self, String> {
+        if let Ok(existing) = std::fs::symlink_metadata(path) {
+            if !existing.file_type().is_socket() {
+                return Err(format!("{} exists and is not a socket", path.display()));
+            }
+            std::fs::remove_file(path).map_err(|e| e.to_string())?;
+        }
+        let listener = UnixListener::bind(path)
+            .map_err(|e| format!("failed to create event socket {}: {e}", path.display()))?;
+        let clients = Arc::new(Mutex::new(Vec::new()));
+        let accepted = clients.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream.and_then(|s| s.set_write_timeout(Some(WRITE_TIMEOUT)).map(|_| s)) {
+                    Ok(stream) => accepted.lock().unwrap().push(stream),
+                    Err(e) => log::warn!("failed to accept event socket client: {e}"),
+                }
+            }
+        });
+        Ok(Self { clients })
+    }
+
+    pub fn send(&self, event: &SpeedTestEvent) {
+        let Ok(mut line) = serde_json::to_string(event) else {
+            return;
+        };
+        line.push('\n');
+        self.clients
+            .lock()
+            .unwrap()
+            .retain_mut(|client| client.write_all(line.as_bytes()).is_ok());
+    }
+}
+
+#[cfg(unix)]
+fn shared(path: &Path) -> Option<&'static EventSocket> {
+    EVENT_SOCKET
+        .get_or_init(|| {
+            EventSocket::bind(path)
+                .map_err(|e| log::warn!("{e}, events will not be streamed"))
+                .ok()
+        })
+        .as_ref()
+}
+
+/// Creates the socket at `path` so that clients can connect before the first event is sent
+#[cfg(unix)]
+pub(crate) fn listen(path: &Path) {
+    shared(path);
+}
+
+/// Sends `event` to all clients of the socket at `path`
+#[cfg(unix)]
+pub(crate) fn publish(path: &Path, event: &SpeedTestEvent) {
+    if let Some(socket) = shared(path) {
+        socket.send(event);
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn listen(_path: &Path) {
+    log::warn!("--event-socket is only supported on unix, events will not be streamed");
+}
+
+#[cfg(not(unix))]
+pub(crate) fn publish(_path: &Path, _event: &SpeedTestEvent) {}
@@ -0,0 +1,147 @@
+use crate::history::HistoryRecord;
+use crate::server::json_response;
+use crate::speedtest::stop_requested;
+use log;
+use serde_json::json;
+use std::{
+    fmt::Write,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    thread,
+};
+use tiny_http::{Header, Response, Server};
+
+/// Values exposed on the `/metrics` endpoint
+#[derive(Default)]
+pub struct Metrics {
+    last_result: Option<HistoryRecord>,
+    runs_total: u64,
+    failures_total: u64,
+}
+
+impl Metrics {
+    pub fn record_run(&mut self, result: &Result<HistoryRecord, String>) {
+        self.runs_total += 1;
+        match result {
+            Ok(record) => self.last_result = Some(record.clone()),
+            Err(_) => self.failures_total += 1,
+        }
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        write_metric(
+            &mut out,
+            "cfspeedtest_runs_total",
+            "counter",
+            "Number of speed test runs since start",
+            Some(self.runs_total as f64),
+        );
+        write_metric(
+            &mut out,
+            "cfspeedtest_run_failures_total",
+            "counter",
+            "Number of failed speed test runs since start",
+            Some(self.failures_total as f64),
+        );
+        let Some(record) = &self.last_result else {
+            return out;
+        };
+        write_metric(
+            &mut out,
+            "cfspeedtest_last_run_timestamp_seconds",
+            "gauge",
+            "Unix time of the last successful run",
+            Some(record.timestamp.timestamp() as f64),
+        );
+        write_metric(
+            &mut out,
+            "cfspeedtest_latency_milliseconds",
+            "gauge",
+            "Average latency of the last run",
+            record.latency_ms,
+        );
+        write_metric(
+            &mut out,
+            "cfspeedtest_jitter_milliseconds",
+            "gauge",
+            "Latency jitter of the last run",
+            record.jitter_ms,
+        );
+        write_metric(
+            &mut out,
+            "cfspeedtest_download_mbit_per_second",
+            "gauge",
+            "Average download speed of the largest payload size in the last run",
+            record.download_mbit,
+        );
+        write_metric(
+            &mut out,
+            "cfspeedtest_upload_mbit_per_second",
+            "gauge",
+            "Average upload speed of the largest payload size in the last run",
+            record.upload_mbit,
+        );
+        let _ = writeln!(
+            out,
+            "# HELP cfspeedtest_info Test server and client of the last run"
+        );
+        let _ = writeln!(out, "# TYPE cfspeedtest_info gauge");
+        let _ = writeln!(
+            out,
+            "cfspeedtest_info{{server=\"{}\",isp=\"{}\"}} 1",
+            escape_label(record.server.as_deref().unwrap_or_default()),
+            escape_label(record.isp.as_deref().unwrap_or_default()),
+        );
+        out
+    }
+}
+
+fn write_metric(out: &mut String, name: &str, metric_type: &str, help: &str, value: Option<f64>) {
+    let Some(value) = value else {
+        return;
+    };
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {metric_type}");
+    let _ = writeln!(out, "{name} {value}");
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Starts serving `/metrics` on `addr` from a background thread
+pub fn spawn_exporter(addr: SocketAddr, metrics: Arc<Mutex<Metrics>>) -> Result<(), String> {
+    let server = Server::http(addr).map_err(|e| format!("Failed to listen on {addr}: {e}"))?;
+    log::info!("serving metrics on http://{addr}/metrics");
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            // scrapers may add query parameters e.g. for cache busting
+            let path = request.url().split('?').next().unwrap_or_default();
+            let response = match path {
+                "/metrics" => {
+                    let body = metrics.lock().unwrap().render();
+                    Response::from_string(body).with_header(
+                        Header::from_bytes("Content-Type", "text/plain; version=0.0.4").unwrap(),
+                    )
+                }
+                // same bodies as the HTTP API so probes can be shared between both modes
+                "/healthz" => json_response(200, &json!({ "status": "ok" })),
+                "/readyz" if stop_requested() => {
+                    json_response(503, &json!({ "status": "shutting down" }))
+                }
+                "/readyz" => json_response(200, &json!({ "status": "ready" })),
+                "/" => Response::from_string("cfspeedtest exporter, see /metrics\n"),
+                _ => Response::from_string("not found\n").with_status_code(404),
+            };
+            if let Err(e) = request.respond(response) {
+                log::warn!("failed to send response: {e}");
+            }
+        }
+    });
+    Ok(())
+}
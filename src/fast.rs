@@ -0,0 +1,124 @@
+use crate::metadata::Metadata;
+use regex::Regex;
+use reqwest::blocking::Client;
+use serde::Deserialize;
+
+const FAST_URL: &str = "https://fast.com";
+const API_URL: &str = "https://api.fast.com/netflix/speedtest/v2";
+
+/// Response of the fast.com API, only the fields that are used
+#[derive(Deserialize)]
+struct ApiResponse {
+    client: ApiClient,
+    targets: Vec<ApiTarget>,
+}
+
+#[derive(Deserialize)]
+struct ApiClient {
+    ip: Option<String>,
+    asn: Option<String>,
+    isp: Option<String>,
+    location: Option<ApiLocation>,
+}
+
+#[derive(Deserialize)]
+struct ApiTarget {
+    url: String,
+    location: Option<ApiLocation>,
+}
+
+#[derive(Deserialize)]
+struct ApiLocation {
+    city: Option<String>,
+    country: Option<String>,
+}
+
+/// Netflix server picked by fast.com and what fast.com knows about the client
+pub struct FastTarget {
+    /// URL of the `/speedtest` resource of the server, it includes a short lived signature
+    pub url: String,
+    pub metadata: Metadata,
+}
+
+/// Looks up the API token used by the fast.com web app and asks the API for the nearest Netflix server
+pub fn discover(client: &Client) -> Result<FastTarget, String> {
+    let token = fetch_token(client)?;
+    let response: ApiResponse = client
+        .get(API_URL)
+        .query(&[
+            ("https", "true"),
+            ("token", token.as_str()),
+            ("urlCount", "1"),
+        ])
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.json())
+        .map_err(|e| format!("fast.com API request failed: {e}"))?;
+    let target = response
+        .targets
+        .into_iter()
+        .next()
+        .ok_or("fast.com API returned no test servers")?;
+
+    let mut metadata = Metadata::unavailable();
+    let ApiClient {
+        ip,
+        asn,
+        isp,
+        location,
+    } = response.client;
+    if let Some(ip) = ip {
+        metadata.ip = ip;
+    }
+    if let Some(asn) = asn {
+        metadata.asn = asn;
+    }
+    metadata.isp = isp;
+    if let Some(location) = location {
+        metadata.city = location.city.unwrap_or(metadata.city);
+        metadata.country = location.country.unwrap_or(metadata.country);
+    }
+    if let Some(server_city) = target.location.and_then(|l| l.city) {
+        metadata.colo = format!("Netflix {server_city}");
+    }
+    Ok(FastTarget {
+        url: target.url,
+        metadata,
+    })
+}
+
+/// The token is embedded in the JavaScript bundle of the web app
+fn fetch_token(client: &Client) -> Result<String, String> {
+    let get = |url: &str| {
+        client
+            .get(url)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.text())
+            .map_err(|e| format!("failed to fetch {url}: {e}"))
+    };
+    let page = get(FAST_URL)?;
+    let script = Regex::new(r#"src="(/app-[^"]+\.js)""#)
+        .unwrap()
+        .captures(&page)
+        .and_then(|captures| captures.get(1))
+        .ok_or("fast.com page has no app script")?
+        .as_str()
+        .to_string();
+    let bundle = get(&format!("{FAST_URL}{script}"))?;
+    Regex::new(r#"token:"([A-Za-z0-9]+)""#)
+        .unwrap()
+        .captures(&bundle)
+        .and_then(|captures| captures.get(1))
+        .map(|token| token.as_str().to_string())
+        .ok_or_else(|| "no API token found in the fast.com app script".to_string())
+}
+
+/// URL serving or accepting exactly `bytes` bytes, a zero byte request still transfers a single byte
+pub(crate) fn range_url(url: &str, bytes: usize) -> String {
+    url.replacen(
+        "/speedtest",
+        &format!("/speedtest/range/0-{}", bytes.saturating_sub(1)),
+        1,
+    )
+}
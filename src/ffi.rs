@@ -0,0 +1,107 @@
+use crate::daemon::run_once;
+use crate::history::HistoryRecord;
+use crate::server::RunConfig;
+use crate::speedtest::{build_client, SpeedTestEvent};
+use crate::SpeedTestCLIOptions;
+use chrono::Utc;
+use clap::{CommandFactory, FromArgMatches};
+use serde_json::{json, Value};
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+/// Receives every progress event as a JSON object, the string is only valid during the call.
+/// Called from the thread running the test.
+pub type EventCallback = Option<extern "C" fn(event_json: *const c_char, user_data: *mut c_void)>;
+
+/// `user_data` is handed back to the callback untouched, the caller is responsible for it being
+/// usable from the test thread
+struct UserData(*mut c_void);
+
+unsafe impl Send for UserData {}
+
+/// Runs one speed test configured by `config_json`, an object with the fields accepted by
+/// `POST /run` of the HTTP API, or NULL for the defaults. `callback` may be NULL.
+///
+/// Returns `{"type": "result", "result": {…}}` or `{"type": "failed", "error": "…"}` as a JSON
+/// string that has to be released with `cfspeedtest_free_string`.
+///
+/// # Safety
+///
+/// `config_json` has to be NULL or a valid NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn cfspeedtest_run(
+    config_json: *const c_char,
+    callback: EventCallback,
+    user_data: *mut c_void,
+) -> *mut c_char {
+    let config = if config_json.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(config_json).to_string_lossy().into_owned())
+    };
+    let user_data = UserData(user_data);
+    let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+        run(config.as_deref(), callback, user_data)
+    }))
+    .unwrap_or_else(|_| Err("the speed test panicked".to_string()));
+    let response = match outcome {
+        Ok(record) => json!({ "type": "result", "result": record }),
+        Err(e) => json!({ "type": "failed", "error": e }),
+    };
+    to_c_string(&response)
+}
+
+/// Releases a string returned by `cfspeedtest_run`
+///
+/// # Safety
+///
+/// `s` has to be NULL or a pointer returned by `cfspeedtest_run` that wasn't released yet.
+#[no_mangle]
+pub unsafe extern "C" fn cfspeedtest_free_string(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+fn run(
+    config: Option<&str>,
+    callback: EventCallback,
+    user_data: UserData,
+) -> Result<HistoryRecord, String> {
+    let config: RunConfig = match config.map(str::trim).filter(|c| !c.is_empty()) {
+        Some(config) => {
+            serde_json::from_str(config).map_err(|e| format!("invalid run config: {e}"))?
+        }
+        None => RunConfig::default(),
+    };
+    let options = default_options().and_then(|options| config.apply(options))?;
+    let client = build_client(&options);
+    let result = run_once(&client, &options, move |event: SpeedTestEvent| {
+        // captures the whole wrapper, the raw pointer alone isn't Send
+        let user_data = &user_data;
+        if let (Some(callback), Ok(event)) = (callback, serde_json::to_value(&event)) {
+            let event = to_c_string(&event);
+            callback(event, user_data.0);
+            unsafe { cfspeedtest_free_string(event) };
+        }
+    })?;
+    Ok(HistoryRecord::from_result(&result, Utc::now()))
+}
+
+/// Defaults of every option, the environment of the host application must not configure the test
+/// (e.g. SMTP_PASSWORD)
+fn default_options() -> Result<SpeedTestCLIOptions, String> {
+    SpeedTestCLIOptions::command()
+        .mut_args(|arg| arg.env(None::<&str>))
+        .try_get_matches_from(["cfspeedtest"])
+        .and_then(|matches| SpeedTestCLIOptions::from_arg_matches(&matches))
+        .map_err(|e| e.to_string())
+}
+
+fn to_c_string(value: &Value) -> *mut c_char {
+    // serde_json escapes control characters, the output never contains NUL bytes
+    CString::new(value.to_string())
+        .map(CString::into_raw)
+        .unwrap_or(ptr::null_mut())
+}
@@ -0,0 +1,48 @@
+use crate::measurements::format_bytes;
+use crate::speedtest::Endpoint;
+use crate::SpeedTestCLIOptions;
+use reqwest::blocking::Client;
+use std::time::{Duration, Instant};
+
+/// Small enough to finish quickly on any working link, large enough to need more than one packet
+const PAYLOAD_SIZE: usize = 10_000;
+
+/// Probes the test server with a latency request and a tiny download, both within
+/// --healthcheck-timeout. Meant as a container health or connectivity probe, the error is
+/// returned so the process exits with 1.
+pub fn run_healthcheck(client: &Client, options: &SpeedTestCLIOptions) -> Result<(), String> {
+    let deadline = Instant::now() + options.healthcheck_timeout;
+    let endpoint = Endpoint::custom(options).unwrap_or_else(|| options.endpoint.clone());
+    let (_, latency) = get(client, &endpoint.download_url(0), deadline)
+        .map_err(|e| format!("healthcheck failed, latency probe: {e}"))?;
+    let (bytes, download) = get(client, &endpoint.download_url(PAYLOAD_SIZE), deadline)
+        .map_err(|e| format!("healthcheck failed, download: {e}"))?;
+    if options.output_format.is_human() {
+        println!(
+            "healthy: latency {:.2} ms, {} downloaded in {:.2} ms",
+            latency.as_secs_f64() * 1_000.0,
+            format_bytes(bytes),
+            download.as_secs_f64() * 1_000.0
+        );
+    }
+    Ok(())
+}
+
+/// Returns the body size and the time the request took, fails once `deadline` passed
+fn get(client: &Client, url: &str, deadline: Instant) -> Result<(usize, Duration), String> {
+    let remaining = deadline
+        .checked_duration_since(Instant::now())
+        .filter(|remaining| !remaining.is_zero())
+        .ok_or("time budget exceeded")?;
+    let start = Instant::now();
+    let response = client
+        .get(url)
+        .timeout(remaining)
+        .send()
+        .map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("server responded with {}", response.status()));
+    }
+    let bytes = response.bytes().map_err(|e| e.to_string())?.len();
+    Ok((bytes, start.elapsed()))
+}
@@ -0,0 +1,483 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc};
+use log;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    fs::{self, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::bufferbloat::Grade;
+use crate::host::HostInfo;
+use crate::measurements::{calc_jitter, headline_mbit};
+use crate::speedtest::{LatencyPercentiles, SpeedTestResult, TestType};
+use crate::trace::PathTrace;
+use crate::HistoryCommand;
+
+const HISTORY_FILE_NAME: &str = "history.jsonl";
+/// Only recent results count towards the consistency, older ones may predate a plan change
+const CONSISTENCY_WEEKS: i64 = 8;
+
+/// A single stored speed test result.
+///
+/// Records are persisted as one JSON object per line so that appending a new
+/// result never requires rewriting the whole file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HistoryRecord {
+    pub id: u64,
+    pub timestamp: DateTime<Utc>,
+    /// Tool that produced the result: `cfspeedtest`, `ookla` or `speedtest-cli`
+    pub source: String,
+    pub server: Option<String>,
+    pub isp: Option<String>,
+    pub ip: Option<String>,
+    pub latency_ms: Option<f64>,
+    pub jitter_ms: Option<f64>,
+    pub download_mbit: Option<f64>,
+    pub upload_mbit: Option<f64>,
+    /// Latency increase under load, only measured with --bufferbloat
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bufferbloat_grade: Option<Grade>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency_percentiles: Option<LatencyPercentiles>,
+    /// Estimated VoIP call quality from 1 to 4.4
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mos: Option<f64>,
+    /// Whether the run reused metadata of an earlier run
+    #[serde(default)]
+    pub metadata_cached: bool,
+    /// Whether the connection was warmed up before measuring, only with --prewarm
+    #[serde(default)]
+    pub prewarmed: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<HostInfo>,
+    /// Route to the test server, only traced with --trace-path
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathTrace>,
+    /// Key-value pairs given with --meta e.g. a device or circuit id
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub meta: BTreeMap<String, String>,
+}
+
+impl HistoryRecord {
+    /// Summarizes a finished cfspeedtest run. The id is assigned when the record is stored.
+    pub fn from_result(result: &SpeedTestResult, timestamp: DateTime<Utc>) -> Self {
+        let metadata = &result.metadata;
+        HistoryRecord {
+            id: 0,
+            timestamp,
+            source: "cfspeedtest".to_string(),
+            server: Some(format!("{} ({})", metadata.colo, metadata.city)),
+            isp: Some(metadata.isp.clone().unwrap_or_else(|| metadata.asn.clone())),
+            ip: Some(metadata.ip.clone()),
+            latency_ms: Some(result.latency.avg_latency).filter(|l| l.is_finite()),
+            jitter_ms: calc_jitter(&result.latency.latencies()),
+            download_mbit: headline_mbit(&result.measurements, TestType::Download),
+            upload_mbit: headline_mbit(&result.measurements, TestType::Upload),
+            bufferbloat_grade: result.bufferbloat.as_ref().map(|b| b.grade),
+            latency_percentiles: result.latency.percentiles,
+            mos: result.mos(),
+            metadata_cached: result.metadata_cached,
+            prewarmed: result.prewarmed,
+            host: result.host_info.clone(),
+            path: result.path.clone(),
+            meta: result.meta.clone(),
+        }
+    }
+
+    /// One line summary of the measured values e.g. for notifications
+    pub fn summary(&self) -> String {
+        let values = [
+            ("Download", self.download_mbit, "mbit/s"),
+            ("Upload", self.upload_mbit, "mbit/s"),
+            ("Latency", self.latency_ms, "ms"),
+            ("Jitter", self.jitter_ms, "ms"),
+        ];
+        values
+            .iter()
+            .filter_map(|(name, value, unit)| value.map(|v| format!("{name} {v:.2} {unit}")))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// How much the weekly averages of recent results differ, as a percentage of their mean.
+/// Each day counts once, no matter how many tests ran on it.
+#[derive(Clone, Debug, Serialize)]
+pub struct Consistency {
+    pub weeks: usize,
+    pub download_percent: Option<f64>,
+    pub upload_percent: Option<f64>,
+}
+
+impl Consistency {
+    /// `None` unless the results of the last weeks span at least two weeks
+    pub fn of(records: &[HistoryRecord], now: DateTime<Utc>) -> Option<Self> {
+        let recent: Vec<&HistoryRecord> = records
+            .iter()
+            .filter(|r| now - r.timestamp < Duration::weeks(CONSISTENCY_WEEKS))
+            .collect();
+        let download = weekly_averages(&recent, |r| r.download_mbit);
+        let upload = weekly_averages(&recent, |r| r.upload_mbit);
+        let weeks = download.len().max(upload.len());
+        if weeks < 2 {
+            return None;
+        }
+        Some(Self {
+            weeks,
+            download_percent: variation(&download),
+            upload_percent: variation(&upload),
+        })
+    }
+}
+
+impl Display for Consistency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let percent = |p: Option<f64>| p.map_or("-".to_string(), |p| format!("±{p:.0}%"));
+        write!(
+            f,
+            "Your connection varies {} down and {} up week to week (last {} weeks)",
+            percent(self.download_percent),
+            percent(self.upload_percent),
+            self.weeks
+        )
+    }
+}
+
+/// Averages of the daily averages per ISO week
+fn weekly_averages(
+    records: &[&HistoryRecord],
+    value: impl Fn(&HistoryRecord) -> Option<f64>,
+) -> Vec<f64> {
+    let mut days: BTreeMap<NaiveDate, Vec<f64>> = BTreeMap::new();
+    for r in records {
+        if let Some(v) = value(r) {
+            days.entry(r.timestamp.date_naive()).or_default().push(v);
+        }
+    }
+    let mut weeks: BTreeMap<(i32, u32), Vec<f64>> = BTreeMap::new();
+    for (day, values) in days {
+        let week = day.iso_week();
+        weeks
+            .entry((week.year(), week.week()))
+            .or_default()
+            .push(mean(&values));
+    }
+    weeks.values().map(|days| mean(days)).collect()
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+/// Standard deviation as a percentage of the mean, `None` for fewer than two values
+fn variation(values: &[f64]) -> Option<f64> {
+    if values.len() < 2 {
+        return None;
+    }
+    let mean = mean(values);
+    let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / values.len() as f64;
+    Some(variance.sqrt() / mean * 100.0).filter(|p| p.is_finite())
+}
+
+pub struct HistoryStore {
+    path: PathBuf,
+}
+
+impl HistoryStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Opens the store at `path` or at the default location in the user's data directory
+    pub fn open(path: Option<PathBuf>) -> Result<Self, String> {
+        match path.or_else(default_history_path) {
+            Some(path) => Ok(Self::new(path)),
+            None => Err("Could not determine a history location, use --history-file".to_string()),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn load(&self) -> io::Result<Vec<HistoryRecord>> {
+        let file = match fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut records = Vec::new();
+        for (line_nr, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(&line) {
+                Ok(record) => records.push(record),
+                Err(e) => log::warn!(
+                    "skipping malformed history entry on line {}: {e}",
+                    line_nr + 1
+                ),
+            }
+        }
+        Ok(records)
+    }
+
+    /// Appends `records` to the store, assigning each one a fresh id
+    pub fn append(&self, records: Vec<HistoryRecord>) -> io::Result<Vec<HistoryRecord>> {
+        let first_id = self.load()?.iter().map(|r| r.id).max().unwrap_or(0) + 1;
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let mut stored = Vec::with_capacity(records.len());
+        for (id, mut record) in (first_id..).zip(records) {
+            record.id = id;
+            writeln!(file, "{}", serde_json::to_string(&record)?)?;
+            stored.push(record);
+        }
+        Ok(stored)
+    }
+}
+
+fn default_history_path() -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join("cfspeedtest").join(HISTORY_FILE_NAME))
+}
+
+/// Result format of the official Ookla speedtest CLI (`speedtest --format=json`)
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OoklaResult {
+    timestamp: DateTime<Utc>,
+    ping: OoklaPing,
+    download: Option<OoklaTransfer>,
+    upload: Option<OoklaTransfer>,
+    isp: Option<String>,
+    interface: Option<OoklaInterface>,
+    server: Option<OoklaServer>,
+}
+
+#[derive(Deserialize)]
+struct OoklaPing {
+    latency: f64,
+    jitter: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct OoklaTransfer {
+    /// Bytes per second
+    bandwidth: f64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OoklaInterface {
+    external_ip: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct OoklaServer {
+    name: Option<String>,
+    location: Option<String>,
+}
+
+/// Result format of the python speedtest-cli (`speedtest-cli --json`)
+#[derive(Deserialize)]
+struct SpeedtestCliResult {
+    timestamp: DateTime<Utc>,
+    ping: f64,
+    /// Bits per second
+    download: Option<f64>,
+    /// Bits per second
+    upload: Option<f64>,
+    server: Option<SpeedtestCliServer>,
+    client: Option<SpeedtestCliClient>,
+}
+
+#[derive(Deserialize)]
+struct SpeedtestCliServer {
+    name: Option<String>,
+    sponsor: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SpeedtestCliClient {
+    ip: Option<String>,
+    isp: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ExternalResult {
+    Ookla(OoklaResult),
+    SpeedtestCli(SpeedtestCliResult),
+}
+
+impl From<ExternalResult> for HistoryRecord {
+    fn from(result: ExternalResult) -> Self {
+        match result {
+            ExternalResult::Ookla(r) => HistoryRecord {
+                id: 0,
+                timestamp: r.timestamp,
+                source: "ookla".to_string(),
+                server: r.server.and_then(|s| join_names(s.name, s.location)),
+                isp: r.isp,
+                ip: r.interface.and_then(|i| i.external_ip),
+                latency_ms: Some(r.ping.latency),
+                jitter_ms: r.ping.jitter,
+                download_mbit: r.download.map(|d| d.bandwidth * 8.0 / 1_000_000.0),
+                upload_mbit: r.upload.map(|u| u.bandwidth * 8.0 / 1_000_000.0),
+                bufferbloat_grade: None,
+                latency_percentiles: None,
+                mos: None,
+                metadata_cached: false,
+                prewarmed: false,
+                host: None,
+                path: None,
+                meta: BTreeMap::new(),
+            },
+            ExternalResult::SpeedtestCli(r) => {
+                let (ip, isp) = r.client.map(|c| (c.ip, c.isp)).unwrap_or_default();
+                HistoryRecord {
+                    id: 0,
+                    timestamp: r.timestamp,
+                    source: "speedtest-cli".to_string(),
+                    server: r.server.and_then(|s| join_names(s.sponsor, s.name)),
+                    isp,
+                    ip,
+                    latency_ms: Some(r.ping),
+                    jitter_ms: None,
+                    download_mbit: r.download.map(|bits| bits / 1_000_000.0),
+                    upload_mbit: r.upload.map(|bits| bits / 1_000_000.0),
+                    bufferbloat_grade: None,
+                    latency_percentiles: None,
+                    mos: None,
+                    metadata_cached: false,
+                    prewarmed: false,
+                    host: None,
+                    path: None,
+                    meta: BTreeMap::new(),
+                }
+            }
+        }
+    }
+}
+
+fn join_names(first: Option<String>, second: Option<String>) -> Option<String> {
+    match (first, second) {
+        (Some(a), Some(b)) => Some(format!("{a} ({b})")),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Parses results exported by speedtest-cli or the Ookla speedtest CLI.
+///
+/// Accepts a single JSON object, a JSON array of results or one JSON object per line.
+/// Ookla progress events (any `type` other than `result`) are ignored.
+pub fn parse_external_results(contents: &str) -> Result<Vec<HistoryRecord>, String> {
+    let values: Vec<Value> = match serde_json::from_str::<Value>(contents) {
+        Ok(Value::Array(values)) => values,
+        Ok(value) => vec![value],
+        Err(_) => contents
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .enumerate()
+            .map(|(i, line)| {
+                serde_json::from_str(line)
+                    .map_err(|e| format!("Invalid JSON on line {}: {e}", i + 1))
+            })
+            .collect::<Result<_, _>>()?,
+    };
+
+    let mut records = Vec::new();
+    for (i, value) in values.into_iter().enumerate() {
+        if let Some(event_type) = value.get("type").and_then(Value::as_str) {
+            if event_type != "result" {
+                continue;
+            }
+        }
+        let result: ExternalResult = serde_json::from_value(value).map_err(|_| {
+            format!(
+                "Entry {} is neither a speedtest-cli nor an Ookla speedtest result",
+                i + 1
+            )
+        })?;
+        records.push(result.into());
+    }
+    Ok(records)
+}
+
+pub fn import_file(store: &HistoryStore, file: &Path) -> Result<Vec<HistoryRecord>, String> {
+    let contents =
+        fs::read_to_string(file).map_err(|e| format!("Failed to read {}: {e}", file.display()))?;
+    let existing = store.load().map_err(|e| e.to_string())?;
+    let records: Vec<HistoryRecord> = parse_external_results(&contents)?
+        .into_iter()
+        .filter(|r| {
+            // importing the same export twice should not duplicate entries
+            !existing
+                .iter()
+                .any(|e| e.source == r.source && e.timestamp == r.timestamp)
+        })
+        .collect();
+    store.append(records).map_err(|e| e.to_string())
+}
+
+fn format_optional(value: Option<f64>) -> String {
+    value.map_or_else(|| "-".to_string(), |v| format!("{v:.2}"))
+}
+
+fn print_records(records: &[HistoryRecord]) {
+    println!(
+        "{:>5}  {:<20} {:<14} {:>9} {:>10} {:>10}  Server",
+        "Id", "Timestamp", "Source", "Latency", "Down", "Up"
+    );
+    for r in records {
+        println!(
+            "{:>5}  {:<20} {:<14} {:>9} {:>10} {:>10}  {}",
+            r.id,
+            r.timestamp.format("%Y-%m-%d %H:%M:%S"),
+            r.source,
+            format_optional(r.latency_ms),
+            format_optional(r.download_mbit),
+            format_optional(r.upload_mbit),
+            r.server.as_deref().unwrap_or("-"),
+        );
+    }
+}
+
+pub fn run_history_command(
+    command: &HistoryCommand,
+    history_file: Option<PathBuf>,
+) -> Result<(), String> {
+    let store = HistoryStore::open(history_file)?;
+    match command {
+        HistoryCommand::List => {
+            let records = store.load().map_err(|e| e.to_string())?;
+            if records.is_empty() {
+                println!("No results stored in {}", store.path().display());
+            } else {
+                print_records(&records);
+                if let Some(consistency) = Consistency::of(&records, Utc::now()) {
+                    println!("\n{consistency}");
+                }
+            }
+        }
+        HistoryCommand::Import { file } => {
+            let imported = import_file(&store, file)?;
+            println!(
+                "Imported {} result(s) into {}",
+                imported.len(),
+                store.path().display()
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,135 @@
+use crate::metadata::anonymize_ip;
+use crate::preflight::EXPECTED_HOST;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Display,
+    net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket},
+};
+
+/// Machine that ran the test, so that results collected from many hosts stay attributable
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HostInfo {
+    pub hostname: Option<String>,
+    /// Operating system and architecture e.g. `linux x86_64`
+    pub os: String,
+    /// Local address the test traffic leaves from
+    pub local_address: Option<String>,
+    /// Network interface the local address belongs to
+    pub interface: Option<String>,
+}
+
+impl HostInfo {
+    /// `public_ip` selects the IP family of the local address, it should be the address the tests used
+    pub fn collect(public_ip: &str) -> Self {
+        let local_address = local_address(public_ip.parse().ok());
+        Self {
+            hostname: hostname(),
+            os: format!("{} {}", std::env::consts::OS, std::env::consts::ARCH),
+            local_address: local_address.map(|address| address.to_string()),
+            interface: local_address.and_then(interface_name),
+        }
+    }
+
+    /// Drops the hostname and masks the local address
+    pub fn anonymize(&mut self) {
+        self.hostname = None;
+        self.local_address = self.local_address.as_deref().map(anonymize_ip);
+    }
+}
+
+impl Display for HostInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Host: {} ({})",
+            self.hostname.as_deref().unwrap_or("unknown"),
+            self.os
+        )?;
+        if let Some(address) = &self.local_address {
+            write!(f, "\nLocal address: {address}")?;
+            if let Some(interface) = &self.interface {
+                write!(f, " ({interface})")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Connecting a UDP socket only selects the route, no packet is sent
+fn local_address(public_ip: Option<IpAddr>) -> Option<IpAddr> {
+    let remote = (EXPECTED_HOST, 443)
+        .to_socket_addrs()
+        .ok()?
+        .find(|remote| public_ip.is_none_or(|ip| ip.is_ipv4() == remote.is_ipv4()))?;
+    let bind: SocketAddr = if remote.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    let socket = UdpSocket::bind(bind).ok()?;
+    socket.connect(remote).ok()?;
+    socket.local_addr().ok().map(|address| address.ip())
+}
+
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+    let mut buffer = [0u8; 256];
+    if unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) } != 0 {
+        return None;
+    }
+    let end = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+    Some(String::from_utf8_lossy(&buffer[..end]).into_owned())
+}
+
+#[cfg(not(unix))]
+fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+#[cfg(unix)]
+fn interface_name(address: IpAddr) -> Option<String> {
+    let mut interfaces: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut interfaces) } != 0 {
+        return None;
+    }
+    let mut name = None;
+    let mut current = interfaces;
+    while !current.is_null() {
+        let interface = unsafe { &*current };
+        if unsafe { sockaddr_ip(interface.ifa_addr) } == Some(address) {
+            let interface_name = unsafe { std::ffi::CStr::from_ptr(interface.ifa_name) };
+            name = Some(interface_name.to_string_lossy().into_owned());
+            break;
+        }
+        current = interface.ifa_next;
+    }
+    unsafe { libc::freeifaddrs(interfaces) };
+    name
+}
+
+#[cfg(not(unix))]
+fn interface_name(_address: IpAddr) -> Option<String> {
+    None
+}
+
+/// # Safety
+/// `address` must be null or point to a valid sockaddr of the size its family implies
+#[cfg(unix)]
+pub(crate) unsafe fn sockaddr_ip(address: *const libc::sockaddr) -> Option<IpAddr> {
+    if address.is_null() {
+        return None;
+    }
+    match (*address).sa_family as libc::c_int {
+        libc::AF_INET => {
+            let address = &*(address as *const libc::sockaddr_in);
+            Some(IpAddr::from(
+                u32::from_be(address.sin_addr.s_addr).to_be_bytes(),
+            ))
+        }
+        libc::AF_INET6 => {
+            let address = &*(address as *const libc::sockaddr_in6);
+            Some(IpAddr::from(address.sin6_addr.s6_addr))
+        }
+        _ => None,
+    }
+}
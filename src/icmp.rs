@@ -0,0 +1,296 @@
+use serde::Serialize;
+use std::net::IpAddr;
+
+/// ICMP echo round trips to the test server
+#[derive(Clone, Debug, Serialize)]
+pub struct IcmpResult {
+    pub target: IpAddr,
+    /// Round trip times of the answered echo requests
+    pub rtts_ms: Vec<f64>,
+    pub avg_ms: f64,
+    /// Number of echo requests that got no answer in time
+    pub lost: u32,
+}
+
+/// Sends `count` echo requests to `target`. Unprivileged ICMP sockets are tried first, raw
+/// sockets need root or CAP_NET_RAW. Fails if neither is permitted or no echo was answered.
+#[cfg(unix)]
+pub fn ping(target: IpAddr, count: u32) -> Result<IcmpResult, String> {
+    use std::time::Instant;
+
+    let socket = imp::open(target)?;
+    let token = std::process::id().to_be_bytes();
+    let mut rtts_ms = Vec::new();
+    let mut lost = 0;
+    for seq in 0..count as u16 {
+        let start = Instant::now();
+        match imp::echo(&socket, target, seq, &token) {
+            Ok(Some(reply)) if reply.reached => {
+                rtts_ms.push(start.elapsed().as_secs_f64() * 1000.0)
+            }
+            Ok(_) => lost += 1,
+            Err(e) => return Err(format!("ICMP echo to {target} failed: {e}")),
+        }
+    }
+    if rtts_ms.is_empty() {
+        return Err(format!("no ICMP echo reply from {target}"));
+    }
+    let avg_ms = rtts_ms.iter().sum::<f64>() / rtts_ms.len() as f64;
+    Ok(IcmpResult {
+        target,
+        rtts_ms,
+        avg_ms,
+        lost,
+    })
+}
+
+#[cfg(not(unix))]
+pub fn ping(_target: IpAddr, _count: u32) -> Result<IcmpResult, String> {
+    Err("ICMP probes are only supported on unix".to_string())
+}
+
+#[cfg(unix)]
+pub(crate) mod imp {
+    use std::io;
+    use std::net::{IpAddr, SocketAddr, UdpSocket};
+    use std::os::fd::{AsRawFd, FromRawFd};
+    use std::time::{Duration, Instant};
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+    const ECHO_REQUEST_V4: u8 = 8;
+    const ECHO_REPLY_V4: u8 = 0;
+    const UNREACHABLE_V4: u8 = 3;
+    const TIME_EXCEEDED_V4: u8 = 11;
+    const ECHO_REQUEST_V6: u8 = 128;
+    const ECHO_REPLY_V6: u8 = 129;
+    const UNREACHABLE_V6: u8 = 1;
+    const TIME_EXCEEDED_V6: u8 = 3;
+
+    /// Answer to an echo request, `reached` is false if a router on the way answered instead
+    pub(crate) struct Reply {
+        pub from: IpAddr,
+        pub reached: bool,
+    }
+
+    /// The ICMP socket is wrapped in a `UdpSocket` to reuse its datagram send/recv and timeouts
+    pub(crate) fn open(target: IpAddr) -> Result<UdpSocket, String> {
+        let (domain, protocol) = match target {
+            IpAddr::V4(_) => (libc::AF_INET, libc::IPPROTO_ICMP),
+            IpAddr::V6(_) => (libc::AF_INET6, libc::IPPROTO_ICMPV6),
+        };
+        let mut fd = unsafe { libc::socket(domain, libc::SOCK_DGRAM, protocol) };
+        if fd < 0 {
+            fd = unsafe { libc::socket(domain, libc::SOCK_RAW, protocol) };
+        }
+        if fd < 0 {
+            return Err(format!(
+                "no permission for ICMP sockets ({}), allow them with net.ipv4.ping_group_range or run as root",
+                io::Error::last_os_error()
+            ));
+        }
+        let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+        socket
+            .set_read_timeout(Some(TIMEOUT))
+            .map_err(|e| e.to_string())?;
+        Ok(socket)
+    }
+
+    /// Limits the hops of the following echo requests. Routers that drop a request answer with
+    /// time exceeded, unprivileged sockets only see those answers with the error queue enabled.
+    pub(crate) fn set_hop_limit(socket: &UdpSocket, target: IpAddr, hops: u32) -> io::Result<()> {
+        let (level, option) = match target {
+            IpAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_TTL),
+            IpAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_UNICAST_HOPS),
+        };
+        set_option(socket, level, option, hops as libc::c_int)?;
+        #[cfg(target_os = "linux")]
+        match target {
+            IpAddr::V4(_) => set_option(socket, libc::IPPROTO_IP, libc::IP_RECVERR, 1)?,
+            IpAddr::V6(_) => set_option(socket, libc::IPPROTO_IPV6, libc::IPV6_RECVERR, 1)?,
+        }
+        Ok(())
+    }
+
+    fn set_option(
+        socket: &UdpSocket,
+        level: libc::c_int,
+        option: libc::c_int,
+        value: libc::c_int,
+    ) -> io::Result<()> {
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                option,
+                (&value as *const libc::c_int).cast(),
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Sends an echo request and waits for its answer, `None` if nothing arrived within the timeout
+    pub(crate) fn echo(
+        socket: &UdpSocket,
+        target: IpAddr,
+        seq: u16,
+        token: &[u8],
+    ) -> io::Result<Option<Reply>> {
+        let request = match target {
+            IpAddr::V4(_) => ECHO_REQUEST_V4,
+            IpAddr::V6(_) => ECHO_REQUEST_V6,
+        };
+        let mut packet = vec![request, 0, 0, 0, 0, 0];
+        packet.extend_from_slice(&seq.to_be_bytes());
+        packet.extend_from_slice(token);
+        // the kernel fills in the checksum for ICMPv6
+        if target.is_ipv4() {
+            let checksum = checksum(&packet);
+            packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+        }
+        socket.send_to(&packet, SocketAddr::new(target, 0))?;
+
+        let deadline = Instant::now() + TIMEOUT;
+        let mut buffer = [0u8; 1500];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+            let mut poll = libc::pollfd {
+                fd: socket.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let ready = unsafe { libc::poll(&mut poll, 1, remaining.as_millis() as libc::c_int) };
+            if ready < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            #[cfg(target_os = "linux")]
+            if poll.revents & libc::POLLERR != 0 {
+                if let Some(reply) = read_error_queue(socket, seq)? {
+                    return Ok(Some(reply));
+                }
+                continue;
+            }
+            if poll.revents & (libc::POLLIN | libc::POLLERR) == 0 {
+                continue;
+            }
+            let (len, from) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                // ICMP errors about earlier requests are reported once by recv
+                Err(_) => continue,
+            };
+            if let Some(reached) = parse_reply(&buffer[..len], target, seq, token) {
+                return Ok(Some(Reply {
+                    from: from.ip(),
+                    reached: reached || from.ip() == target,
+                }));
+            }
+        }
+    }
+
+    /// Returns whether `packet` answers the echo request `seq`, `Some(false)` for an error that a
+    /// router sent about it
+    fn parse_reply(packet: &[u8], target: IpAddr, seq: u16, token: &[u8]) -> Option<bool> {
+        let (reply, errors) = match target {
+            IpAddr::V4(_) => (ECHO_REPLY_V4, [UNREACHABLE_V4, TIME_EXCEEDED_V4]),
+            IpAddr::V6(_) => (ECHO_REPLY_V6, [UNREACHABLE_V6, TIME_EXCEEDED_V6]),
+        };
+        // raw IPv4 sockets and some systems deliver the IP header as well
+        let icmp = skip_ipv4_header(packet, target);
+        let kind = *icmp.first()?;
+        // the identifier is rewritten by the kernel for unprivileged sockets, so the
+        // sequence number and token identify the answer
+        if kind == reply {
+            return (icmp.get(6..8)? == seq.to_be_bytes()
+                && icmp.get(8..8 + token.len())? == token)
+                .then_some(true);
+        }
+        if !errors.contains(&kind) {
+            return None;
+        }
+        // errors quote the IP header and the first 8 bytes of the dropped request
+        let quoted = match target {
+            IpAddr::V4(_) => skip_ipv4_header(icmp.get(8..)?, target),
+            IpAddr::V6(_) => icmp.get(48..)?,
+        };
+        (quoted.first() == Some(&request_type(target)) && quoted.get(6..8)? == seq.to_be_bytes())
+            .then_some(false)
+    }
+
+    fn request_type(target: IpAddr) -> u8 {
+        match target {
+            IpAddr::V4(_) => ECHO_REQUEST_V4,
+            IpAddr::V6(_) => ECHO_REQUEST_V6,
+        }
+    }
+
+    fn skip_ipv4_header(packet: &[u8], target: IpAddr) -> &[u8] {
+        match packet.first() {
+            Some(first) if target.is_ipv4() && first >> 4 == 4 => {
+                &packet[((first & 0x0f) as usize * 4).min(packet.len())..]
+            }
+            _ => packet,
+        }
+    }
+
+    /// Reads an ICMP error about one of our requests from the socket error queue. The queue holds
+    /// the dropped request and the address of the router that reported it.
+    #[cfg(target_os = "linux")]
+    fn read_error_queue(socket: &UdpSocket, seq: u16) -> io::Result<Option<Reply>> {
+        let mut data = [0u8; 1500];
+        let mut control = [0u8; 512];
+        let mut iov = libc::iovec {
+            iov_base: data.as_mut_ptr().cast(),
+            iov_len: data.len(),
+        };
+        let mut message: libc::msghdr = unsafe { std::mem::zeroed() };
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr().cast();
+        message.msg_controllen = control.len() as _;
+        let len = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut message, libc::MSG_ERRQUEUE) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let request = &data[..len as usize];
+        if request.get(6..8) != Some(&seq.to_be_bytes()) {
+            return Ok(None);
+        }
+        let mut header = unsafe { libc::CMSG_FIRSTHDR(&message) };
+        while !header.is_null() {
+            let cmsg = unsafe { &*header };
+            if matches!(
+                (cmsg.cmsg_level, cmsg.cmsg_type),
+                (libc::IPPROTO_IP, libc::IP_RECVERR) | (libc::IPPROTO_IPV6, libc::IPV6_RECVERR)
+            ) {
+                let error = unsafe { libc::CMSG_DATA(header) } as *const libc::sock_extended_err;
+                let origin = unsafe { (*error).ee_origin };
+                if origin == libc::SO_EE_ORIGIN_ICMP || origin == libc::SO_EE_ORIGIN_ICMP6 {
+                    let from = unsafe { crate::host::sockaddr_ip(libc::SO_EE_OFFENDER(error)) };
+                    return Ok(from.map(|from| Reply {
+                        from,
+                        reached: false,
+                    }));
+                }
+            }
+            header = unsafe { libc::CMSG_NXTHDR(&message, header) };
+        }
+        Ok(None)
+    }
+
+    fn checksum(data: &[u8]) -> u16 {
+        let mut sum: u32 = data
+            .chunks(2)
+            .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]) as u32)
+            .sum();
+        while sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        !(sum as u16)
+    }
+}
@@ -0,0 +1,69 @@
+use crate::history::HistoryRecord;
+use log;
+use reqwest::blocking::Client;
+use serde_json::json;
+
+/// Creates annotations through the Grafana HTTP API
+pub struct GrafanaAnnotator {
+    client: Client,
+    url: String,
+    token: String,
+    dashboard_uid: Option<String>,
+    tags: Vec<String>,
+}
+
+impl GrafanaAnnotator {
+    pub fn new(
+        url: &str,
+        token: &str,
+        dashboard_uid: Option<String>,
+        tags: Vec<String>,
+    ) -> Result<Self, String> {
+        let url = reqwest::Url::parse(url)
+            .and_then(|url| url.join("api/annotations"))
+            .map_err(|e| format!("Invalid Grafana url {url}: {e}"))?;
+        Ok(Self {
+            client: Client::new(),
+            url: url.to_string(),
+            token: token.to_string(),
+            dashboard_uid,
+            tags,
+        })
+    }
+
+    /// Annotates the time of `record` with `text`, tagged as violation if `violated`
+    pub fn annotate(
+        &self,
+        record: &HistoryRecord,
+        text: &str,
+        violated: bool,
+    ) -> Result<(), String> {
+        let mut tags = vec!["cfspeedtest".to_string()];
+        tags.extend(self.tags.iter().cloned());
+        if violated {
+            tags.push("threshold-violation".to_string());
+        }
+        let mut annotation = json!({
+            "time": record.timestamp.timestamp_millis(),
+            "tags": tags,
+            "text": text,
+        });
+        if let Some(uid) = &self.dashboard_uid {
+            annotation["dashboardUID"] = json!(uid);
+        }
+
+        let response = self
+            .client
+            .post(&self.url)
+            .bearer_auth(&self.token)
+            .json(&annotation)
+            .send()
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            let status = response.status();
+            return Err(format!("{status}: {}", response.text().unwrap_or_default()));
+        }
+        log::info!("created Grafana annotation");
+        Ok(())
+    }
+}
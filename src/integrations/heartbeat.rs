@@ -0,0 +1,30 @@
+use crate::history::HistoryRecord;
+use log;
+use reqwest::blocking::Client;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pings a healthchecks.io style url after every run: `url` on success and
+/// `url/fail` on failure. `message` is sent as the request body.
+pub fn ping(
+    url: &str,
+    outcome: &Result<HistoryRecord, String>,
+    message: String,
+) -> Result<(), String> {
+    let url = match outcome {
+        Ok(_) => url.to_string(),
+        Err(_) => format!("{}/fail", url.trim_end_matches('/')),
+    };
+    let body = message;
+    let response = Client::builder()
+        .timeout(TIMEOUT)
+        .build()
+        .and_then(|client| client.post(&url).body(body).send())
+        .map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("{url} returned {}", response.status()));
+    }
+    log::info!("sent heartbeat to {url}");
+    Ok(())
+}
@@ -0,0 +1,172 @@
+use crate::history::HistoryRecord;
+use log;
+use reqwest::{blocking::Client, StatusCode};
+use std::{fmt::Display, sync::Mutex, thread, time::Duration};
+
+const MEASUREMENT: &str = "cfspeedtest";
+const MAX_ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Upper bound of unsent points kept around while InfluxDB is unreachable
+const MAX_BACKLOG: usize = 10_000;
+
+/// Writes results to the InfluxDB v2 write API.
+///
+/// Points that could not be written are kept and sent together with the next
+/// result, so a temporarily unavailable InfluxDB does not leave gaps in daemon mode.
+pub struct InfluxWriter {
+    client: Client,
+    write_url: String,
+    token: String,
+    backlog: Mutex<Vec<String>>,
+}
+
+impl InfluxWriter {
+    pub fn new(url: &str, org: &str, bucket: &str, token: &str) -> Result<Self, String> {
+        // without the trailing slash join replaces the last segment of a path prefix
+        let mut write_url = reqwest::Url::parse(url)
+            .and_then(|mut url| {
+                if !url.path().ends_with('/') {
+                    url.set_path(&format!("{}/", url.path()));
+                }
+                url.join("api/v2/write")
+            })
+            .map_err(|e| format!("Invalid InfluxDB url {url}: {e}"))?;
+        write_url
+            .query_pairs_mut()
+            .append_pair("org", org)
+            .append_pair("bucket", bucket)
+            .append_pair("precision", "s");
+        Ok(Self {
+            client: Client::new(),
+            write_url: write_url.to_string(),
+            token: token.to_string(),
+            backlog: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub fn write(&self, record: &HistoryRecord) -> Result<(), String> {
+        let mut backlog = self.backlog.lock().unwrap();
+        match line_protocol(record) {
+            Some(line) => backlog.push(line),
+            None => log::warn!("skipping InfluxDB point without measured values"),
+        }
+        if backlog.is_empty() {
+            return Ok(());
+        }
+        if backlog.len() > MAX_BACKLOG {
+            let overflow = backlog.len() - MAX_BACKLOG;
+            log::warn!("dropping {overflow} unsent InfluxDB point(s)");
+            backlog.drain(..overflow);
+        }
+
+        let body = backlog.join("\n");
+        let mut attempt = 1;
+        loop {
+            match self.send(&body) {
+                Ok(()) => {
+                    log::info!("wrote {} point(s) to InfluxDB", backlog.len());
+                    backlog.clear();
+                    return Ok(());
+                }
+                Err(WriteError::Rejected(e)) => {
+                    let dropped = backlog.len();
+                    backlog.clear();
+                    return Err(format!("{e}, dropping {dropped} rejected point(s)"));
+                }
+                Err(e) if attempt < MAX_ATTEMPTS => {
+                    log::warn!("InfluxDB write failed ({e}), retrying");
+                    thread::sleep(RETRY_DELAY * 2u32.pow(attempt - 1));
+                    attempt += 1;
+                }
+                Err(e) => {
+                    return Err(format!(
+                        "{e}, keeping {} point(s) for the next attempt",
+                        backlog.len()
+                    ))
+                }
+            }
+        }
+    }
+
+    fn send(&self, body: &str) -> Result<(), WriteError> {
+        let response = self
+            .client
+            .post(&self.write_url)
+            .header("Authorization", format!("Token {}", self.token))
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(body.to_string())
+            .send()
+            .map_err(|e| WriteError::Unavailable(e.to_string()))?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let message = format!("{status}: {}", response.text().unwrap_or_default());
+        // retrying doesn't help against malformed points or a wrong token, rate limits pass
+        if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS {
+            Err(WriteError::Rejected(message))
+        } else {
+            Err(WriteError::Unavailable(message))
+        }
+    }
+}
+
+enum WriteError {
+    /// InfluxDB refused the points, sending them again fails the same way
+    Rejected(String),
+    /// Network errors, rate limits and server errors
+    Unavailable(String),
+}
+
+impl Display for WriteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Rejected(message) | Self::Unavailable(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+/// Renders `record` as a single InfluxDB line protocol point with second precision, `None`
+/// without a finite value as the line protocol needs at least one field
+pub fn line_protocol(record: &HistoryRecord) -> Option<String> {
+    let mut line = String::from(MEASUREMENT);
+    let tags = [
+        ("source", Some(&record.source)),
+        ("server", record.server.as_ref()),
+        ("isp", record.isp.as_ref()),
+    ];
+    for (key, value) in tags {
+        if let Some(value) = value.filter(|v| !v.is_empty()) {
+            line.push_str(&format!(",{key}={}", escape(value)));
+        }
+    }
+    let fields: Vec<String> = [
+        ("latency_ms", record.latency_ms),
+        ("jitter_ms", record.jitter_ms),
+        ("download_mbit", record.download_mbit),
+        ("upload_mbit", record.upload_mbit),
+    ]
+    .iter()
+    .filter_map(|(key, value)| {
+        value
+            .filter(|v| v.is_finite())
+            .map(|v| format!("{key}={v}"))
+    })
+    .collect();
+    if fields.is_empty() {
+        return None;
+    }
+    line.push(' ');
+    line.push_str(&fields.join(","));
+    line.push_str(&format!(" {}", record.timestamp.timestamp()));
+    Some(line)
+}
+
+/// Escapes tag values according to the line protocol rules
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
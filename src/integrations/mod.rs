@@ -0,0 +1,216 @@
+pub mod grafana;
+pub mod heartbeat;
+pub mod influx;
+pub mod mqtt;
+pub mod smtp;
+pub mod syslog;
+pub mod template;
+pub mod webhook;
+
+use crate::history::HistoryRecord;
+use crate::{AnnotateOn, IntegrationOptions, ThresholdOptions};
+use grafana::GrafanaAnnotator;
+use influx::InfluxWriter;
+use log;
+use smtp::SmtpServer;
+use template::{default_message, template_context, Templates};
+
+const WEBHOOK_TEMPLATE: &str = "webhook";
+const MESSAGE_TEMPLATE: &str = "message";
+
+/// All integrations configured on the command line.
+///
+/// Create it once and reuse it for every run, some integrations keep state
+/// between runs (e.g. unsent InfluxDB points).
+pub struct Integrations {
+    options: IntegrationOptions,
+    influx: Option<InfluxWriter>,
+    grafana: Option<GrafanaAnnotator>,
+    smtp: Option<SmtpServer>,
+    templates: Templates,
+}
+
+impl Integrations {
+    pub fn new(options: IntegrationOptions) -> Result<Self, String> {
+        let influx = match (
+            &options.influx_url,
+            &options.influx_org,
+            &options.influx_bucket,
+            &options.influx_token,
+        ) {
+            (Some(url), Some(org), Some(bucket), Some(token)) => {
+                Some(InfluxWriter::new(url, org, bucket, token)?)
+            }
+            _ => None,
+        };
+        let grafana = match (&options.grafana_url, &options.grafana_token) {
+            (Some(url), Some(token)) => Some(GrafanaAnnotator::new(
+                url,
+                token,
+                options.grafana_dashboard_uid.clone(),
+                options.grafana_tags.clone(),
+            )?),
+            _ => None,
+        };
+        let smtp = match (&options.smtp_url, &options.smtp_from) {
+            (Some(url), Some(from)) => Some(SmtpServer::new(
+                url,
+                options.smtp_password.as_deref(),
+                options.smtp_insecure,
+                from,
+                &options.smtp_to,
+            )?),
+            _ => None,
+        };
+        let mut templates = Templates::new();
+        if let Some(path) = &options.webhook_template {
+            templates.register_file(WEBHOOK_TEMPLATE, path)?;
+        }
+        if let Some(path) = &options.message_template {
+            templates.register_file(MESSAGE_TEMPLATE, path)?;
+        }
+        Ok(Self {
+            options,
+            influx,
+            grafana,
+            smtp,
+            templates,
+        })
+    }
+
+    /// Publishes the outcome of a finished run to all configured integrations.
+    /// Failing integrations are logged and never affect the run itself.
+    pub fn publish(&self, outcome: &Result<HistoryRecord, String>) {
+        let violations = match outcome {
+            Ok(record) => threshold_violations(&self.options.thresholds, record),
+            Err(_) => Vec::new(),
+        };
+        if let Some(target) = self.options.log_results {
+            if let Err(e) = syslog::log_result(
+                target,
+                self.options.log_results_format,
+                outcome,
+                &violations,
+            ) {
+                log::error!("failed to log result to {target:?}: {e}");
+            }
+        }
+        let context = template_context(outcome, &violations);
+        let message = self.message(outcome, &violations, &context);
+        if let Some(url) = &self.options.webhook_url {
+            let body = if self.templates.has(WEBHOOK_TEMPLATE) {
+                self.templates.render(WEBHOOK_TEMPLATE, &context)
+            } else {
+                Ok(webhook::default_body(&context))
+            };
+            if let Err(e) =
+                body.and_then(|body| webhook::send(url, &self.options.webhook_content_type, body))
+            {
+                log::error!("failed to send webhook: {e}");
+            }
+        }
+        if let Some(url) = &self.options.heartbeat_url {
+            if let Err(e) = heartbeat::ping(url, outcome, message.clone()) {
+                log::error!("failed to send heartbeat: {e}");
+            }
+        }
+        if let Some(smtp) = &self.smtp {
+            let alert = outcome.is_err() || !violations.is_empty();
+            if self.options.smtp_send == AnnotateOn::Always || alert {
+                let subject = match outcome {
+                    Ok(_) if alert => "cfspeedtest: threshold violated".to_string(),
+                    Ok(record) => format!("cfspeedtest: {}", record.summary()),
+                    Err(_) => "cfspeedtest: run failed".to_string(),
+                };
+                if let Err(e) = smtp.send(&subject, &message) {
+                    log::error!("failed to send email: {e}");
+                }
+            }
+        }
+        let Ok(record) = outcome else {
+            return;
+        };
+        if let Some(url) = &self.options.mqtt_url {
+            if let Err(e) = mqtt::publish(
+                url,
+                &self.options.mqtt_topic,
+                self.options.mqtt_ha_discovery,
+                record,
+            ) {
+                log::error!("failed to publish result via MQTT: {e}");
+            }
+        }
+        if let Some(influx) = &self.influx {
+            if let Err(e) = influx.write(record) {
+                log::error!("failed to write result to InfluxDB: {e}");
+            }
+        }
+        if let Some(grafana) = &self.grafana {
+            if self.options.grafana_annotate == AnnotateOn::Always || !violations.is_empty() {
+                if let Err(e) = grafana.annotate(record, &message, !violations.is_empty()) {
+                    log::error!("failed to create Grafana annotation: {e}");
+                }
+            }
+        }
+    }
+
+    /// Human readable notification text, rendered from --message-template if configured
+    fn message(
+        &self,
+        outcome: &Result<HistoryRecord, String>,
+        violations: &[String],
+        context: &serde_json::Value,
+    ) -> String {
+        if self.templates.has(MESSAGE_TEMPLATE) {
+            match self.templates.render(MESSAGE_TEMPLATE, context) {
+                Ok(message) => return message,
+                Err(e) => log::error!("{e}"),
+            }
+        }
+        default_message(outcome, violations)
+    }
+}
+
+/// Describes every threshold `record` does not stay within
+pub fn threshold_violations(thresholds: &ThresholdOptions, record: &HistoryRecord) -> Vec<String> {
+    let mut violations = Vec::new();
+    if let (Some(min), Some(download)) = (thresholds.min_download, record.download_mbit) {
+        if download < min {
+            violations.push(format!("download {download:.2} < {min} mbit/s"));
+        }
+    }
+    if let (Some(min), Some(upload)) = (thresholds.min_upload, record.upload_mbit) {
+        if upload < min {
+            violations.push(format!("upload {upload:.2} < {min} mbit/s"));
+        }
+    }
+    if let (Some(max), Some(latency)) = (thresholds.max_latency, record.latency_ms) {
+        if latency > max {
+            violations.push(format!("latency {latency:.2} > {max} ms"));
+        }
+    }
+    violations
+}
+
+/// Decodes the %XX escapes of a url's user name or password
+pub(crate) fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = s
+            .get(i + 1..i + 3)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
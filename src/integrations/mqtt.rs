@@ -0,0 +1,117 @@
+use crate::history::HistoryRecord;
+use crate::integrations::percent_decode;
+use log;
+use reqwest::Url;
+use rumqttc::{Client, Event, MqttOptions, Outgoing, Packet, QoS, RecvTimeoutError};
+use serde_json::json;
+use std::time::{Duration, Instant};
+
+const DEFAULT_PORT: u16 = 1883;
+/// Time the broker gets to acknowledge all messages of a result
+const TIMEOUT: Duration = Duration::from_secs(10);
+const DISCOVERY_PREFIX: &str = "homeassistant";
+
+/// Sensors announced via Home Assistant MQTT discovery: (result field, name, unit)
+const SENSORS: [(&str, &str, &str); 4] = [
+    ("download_mbit", "Download", "Mbit/s"),
+    ("upload_mbit", "Upload", "Mbit/s"),
+    ("latency_ms", "Latency", "ms"),
+    ("jitter_ms", "Jitter", "ms"),
+];
+
+/// Publishes `record` as a retained JSON message to `topic` on the broker at `url`
+/// (`mqtt://[user:password@]host[:port]`, with percent-encoded credentials), optionally preceded
+/// by Home Assistant discovery messages for the individual values.
+pub fn publish(
+    url: &str,
+    topic: &str,
+    ha_discovery: bool,
+    record: &HistoryRecord,
+) -> Result<(), String> {
+    let options = parse_url(url)?;
+    let mut messages = Vec::new();
+    if ha_discovery {
+        messages.extend(discovery_messages(topic));
+    }
+    messages.push((
+        topic.to_string(),
+        serde_json::to_vec(record).map_err(|e| e.to_string())?,
+    ));
+
+    let (client, mut connection) = Client::new(options, messages.len() + 1);
+    for (topic, payload) in &messages {
+        client
+            .publish(topic, QoS::AtLeastOnce, true, payload.clone())
+            .map_err(|e| e.to_string())?;
+    }
+
+    // wait until the broker acknowledged every message before disconnecting
+    let deadline = Instant::now() + TIMEOUT;
+    let mut pending_acks = messages.len();
+    loop {
+        let remaining = deadline
+            .checked_duration_since(Instant::now())
+            .ok_or("timed out waiting for the broker to acknowledge the result")?;
+        let notification = match connection.recv_timeout(remaining) {
+            Ok(notification) => notification,
+            Err(RecvTimeoutError::Timeout) => {
+                return Err("timed out waiting for the broker to acknowledge the result".into())
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        match notification.map_err(|e| e.to_string())? {
+            Event::Incoming(Packet::PubAck(_)) if pending_acks > 0 => {
+                pending_acks -= 1;
+                if pending_acks == 0 {
+                    client.disconnect().map_err(|e| e.to_string())?;
+                }
+            }
+            Event::Outgoing(Outgoing::Disconnect) => break,
+            _ => {}
+        }
+    }
+    log::info!("published result to MQTT topic {topic}");
+    Ok(())
+}
+
+fn parse_url(url: &str) -> Result<MqttOptions, String> {
+    let url = Url::parse(url).map_err(|e| format!("Invalid MQTT url {url}: {e}"))?;
+    if url.scheme() != "mqtt" {
+        return Err("MQTT url needs to start with mqtt://".to_string());
+    }
+    let host = url.host_str().ok_or("MQTT url is missing the host")?;
+    let client_id = format!("cfspeedtest-{}", std::process::id());
+    let mut options = MqttOptions::new(client_id, host, url.port().unwrap_or(DEFAULT_PORT));
+    options.set_keep_alive(Duration::from_secs(30));
+    if !url.username().is_empty() {
+        options.set_credentials(
+            percent_decode(url.username()),
+            percent_decode(url.password().unwrap_or_default()),
+        );
+    }
+    Ok(options)
+}
+
+fn discovery_messages(state_topic: &str) -> Vec<(String, Vec<u8>)> {
+    let node_id = state_topic.replace(['/', '#', '+'], "_");
+    SENSORS
+        .iter()
+        .map(|(field, name, unit)| {
+            let config = json!({
+                "name": name,
+                "state_topic": state_topic,
+                "value_template": format!("{{{{ value_json.{field} }}}}"),
+                "unit_of_measurement": unit,
+                "unique_id": format!("{node_id}_{field}"),
+                "device": {
+                    "identifiers": [node_id],
+                    "name": "cfspeedtest",
+                },
+            });
+            (
+                format!("{DISCOVERY_PREFIX}/sensor/{node_id}/{field}/config"),
+                config.to_string().into_bytes(),
+            )
+        })
+        .collect()
+}
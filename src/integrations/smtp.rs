@@ -0,0 +1,99 @@
+use crate::integrations::percent_decode;
+use lettre::message::{header::ContentType, Mailbox, Message};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::client::{Tls, TlsParameters};
+use lettre::transport::smtp::extension::ClientId;
+use lettre::{SmtpTransport, Transport};
+use log;
+use reqwest::Url;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+const SMTPS_PORT: u16 = 465;
+const SUBMISSION_PORT: u16 = 587;
+
+/// Mail server, account and addresses from the --smtp-* options
+pub struct SmtpServer {
+    transport: SmtpTransport,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+}
+
+impl SmtpServer {
+    /// Parses `smtps://[user[:password]@]host[:port]` or `smtp://…`, `password` overrides the one
+    /// in the url. smtp:// requires STARTTLS unless `allow_plaintext` is set.
+    pub fn new(
+        url: &str,
+        password: Option<&str>,
+        allow_plaintext: bool,
+        from: &str,
+        to: &[String],
+    ) -> Result<Self, String> {
+        let url = Url::parse(url).map_err(|e| format!("Invalid SMTP url {url}: {e}"))?;
+        let host = url
+            .host_str()
+            .ok_or("SMTP url is missing the host")?
+            .trim_matches(['[', ']']);
+        let tls_parameters = TlsParameters::new(host.to_string()).map_err(|e| e.to_string())?;
+        let (tls, default_port) = match url.scheme() {
+            "smtps" => (Tls::Wrapper(tls_parameters), SMTPS_PORT),
+            "smtp" if allow_plaintext => (Tls::Opportunistic(tls_parameters), SUBMISSION_PORT),
+            "smtp" => (Tls::Required(tls_parameters), SUBMISSION_PORT),
+            _ => return Err("SMTP url needs to start with smtps:// or smtp://".to_string()),
+        };
+        let from = mailbox(from)?;
+        let mut transport = SmtpTransport::builder_dangerous(host)
+            .port(url.port().unwrap_or(default_port))
+            .tls(tls)
+            .timeout(Some(TIMEOUT))
+            .hello_name(ClientId::Domain(from.email.domain().to_string()));
+        if !url.username().is_empty() {
+            let password = password
+                .map(str::to_string)
+                .unwrap_or_else(|| percent_decode(url.password().unwrap_or_default()));
+            transport =
+                transport.credentials(Credentials::new(percent_decode(url.username()), password));
+        }
+        Ok(Self {
+            transport: transport.build(),
+            from,
+            to: to.iter().map(|to| mailbox(to)).collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Sends a plain text email to every recipient, non-ASCII headers are encoded by lettre
+    pub fn send(&self, subject: &str, body: &str) -> Result<(), String> {
+        let mut message = Message::builder()
+            .from(self.from.clone())
+            .subject(single_line(subject)?)
+            .header(ContentType::TEXT_PLAIN);
+        for to in &self.to {
+            message = message.to(to.clone());
+        }
+        let message = message.body(body.to_string()).map_err(|e| e.to_string())?;
+        self.transport.send(&message).map_err(|e| e.to_string())?;
+        log::info!(
+            "sent email to {}",
+            self.to
+                .iter()
+                .map(|to| to.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        Ok(())
+    }
+}
+
+/// `address` or `Name <address>`, line breaks are rejected so that no headers can be injected
+fn mailbox(address: &str) -> Result<Mailbox, String> {
+    single_line(address)
+        .and_then(|address| address.parse().map_err(|e| format!("{address}: {e}")))
+        .map_err(|e| format!("invalid email address {e}"))
+}
+
+fn single_line(value: &str) -> Result<&str, String> {
+    if value.contains(['\r', '\n']) {
+        return Err(format!("{value:?} contains a line break"));
+    }
+    Ok(value)
+}
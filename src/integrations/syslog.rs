@@ -0,0 +1,180 @@
+use crate::history::HistoryRecord;
+use clap::ValueEnum;
+use serde_json::{json, Map, Value};
+
+const IDENTIFIER: &str = "cfspeedtest";
+#[cfg(unix)]
+const SYSLOG_SOCKET: &str = "/dev/log";
+#[cfg(unix)]
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogTarget {
+    /// Local syslog daemon via /dev/log
+    Syslog,
+    /// systemd journal with one journal field per value
+    Journald,
+    /// Windows Application event log
+    Eventlog,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordFormat {
+    /// Space separated key=value pairs
+    Kv,
+    /// A single JSON object
+    Json,
+}
+
+#[derive(Clone, Copy)]
+enum Severity {
+    Error = 3,
+    Warning = 4,
+    Info = 6,
+}
+
+/// Emits one structured record per run to the system logger
+pub fn log_result(
+    target: LogTarget,
+    format: RecordFormat,
+    outcome: &Result<HistoryRecord, String>,
+    violations: &[String],
+) -> Result<(), String> {
+    let mut fields = Map::new();
+    let severity = match outcome {
+        Ok(record) => {
+            fields.insert("status".to_string(), json!("ok"));
+            if let Value::Object(values) =
+                serde_json::to_value(record).map_err(|e| e.to_string())?
+            {
+                fields.extend(values.into_iter().filter(|(_, v)| !v.is_null()));
+            }
+            if violations.is_empty() {
+                Severity::Info
+            } else {
+                fields.insert("violations".to_string(), json!(violations.join("; ")));
+                Severity::Warning
+            }
+        }
+        Err(e) => {
+            fields.insert("status".to_string(), json!("failed"));
+            fields.insert("error".to_string(), json!(e));
+            Severity::Error
+        }
+    };
+    let message = match format {
+        RecordFormat::Kv => format_kv(&fields),
+        RecordFormat::Json => Value::Object(fields.clone()).to_string(),
+    };
+    match target {
+        LogTarget::Syslog => send_syslog(severity, &message),
+        LogTarget::Journald => send_journald(severity, &message, &fields),
+        LogTarget::Eventlog => send_eventlog(severity, &message),
+    }
+}
+
+fn format_kv(fields: &Map<String, Value>) -> String {
+    fields
+        .iter()
+        .map(|(key, value)| {
+            let value = match value {
+                Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            if value.contains([' ', '"', '=']) {
+                format!("{key}=\"{}\"", value.replace('"', "\\\""))
+            } else {
+                format!("{key}={value}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(unix)]
+fn send_syslog(severity: Severity, message: &str) -> Result<(), String> {
+    use std::os::unix::net::UnixDatagram;
+    // facility "user" (1)
+    let priority = 8 + severity as u8;
+    let line = format!(
+        "<{priority}>{IDENTIFIER}[{}]: {}",
+        std::process::id(),
+        message.replace('\n', " ")
+    );
+    let socket = UnixDatagram::unbound().map_err(|e| e.to_string())?;
+    socket
+        .send_to(line.as_bytes(), SYSLOG_SOCKET)
+        .map_err(|e| format!("{SYSLOG_SOCKET}: {e}"))?;
+    Ok(())
+}
+
+/// Uses the native journal protocol so every value ends up in its own queryable field
+/// e.g. `journalctl SYSLOG_IDENTIFIER=cfspeedtest CFSPEEDTEST_STATUS=failed`
+#[cfg(unix)]
+fn send_journald(
+    severity: Severity,
+    message: &str,
+    fields: &Map<String, Value>,
+) -> Result<(), String> {
+    use std::os::unix::net::UnixDatagram;
+    let mut datagram = format!(
+        "SYSLOG_IDENTIFIER={IDENTIFIER}\nPRIORITY={}\nMESSAGE={}\n",
+        severity as u8,
+        message.replace('\n', " ")
+    );
+    for (key, value) in fields {
+        let value = match value {
+            Value::String(s) => s.replace('\n', " "),
+            other => other.to_string(),
+        };
+        datagram.push_str(&format!("CFSPEEDTEST_{}={value}\n", key.to_uppercase()));
+    }
+    let socket = UnixDatagram::unbound().map_err(|e| e.to_string())?;
+    socket
+        .send_to(datagram.as_bytes(), JOURNALD_SOCKET)
+        .map_err(|e| format!("{JOURNALD_SOCKET}: {e}"))?;
+    Ok(())
+}
+
+/// Uses the eventcreate tool that ships with Windows. Registering the event source the first time
+/// needs an elevated prompt, later runs work without.
+#[cfg(windows)]
+fn send_eventlog(severity: Severity, message: &str) -> Result<(), String> {
+    use std::process::Command;
+    // eventcreate only accepts ids 1 to 1000, the severity is reused so events can be filtered
+    let event_type = match severity {
+        Severity::Error => "ERROR",
+        Severity::Warning => "WARNING",
+        Severity::Info => "INFORMATION",
+    };
+    let id = (severity as u8).to_string();
+    let output = Command::new("eventcreate")
+        .args(["/L", "APPLICATION", "/SO", IDENTIFIER, "/T", event_type])
+        .args(["/ID", id.as_str(), "/D", message])
+        .output()
+        .map_err(|e| format!("failed to run eventcreate: {e}"))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).trim().to_string())
+    }
+}
+
+#[cfg(not(windows))]
+fn send_eventlog(_severity: Severity, _message: &str) -> Result<(), String> {
+    Err("the event log is only supported on Windows".to_string())
+}
+
+#[cfg(not(unix))]
+fn send_syslog(_severity: Severity, _message: &str) -> Result<(), String> {
+    Err("syslog is only supported on unix systems".to_string())
+}
+
+#[cfg(not(unix))]
+fn send_journald(
+    _severity: Severity,
+    _message: &str,
+    _fields: &Map<String, Value>,
+) -> Result<(), String> {
+    Err("journald is only supported on unix systems".to_string())
+}
@@ -0,0 +1,79 @@
+use crate::history::HistoryRecord;
+use handlebars::{handlebars_helper, no_escape, Handlebars};
+use serde_json::{json, Value};
+use std::{fs, path::Path};
+
+handlebars_helper!(json_helper: |value: Json| value.to_string());
+handlebars_helper!(round_helper: |value: f64, digits: u64| format!("{value:.0$}", digits as usize));
+
+/// User provided handlebars templates for integration payloads.
+///
+/// Templates are rendered with the fields of the result record plus `status`
+/// (`ok` or `failed`), `error`, `violations` and `summary`. Values are inserted
+/// as is, use `{{json value}}` to insert a quoted JSON value and
+/// `{{round value 1}}` to round numbers.
+pub struct Templates {
+    registry: Handlebars<'static>,
+}
+
+impl Templates {
+    pub fn new() -> Self {
+        let mut registry = Handlebars::new();
+        registry.register_escape_fn(no_escape);
+        registry.register_helper("json", Box::new(json_helper));
+        registry.register_helper("round", Box::new(round_helper));
+        Self { registry }
+    }
+
+    pub fn register_file(&mut self, name: &str, path: &Path) -> Result<(), String> {
+        let template = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read template {}: {e}", path.display()))?;
+        self.registry
+            .register_template_string(name, template)
+            .map_err(|e| format!("Invalid template {}: {e}", path.display()))
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.registry.has_template(name)
+    }
+
+    pub fn render(&self, name: &str, context: &Value) -> Result<String, String> {
+        self.registry
+            .render(name, context)
+            .map_err(|e| format!("Failed to render template {name}: {e}"))
+    }
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Values available to templates for the outcome of a run
+pub fn template_context(outcome: &Result<HistoryRecord, String>, violations: &[String]) -> Value {
+    let mut context = match outcome {
+        Ok(record) => {
+            let mut context = serde_json::to_value(record).unwrap_or_else(|_| json!({}));
+            context["status"] = json!("ok");
+            context["summary"] = json!(record.summary());
+            context
+        }
+        Err(e) => json!({ "status": "failed", "error": e }),
+    };
+    context["violations"] = json!(violations);
+    context
+}
+
+/// Default notification text when no message template is configured
+pub fn default_message(outcome: &Result<HistoryRecord, String>, violations: &[String]) -> String {
+    match outcome {
+        Ok(record) if violations.is_empty() => record.summary(),
+        Ok(record) => format!(
+            "{}\nThreshold violated: {}",
+            record.summary(),
+            violations.join(", ")
+        ),
+        Err(e) => e.clone(),
+    }
+}
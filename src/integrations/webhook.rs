@@ -0,0 +1,32 @@
+use log;
+use reqwest::blocking::Client;
+use serde_json::Value;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// POSTs `body` to `url`. Without a template the body is the JSON template context.
+pub fn send(url: &str, content_type: &str, body: String) -> Result<(), String> {
+    let response = Client::builder()
+        .timeout(TIMEOUT)
+        .build()
+        .and_then(|client| {
+            client
+                .post(url)
+                .header("Content-Type", content_type)
+                .body(body)
+                .send()
+        })
+        .map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        let status = response.status();
+        return Err(format!("{status}: {}", response.text().unwrap_or_default()));
+    }
+    log::info!("sent webhook to {url}");
+    Ok(())
+}
+
+/// Default webhook body: the template context as JSON
+pub fn default_body(context: &Value) -> String {
+    context.to_string()
+}
@@ -0,0 +1,89 @@
+use crate::speedtest::PayloadSize;
+use crate::{OutputFormat, SpeedTestCLIOptions};
+use std::io::{self, BufRead, IsTerminal, Write};
+
+/// Asks for the test profile, direction, IP family and output format and applies the answers
+/// to `options`. Empty answers keep the default choice.
+pub fn prompt_options(options: &mut SpeedTestCLIOptions) -> Result<(), String> {
+    if !io::stdin().is_terminal() {
+        return Err("--interactive needs a terminal".to_string());
+    }
+
+    match choose(
+        "Which test should run?",
+        &[
+            "Quick (payloads up to 10MB, fewer samples)",
+            "Standard",
+            "Thorough (payloads up to 100MB, more samples)",
+        ],
+        1,
+    )? {
+        0 => {
+            options.nr_tests = 4;
+            options.nr_latency_tests = 10;
+            options.max_payload_size = PayloadSize::M10;
+        }
+        2 => {
+            options.nr_tests = 20;
+            options.nr_latency_tests = 50;
+            options.max_payload_size = PayloadSize::M100;
+        }
+        _ => {}
+    }
+
+    let direction = choose(
+        "What should be measured?",
+        &["Download and upload", "Download only", "Upload only"],
+        0,
+    )?;
+    options.download_only = direction == 1;
+    options.upload_only = direction == 2;
+
+    let family = choose(
+        "Which IP version should be used?",
+        &["Whatever the system prefers", "IPv4", "IPv6"],
+        0,
+    )?;
+    options.ipv4 = family == 1;
+    options.ipv6 = family == 2;
+
+    let formats = [
+        ("Summary table", OutputFormat::StdOut),
+        ("Colored summary with quality hints", OutputFormat::Pretty),
+        ("JSON", OutputFormat::JsonPretty),
+        ("CSV", OutputFormat::Csv),
+    ];
+    let labels: Vec<&str> = formats.iter().map(|(label, _)| *label).collect();
+    options.output_format = formats[choose("How should the result be shown?", &labels, 0)?].1;
+    println!();
+    Ok(())
+}
+
+/// Returns the index of the chosen entry, asking again until the answer is valid
+fn choose(question: &str, choices: &[&str], default: usize) -> Result<usize, String> {
+    println!("\n{question}");
+    for (i, choice) in choices.iter().enumerate() {
+        let marker = if i == default { " (default)" } else { "" };
+        println!("  {}) {choice}{marker}", i + 1);
+    }
+    loop {
+        print!("> ");
+        io::stdout().flush().map_err(|e| e.to_string())?;
+        let mut answer = String::new();
+        let read = io::stdin()
+            .lock()
+            .read_line(&mut answer)
+            .map_err(|e| format!("failed to read from stdin: {e}"))?;
+        if read == 0 {
+            return Err("no answer given".to_string());
+        }
+        let answer = answer.trim();
+        if answer.is_empty() {
+            return Ok(default);
+        }
+        match answer.parse::<usize>() {
+            Ok(n) if (1..=choices.len()).contains(&n) => return Ok(n - 1),
+            _ => println!("Please enter a number between 1 and {}", choices.len()),
+        }
+    }
+}
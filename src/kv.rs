@@ -0,0 +1,82 @@
+use crate::measurements::{calc_jitter, headline_mbit};
+use crate::speedtest::{SpeedTestResult, TestType};
+
+/// Prints the result as one `metric=value` pair per line for --output-format kv.
+/// Metrics that weren't measured are left out.
+pub fn print_kv(result: &SpeedTestResult) {
+    for (key, value) in kv_pairs(result) {
+        println!("{key}={value}");
+    }
+}
+
+pub(crate) fn kv_pairs(result: &SpeedTestResult) -> Vec<(String, String)> {
+    let metadata = &result.metadata;
+    let latency = &result.latency;
+    let mut numbers: Vec<(String, Option<f64>)> = vec![
+        (
+            "download_mbps".to_string(),
+            headline_mbit(&result.measurements, TestType::Download),
+        ),
+        (
+            "upload_mbps".to_string(),
+            headline_mbit(&result.measurements, TestType::Upload),
+        ),
+        (
+            "latency_ms".to_string(),
+            Some(latency.avg_latency).filter(|l| l.is_finite()),
+        ),
+        ("jitter_ms".to_string(), calc_jitter(&latency.latencies())),
+    ];
+    if let Some(percentiles) = latency.percentiles {
+        for (label, latency_ms) in percentiles.rows() {
+            let label = label.replace('.', "");
+            numbers.push((format!("latency_{label}_ms"), Some(latency_ms)));
+        }
+    }
+    numbers.push(("mos".to_string(), result.mos()));
+    numbers.push((
+        "dns_lookup_ms".to_string(),
+        result.dns_lookup.as_ref().map(|d| d.duration_ms),
+    ));
+    numbers.push((
+        "icmp_rtt_ms".to_string(),
+        result.icmp.as_ref().map(|i| i.avg_ms),
+    ));
+    numbers.push((
+        "udp_rtt_ms".to_string(),
+        result.stun.as_ref().map(|s| s.avg_ms),
+    ));
+    numbers.push(("colo_distance_km".to_string(), metadata.colo_distance_km));
+    for capacity in &result.capacity {
+        let direction = format!("{:?}", capacity.test_type).to_lowercase();
+        numbers.push((format!("{direction}_capacity_mbps"), Some(capacity.mbit)));
+    }
+    let mut pairs: Vec<(String, String)> = numbers
+        .into_iter()
+        .filter_map(|(key, value)| value.map(|v| (key, format!("{v:.2}"))))
+        .collect();
+    if let Some(bufferbloat) = &result.bufferbloat {
+        pairs.push((
+            "bufferbloat_grade".to_string(),
+            bufferbloat.grade.to_string(),
+        ));
+    }
+    pairs.push((
+        "failed_samples".to_string(),
+        result.failed_measurements.to_string(),
+    ));
+    pairs.push(("incomplete".to_string(), result.is_incomplete().to_string()));
+    pairs.push(("colo".to_string(), metadata.colo.clone()));
+    pairs.push(("ip".to_string(), metadata.ip.clone()));
+    pairs.push(("asn".to_string(), metadata.asn.clone()));
+    if let Some(as_organization) = &metadata.as_organization {
+        pairs.push(("as_organization".to_string(), as_organization.clone()));
+    }
+    if let Some(isp) = &metadata.isp {
+        pairs.push(("isp".to_string(), isp.clone()));
+    }
+    for (key, value) in &result.meta {
+        pairs.push((format!("meta_{key}"), value.clone()));
+    }
+    pairs
+}
@@ -1,31 +1,218 @@
-use cfspeedtest::speedtest;
+use cfspeedtest::chart::write_chart;
+use cfspeedtest::collector::{run_collector, DEFAULT_COLLECT_ADDR};
+use cfspeedtest::colos::run_list_colos;
+use cfspeedtest::daemon::run_daemon;
+use cfspeedtest::dns::run_dns_benchmark;
+use cfspeedtest::exporter::{spawn_exporter, Metrics};
+use cfspeedtest::healthcheck::run_healthcheck;
+use cfspeedtest::history::{run_history_command, HistoryRecord, HistoryStore};
+use cfspeedtest::integrations::Integrations;
+use cfspeedtest::interactive::prompt_options;
+use cfspeedtest::preflight::{check_connection, run_checks};
+use cfspeedtest::retry::RetryPolicy;
+use cfspeedtest::server::{run_server, DEFAULT_ADDR};
+use cfspeedtest::speedtest::{self, build_client};
+use cfspeedtest::tcp::{run_tcp_server, run_tcp_test};
+use cfspeedtest::warp::run_warp_comparison;
+use cfspeedtest::Backend;
+use cfspeedtest::Command;
+use cfspeedtest::DaemonOptions;
 use cfspeedtest::OutputFormat;
 use cfspeedtest::SpeedTestCLIOptions;
+use chrono::Utc;
 use clap::Parser;
-use std::net::IpAddr;
+use std::fs::OpenOptions;
+use std::sync::{Arc, Mutex};
 
 use speedtest::speed_test;
 
 fn main() {
-    env_logger::init();
-    let options = SpeedTestCLIOptions::parse();
-    if options.output_format == OutputFormat::StdOut {
-        println!("Starting Cloudflare speed test");
-    }
-    let client;
-    if options.ipv4 {
-        client = reqwest::blocking::Client::builder()
-            .local_address("0.0.0.0".parse::<IpAddr>().unwrap())
-            .build();
-    } else if options.ipv6 {
-        client = reqwest::blocking::Client::builder()
-            .local_address("::1".parse::<IpAddr>().unwrap())
-            .build();
+    let mut options = SpeedTestCLIOptions::parse();
+    if let Err(e) = init_logging(&options) {
+        eprintln!("Error: {e}");
+        std::process::exit(1);
+    }
+    if options.interactive && options.command.is_none() {
+        if let Err(e) = prompt_options(&mut options) {
+            eprintln!("Error: {e}");
+            std::process::exit(1);
+        }
+    }
+    if let Err(e) = options.validate_custom_urls() {
+        eprintln!("Error: {e}");
+        std::process::exit(1);
+    }
+    let result = match options.command.clone() {
+        Some(Command::History { command }) => {
+            run_history_command(&command, options.history_file.clone())
+        }
+        Some(Command::Daemon(daemon_options)) => run_scheduled(options, daemon_options),
+        Some(Command::Serve) => {
+            let addr = options
+                .listen
+                .unwrap_or_else(|| DEFAULT_ADDR.parse().unwrap());
+            handle_stop_signals("Shutting down, a running test is cancelled");
+            run_server(build_client(&options), options, addr)
+        }
+        Some(Command::Collect) => {
+            let addr = options
+                .listen
+                .unwrap_or_else(|| DEFAULT_COLLECT_ADDR.parse().unwrap());
+            HistoryStore::open(options.history_file.clone())
+                .and_then(|history| run_collector(history, addr))
+        }
+        Some(Command::ListColos(list_options)) => run_list_colos(
+            &build_client(&options),
+            &list_options,
+            &RetryPolicy::from_options(&options),
+        ),
+        Some(Command::Tcp { server }) => run_tcp_test(&server, &options),
+        Some(Command::TcpServer { bind }) => run_tcp_server(bind),
+        Some(Command::Dns(dns_options)) => run_dns_benchmark(&dns_options, options.output_format),
+        Some(Command::WarpCompare { manual }) => {
+            if !options.uses_cloudflare() {
+                Err("warp-compare only works with the Cloudflare backend".to_string())
+            } else {
+                run_warp_comparison(|| build_client(&options), options.clone(), manual)
+            }
+        }
+        None if options.check => run_checks(&build_client(&options), &options),
+        None if options.healthcheck => run_healthcheck(&build_client(&options), &options),
+        None if options.listen.is_some() => run_scheduled(options, DaemonOptions::default()),
+        None => {
+            if options.output_format.is_human() || options.human_stderr {
+                let target = match options.backend {
+                    _ if options.download_url.is_some() || options.upload_url.is_some() => {
+                        "custom URL"
+                    }
+                    Backend::Cloudflare => "Cloudflare",
+                    Backend::Fast => "fast.com",
+                };
+                if options.human_stderr {
+                    eprintln!("Starting {target} speed test");
+                } else {
+                    println!("Starting {target} speed test");
+                }
+            }
+            run_single(options)
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("Error: {e}");
+        std::process::exit(1);
+    }
+}
+
+/// RUST_LOG still selects the level and modules unless --log-level is given
+fn init_logging(options: &SpeedTestCLIOptions) -> Result<(), String> {
+    let mut builder = env_logger::Builder::from_default_env();
+    if let Some(level) = options.log_level {
+        builder.filter_level(level);
+    }
+    if let Some(path) = &options.log_file {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("failed to open log file {}: {e}", path.display()))?;
+        builder
+            .target(env_logger::Target::Pipe(Box::new(file)))
+            .write_style(env_logger::WriteStyle::Never);
+    }
+    builder.init();
+    Ok(())
+}
+
+fn run_single(options: SpeedTestCLIOptions) -> Result<(), String> {
+    let integrations = Integrations::new(options.integrations.clone())?;
+    let client = build_client(&options);
+    if !options.skip_connection_check && options.uses_cloudflare() {
+        check_connection(&client)?;
+    }
+    // the first Ctrl-C cancels the running transfer and skips the remaining samples so the
+    // summary of what was measured still gets printed
+    handle_stop_signals("Interrupted, stopping the test");
+    let chart = options.chart.clone();
+    let result = match options.stderr_machine_format()? {
+        Some(machine_format) => {
+            let include_raw = options.include_raw;
+            let options = SpeedTestCLIOptions {
+                output_format: OutputFormat::StdOut,
+                ..options
+            };
+            let result = with_stdout_on_stderr(|| speed_test(client, options))?;
+            speedtest::print_machine_output(&result, machine_format, include_raw);
+            result
+        }
+        None => speed_test(client, options),
+    };
+    let chart = chart.map(|path| write_chart(&path, &result)).transpose();
+    let outcome = if result.has_samples() {
+        Ok(HistoryRecord::from_result(&result, Utc::now()))
     } else {
-        client = reqwest::blocking::Client::builder().build();
+        Err("all test requests failed".to_string())
+    };
+    // an interrupted run still publishes what it measured before exiting with 130
+    integrations.publish(&outcome);
+    if speedtest::stop_requested() {
+        std::process::exit(130);
+    }
+    chart?;
+    outcome.map(|_| ())
+}
+
+/// Runs `f` with everything written to stdout going to stderr instead
+#[cfg(unix)]
+fn with_stdout_on_stderr<T>(f: impl FnOnce() -> T) -> Result<T, String> {
+    use std::io::Write;
+    let _ = std::io::stdout().flush();
+    let stdout = unsafe { libc::dup(libc::STDOUT_FILENO) };
+    if stdout < 0 || unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } < 0 {
+        return Err("failed to redirect stdout to stderr".to_string());
+    }
+    let result = f();
+    let _ = std::io::stdout().flush();
+    unsafe {
+        libc::dup2(stdout, libc::STDOUT_FILENO);
+        libc::close(stdout);
+    }
+    Ok(result)
+}
+
+#[cfg(not(unix))]
+fn with_stdout_on_stderr<T>(_f: impl FnOnce() -> T) -> Result<T, String> {
+    Err("--human-stderr is only supported on Unix".to_string())
+}
+
+/// The first Ctrl-C or SIGTERM requests a stop, the second one exits right away
+fn handle_stop_signals(message: &'static str) {
+    let _ = ctrlc::set_handler(move || {
+        if speedtest::stop_requested() {
+            std::process::exit(130);
+        }
+        eprintln!("\n{message}. Press Ctrl-C again to exit immediately");
+        speedtest::request_stop();
+    });
+}
+
+fn run_scheduled(
+    options: SpeedTestCLIOptions,
+    daemon_options: DaemonOptions,
+) -> Result<(), String> {
+    // a running test is cancelled and stored before the daemon exits
+    handle_stop_signals("Shutting down, a running test is cancelled");
+    let metrics = Arc::new(Mutex::new(Metrics::default()));
+    let integrations = Integrations::new(options.integrations.clone())?;
+    if let Some(addr) = options.listen {
+        spawn_exporter(addr, metrics.clone())?;
     }
-    speed_test(
-        client.expect("Failed to initialize reqwest client"),
+    run_daemon(
+        build_client(&options),
         options,
-    );
+        &daemon_options,
+        |outcome| {
+            metrics.lock().unwrap().record_run(outcome);
+            integrations.publish(outcome);
+        },
+    )
 }
@@ -1,27 +1,70 @@
 use crate::boxplot;
-use crate::speedtest::TestType;
+use crate::bufferbloat::Bufferbloat;
+use crate::metadata::Metadata;
+use crate::resources::PhaseUsage;
+use crate::speedtest::{LatencyPercentiles, TestType};
+use crate::throughput::ThroughputPoint;
+use crate::trace::PathTrace;
 use crate::OutputFormat;
 use indexmap::IndexSet;
 use serde::Serialize;
-use std::{fmt::Display, io};
+use std::{collections::BTreeMap, fmt::Display, io};
 
+/// Stats of one payload size, empty if the phase was aborted before enough samples were collected
 #[derive(Serialize)]
-struct StatMeasurement {
+pub(crate) struct StatMeasurement {
     test_type: TestType,
     payload_size: usize,
-    min: f64,
-    q1: f64,
-    median: f64,
-    q3: f64,
-    max: f64,
-    avg: f64,
+    min: Option<f64>,
+    q1: Option<f64>,
+    median: Option<f64>,
+    q3: Option<f64>,
+    max: Option<f64>,
+    avg: Option<f64>,
+    /// Number of samples excluded because the server answered with a non-2xx status
+    error_samples: usize,
+    /// Whether the phase was aborted before all samples were taken
+    incomplete: bool,
+    error: Option<String>,
+    /// CPU time used by cfspeedtest itself while testing this payload size
+    cpu_time_ms: Option<f64>,
+    cpu_percent: Option<f64>,
+    peak_rss_kb: Option<u64>,
 }
 
-#[derive(Serialize)]
+/// A test phase that was aborted because its requests kept failing
+#[derive(Clone, Debug, Serialize)]
+pub struct PhaseError {
+    pub test_type: TestType,
+    pub payload_size: usize,
+    pub error: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
 pub struct Measurement {
     pub test_type: TestType,
     pub payload_size: usize,
     pub mbit: f64,
+    /// Number of times the request had to be retried
+    pub retries: u32,
+    /// HTTP status of the response, samples without a 2xx status are excluded from all stats
+    pub status: u16,
+    /// Cloudflare colo that served the request
+    pub colo: Option<String>,
+    /// Time until the first byte of the body was transferred, excluded from `mbit`
+    pub ttfb_ms: f64,
+    /// Throughput over the course of the transfer, only recorded with --include-raw
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub throughput: Vec<ThroughputPoint>,
+    /// Time until the throughput reached 90% of its peak, only for large transfers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ramp_ms: Option<f64>,
+}
+
+impl Measurement {
+    pub fn is_valid(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
 }
 
 impl Display for Measurement {
@@ -36,12 +79,43 @@ impl Display for Measurement {
     }
 }
 
+/// A whole run as printed by the machine readable output formats
+#[derive(Serialize)]
+pub(crate) struct RunSummary<'a> {
+    /// Client and colo of the run, including the colo location and ASN organization
+    pub metadata: &'a Metadata,
+    pub measurements: Vec<StatMeasurement>,
+    pub latency: LatencySummary,
+    /// Whether the connection was warmed up before measuring, only with --prewarm
+    pub prewarmed: bool,
+    /// Latency increase under load, only measured with --bufferbloat
+    pub bufferbloat: Option<&'a Bufferbloat>,
+    /// Routers on the path with their round trip times, only traced with --trace-path
+    pub path: Option<&'a PathTrace>,
+    /// Key-value pairs given with --meta
+    pub meta: &'a BTreeMap<String, String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct LatencySummary {
+    pub avg_ms: Option<f64>,
+    pub jitter_ms: Option<f64>,
+    pub percentiles: Option<LatencyPercentiles>,
+    /// Number of retried latency requests
+    pub retries: u32,
+    /// Number of latency samples skipped because the request failed
+    pub failed: u32,
+}
+
+/// Prints the summary table for the stdout format and returns its rows
 pub(crate) fn log_measurements(
     measurements: &[Measurement],
+    phase_errors: &[PhaseError],
+    resource_usage: &[PhaseUsage],
     payload_sizes: Vec<usize>,
     verbose: bool,
     output_format: OutputFormat,
-) {
+) -> Vec<StatMeasurement> {
     if output_format == OutputFormat::StdOut {
         println!("\nSummary Statistics");
         println!("Type     Payload |  min/max/avg in mbit/s");
@@ -50,41 +124,123 @@ pub(crate) fn log_measurements(
     measurements
         .iter()
         .map(|m| m.test_type)
+        .chain(phase_errors.iter().map(|e| e.test_type))
         .collect::<IndexSet<TestType>>()
         .iter()
         .for_each(|t| {
             stat_measurements.extend(log_measurements_by_test_type(
                 measurements,
+                phase_errors,
+                resource_usage,
                 payload_sizes.clone(),
                 verbose,
                 output_format,
                 *t,
             ))
         });
+    stat_measurements
+}
+
+/// Prints `run` for the machine readable output formats. CSV has no nesting, there the values of
+/// the whole run in `run_columns` are repeated in every row.
+pub(crate) fn print_run(
+    run: &RunSummary<'_>,
+    run_columns: &[(String, String)],
+    output_format: OutputFormat,
+) {
+    let printed = match output_format {
+        OutputFormat::Csv => print_csv(&run.measurements, run_columns).map_err(|e| e.to_string()),
+        OutputFormat::Json => serde_json::to_string(run)
+            .map(|json| println!("{json}"))
+            .map_err(|e| e.to_string()),
+        OutputFormat::JsonPretty => serde_json::to_string_pretty(run)
+            .map(|json| println!("{json}"))
+            .map_err(|e| e.to_string()),
+        OutputFormat::Toml => toml::to_string(run)
+            .map(|toml| print!("{toml}"))
+            .map_err(|e| e.to_string()),
+        _ => Ok(()),
+    };
+    if let Err(e) = printed {
+        eprintln!("failed to format the results: {e}");
+    }
+}
+
+fn print_csv<T: Serialize>(rows: &[T], columns: &[(String, String)]) -> Result<(), csv::Error> {
+    // the rows are written by serde first so their headers follow the field names
+    let mut table = csv::Writer::from_writer(Vec::new());
+    for row in rows {
+        table.serialize(row)?;
+    }
+    let table = table.into_inner().map_err(|e| e.into_error())?;
+    let mut reader = csv::Reader::from_reader(table.as_slice());
+    let mut wtr = csv::Writer::from_writer(io::stdout());
+    let headers = reader.headers()?.clone();
+    // e.g. `incomplete` exists per row and for the whole run
+    let column_names = columns.iter().map(|(key, _)| {
+        if headers.iter().any(|header| header == key) {
+            format!("run_{key}")
+        } else {
+            key.clone()
+        }
+    });
+    wtr.write_record(headers.iter().map(str::to_string).chain(column_names))?;
+    for record in reader.records() {
+        let record = record?;
+        wtr.write_record(
+            record
+                .iter()
+                .chain(columns.iter().map(|(_, value)| value.as_str())),
+        )?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Prints `rows` for the machine readable output formats, `table` names the TOML array of tables.
+/// Human readable formats print their own tables, so nothing is printed for them.
+pub(crate) fn print_rows<T: Serialize>(rows: &[T], table: &str, output_format: OutputFormat) {
     match output_format {
         OutputFormat::Csv => {
             let mut wtr = csv::Writer::from_writer(io::stdout());
-            for measurement in &stat_measurements {
-                wtr.serialize(measurement).unwrap();
+            for row in rows {
+                wtr.serialize(row).unwrap();
             }
             wtr.flush().unwrap();
         }
         OutputFormat::Json => {
-            serde_json::to_writer(io::stdout(), &stat_measurements).unwrap();
+            serde_json::to_writer(io::stdout(), rows).unwrap();
             println!();
         }
         OutputFormat::JsonPretty => {
             // json_pretty output test
-            serde_json::to_writer_pretty(io::stdout(), &stat_measurements).unwrap();
+            serde_json::to_writer_pretty(io::stdout(), rows).unwrap();
             println!();
         }
+        OutputFormat::Toml => match to_toml(rows, table) {
+            Ok(toml) => print!("{toml}"),
+            Err(e) => eprintln!("failed to format the results as TOML: {e}"),
+        },
+        // every sample was already streamed as an event
+        OutputFormat::Ndjson => {}
+        // the pretty summary is printed once the whole result is available
+        OutputFormat::Pretty => {}
+        OutputFormat::Kv => {}
         OutputFormat::StdOut => {}
         OutputFormat::None => {}
     }
 }
 
+/// One `[[table]]` per row, unset fields are left out as TOML has no null
+fn to_toml<T: Serialize>(rows: &[T], table: &str) -> Result<String, String> {
+    let document: BTreeMap<&str, &[T]> = BTreeMap::from([(table, rows)]);
+    toml::to_string(&document).map_err(|e| e.to_string())
+}
+
 fn log_measurements_by_test_type(
     measurements: &[Measurement],
+    phase_errors: &[PhaseError],
+    resource_usage: &[PhaseUsage],
     payload_sizes: Vec<usize>,
     verbose: bool,
     output_format: OutputFormat,
@@ -92,46 +248,97 @@ fn log_measurements_by_test_type(
 ) -> Vec<StatMeasurement> {
     let mut stat_measurements: Vec<StatMeasurement> = Vec::new();
     for payload_size in payload_sizes {
-        let type_measurements: Vec<f64> = measurements
+        let (valid, invalid): (Vec<&Measurement>, Vec<&Measurement>) = measurements
             .iter()
             .filter(|m| m.test_type == test_type)
             .filter(|m| m.payload_size == payload_size)
-            .map(|m| m.mbit)
-            .collect();
-
-        // check if there are any measurements for the current payload_size
-        // skip stats calculation if there are no measurements
-        if !type_measurements.is_empty() {
-            let (min, q1, median, q3, max, avg) = calc_stats(type_measurements).unwrap();
+            .partition(|m| m.is_valid());
+        let type_measurements: Vec<f64> = valid.iter().map(|m| m.mbit).collect();
+        let error_samples = invalid.len();
+        let error = phase_errors
+            .iter()
+            .find(|e| e.test_type == test_type && e.payload_size == payload_size)
+            .map(|e| e.error.clone());
+        let usage = resource_usage
+            .iter()
+            .find(|u| u.test_type == test_type && u.payload_size == payload_size);
+        let formatted_payload = format_bytes(payload_size);
+        let fmt_test_type = format!("{:?}", test_type);
 
-            let formatted_payload = format_bytes(payload_size);
-            let fmt_test_type = format!("{:?}", test_type);
-            stat_measurements.push(StatMeasurement {
-                test_type,
-                payload_size,
-                min,
-                q1,
-                median,
-                q3,
-                max,
-                avg,
-            });
+        // skip stats calculation if there are not enough measurements for the current payload_size
+        // skipped samples can leave too few measurements for stats as well
+        let sparkline = boxplot::render_sparkline(&type_measurements);
+        if let Some((min, q1, median, q3, max, avg)) = calc_stats(type_measurements) {
             if output_format == OutputFormat::StdOut {
-                println!(
-                "{fmt_test_type:<9} {formatted_payload:<7}|  min {min:<7.2} max {max:<7.2} avg {avg:<7.2}"
+                print!(
+                "{fmt_test_type:<9} {formatted_payload:<7}|  min {min:<7.2} max {max:<7.2} avg {avg:<7.2} {sparkline}"
             );
+                if error_samples > 0 {
+                    print!(" ({error_samples} error samples excluded)");
+                }
+                if let Some(error) = &error {
+                    print!(" (incomplete: {error})");
+                }
+                println!();
+                if let Some(usage) = usage.filter(|_| verbose) {
+                    println!(
+                        "                  cpu {:.0}% ({:.0}ms), peak rss {}",
+                        usage.cpu_percent,
+                        usage.cpu_time_ms,
+                        format_bytes(usage.peak_rss_kb as usize * 1024)
+                    );
+                }
                 if verbose {
                     let plot = boxplot::render_plot(min, q1, median, q3, max);
                     println!("{plot}\n");
                 }
             }
+            stat_measurements.push(StatMeasurement {
+                test_type,
+                payload_size,
+                min: Some(min),
+                q1: Some(q1),
+                median: Some(median),
+                q3: Some(q3),
+                max: Some(max),
+                avg: Some(avg),
+                error_samples,
+                incomplete: error.is_some(),
+                error,
+                cpu_time_ms: usage.map(|u| u.cpu_time_ms),
+                cpu_percent: usage.map(|u| u.cpu_percent),
+                peak_rss_kb: usage.map(|u| u.peak_rss_kb),
+            });
+        } else if let Some(error) = error {
+            if output_format == OutputFormat::StdOut {
+                println!("{fmt_test_type:<9} {formatted_payload:<7}|  incomplete: {error}");
+            }
+            stat_measurements.push(StatMeasurement {
+                test_type,
+                payload_size,
+                min: None,
+                q1: None,
+                median: None,
+                q3: None,
+                max: None,
+                avg: None,
+                error_samples,
+                incomplete: true,
+                error: Some(error),
+                cpu_time_ms: None,
+                cpu_percent: None,
+                peak_rss_kb: None,
+            });
         }
     }
 
     stat_measurements
 }
 
-fn calc_stats(mbit_measurements: Vec<f64>) -> Option<(f64, f64, f64, f64, f64, f64)> {
+// plain arithmetic keeps the crate building on toolchains without `is_multiple_of`, their
+// clippy doesn't know the lints either
+#[allow(unknown_lints, clippy::manual_is_multiple_of, clippy::manual_div_ceil)]
+pub(crate) fn calc_stats(mbit_measurements: Vec<f64>) -> Option<(f64, f64, f64, f64, f64, f64)> {
     log::debug!("calc_stats for mbit_measurements {mbit_measurements:?}");
     let length = mbit_measurements.len();
     if length < 4 {
@@ -163,6 +370,81 @@ fn calc_stats(mbit_measurements: Vec<f64>) -> Option<(f64, f64, f64, f64, f64, f
     ))
 }
 
+/// Average speed of the largest payload size that was tested for `test_type`
+pub fn headline_mbit(measurements: &[Measurement], test_type: TestType) -> Option<f64> {
+    let largest_payload = measurements
+        .iter()
+        .filter(|m| m.test_type == test_type && m.is_valid())
+        .map(|m| m.payload_size)
+        .max()?;
+    let mbits: Vec<f64> = measurements
+        .iter()
+        .filter(|m| m.test_type == test_type && m.payload_size == largest_payload)
+        .filter(|m| m.is_valid())
+        .map(|m| m.mbit)
+        .collect();
+    Some(mbits.iter().sum::<f64>() / mbits.len() as f64)
+}
+
+/// Linearly interpolated percentile `p` in 0..=100 of `sorted`, which must not be empty
+pub fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = p / 100.0 * (sorted.len() - 1) as f64;
+    let (lower, upper) = (rank.floor() as usize, rank.ceil() as usize);
+    sorted[lower] + (sorted[upper] - sorted[lower]) * (rank - lower as f64)
+}
+
+/// Mean absolute difference between consecutive latency measurements
+pub fn calc_jitter(latency_measurements: &[f64]) -> Option<f64> {
+    if latency_measurements.len() < 2 {
+        return None;
+    }
+    let diffs: Vec<f64> = latency_measurements
+        .windows(2)
+        .map(|w| (w[1] - w[0]).abs())
+        .collect();
+    Some(diffs.iter().sum::<f64>() / diffs.len() as f64)
+}
+
+/// Estimated call quality of VoIP from 1 (bad) to 4.4 (excellent) after a simplified ITU-T G.107
+/// E-model, `loss_percent` is in 0..=100
+pub fn estimate_mos(latency_ms: f64, jitter_ms: f64, loss_percent: f64) -> f64 {
+    // jitter buffers add delay as well, 10 ms account for the codec
+    let effective_latency = latency_ms + 2.0 * jitter_ms + 10.0;
+    let latency_impairment = if effective_latency < 160.0 {
+        effective_latency / 40.0
+    } else {
+        (effective_latency - 120.0) / 10.0
+    };
+    let r = (93.2 - latency_impairment - 2.5 * loss_percent).clamp(0.0, 100.0);
+    1.0 + 0.035 * r + 0.000007 * r * (r - 60.0) * (100.0 - r)
+}
+
+/// Half width of the 95% confidence interval of the mean, needs at least 4 measurements
+pub fn confidence_interval(measurements: &[f64]) -> Option<f64> {
+    let n = measurements.len();
+    if n < 4 {
+        return None;
+    }
+    let mean = measurements.iter().sum::<f64>() / n as f64;
+    let variance = measurements.iter().map(|m| (m - mean).powi(2)).sum::<f64>() / (n - 1) as f64;
+    Some(t_value_95(n - 1) * (variance / n as f64).sqrt())
+}
+
+/// Two-sided 95% quantile of Student's t-distribution
+fn t_value_95(degrees_of_freedom: usize) -> f64 {
+    const TABLE: [f64; 10] = [
+        12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228,
+    ];
+    match degrees_of_freedom {
+        0 => f64::INFINITY,
+        1..=10 => TABLE[degrees_of_freedom - 1],
+        11..=20 => 2.1,
+        21..=30 => 2.05,
+        _ => 1.96,
+    }
+}
+
+#[allow(unknown_lints, clippy::manual_is_multiple_of)]
 fn median(data: &[f64]) -> f64 {
     let length = data.len();
     if length % 2 == 0 {
@@ -176,6 +458,7 @@ pub(crate) fn format_bytes(bytes: usize) -> String {
     match bytes {
         1_000..=999_999 => format!("{}KB", bytes / 1_000),
         1_000_000..=999_999_999 => format!("{}MB", bytes / 1_000_000),
+        1_000_000_000.. => format!("{}GB", bytes / 1_000_000_000),
         _ => format!("{bytes} bytes"),
     }
 }
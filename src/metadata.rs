@@ -0,0 +1,391 @@
+use crate::colos::{self, ColoLocation};
+use crate::retry::RetryPolicy;
+use crate::speedtest::{BASE_URL, DOWNLOAD_URL};
+use crate::tls::TlsDetails;
+use log;
+use reqwest::{blocking::Client, header::HeaderMap};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+const META_URL: &str = "meta";
+const TRACE_URL: &str = "cdn-cgi/trace";
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Metadata of an earlier run in this process and when it was fetched
+static METADATA_CACHE: Mutex<Option<(Instant, Metadata)>> = Mutex::new(None);
+
+/// Names of large ISPs, used when the ASN organization can't be fetched
+const ISP_NAMES: &[(u32, &str)] = &[
+    (577, "Bell Canada"),
+    (701, "Verizon"),
+    (812, "Rogers"),
+    (1136, "KPN"),
+    (1221, "Telstra"),
+    (2516, "KDDI"),
+    (2856, "BT"),
+    (3209, "Vodafone Germany"),
+    (3215, "Orange France"),
+    (3269, "Telecom Italia"),
+    (3303, "Swisscom"),
+    (3320, "Deutsche Telekom"),
+    (3352, "Telefonica Spain"),
+    (4134, "China Telecom"),
+    (4713, "NTT OCN"),
+    (4766, "Korea Telecom"),
+    (4804, "Optus"),
+    (4837, "China Unicom"),
+    (5089, "Virgin Media"),
+    (5432, "Proximus"),
+    (5650, "Frontier"),
+    (6830, "Liberty Global"),
+    (6848, "Telenet"),
+    (7018, "AT&T"),
+    (7922, "Comcast"),
+    (8447, "A1 Telekom Austria"),
+    (9498, "Bharti Airtel"),
+    (9808, "China Mobile"),
+    (12322, "Free"),
+    (13335, "Cloudflare"),
+    (15557, "SFR"),
+    (17676, "SoftBank"),
+    (20115, "Charter"),
+    (21928, "T-Mobile US"),
+    (22773, "Cox"),
+    (33915, "Ziggo"),
+    (55836, "Reliance Jio"),
+];
+
+fn isp_name(asn: &str) -> Option<&'static str> {
+    let asn: u32 = asn.trim_start_matches("AS").parse().ok()?;
+    ISP_NAMES
+        .iter()
+        .find(|(number, _)| *number == asn)
+        .map(|(_, name)| *name)
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Metadata {
+    pub city: String,
+    pub country: String,
+    pub ip: String,
+    pub asn: String,
+    pub colo: String,
+    /// Name of the organization the ASN is registered to
+    pub as_organization: Option<String>,
+    /// Name of the ISP, from the ASN organization or the bundled ASN table
+    pub isp: Option<String>,
+    /// Public addresses of both IP families, only probed if no family is forced
+    pub ipv4: Option<String>,
+    pub ipv6: Option<String>,
+    /// Approximate location of the client
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    /// Location of the colo from the bundled colo table
+    pub colo_location: Option<ColoLocation>,
+    /// Approximate distance between the client and the colo
+    pub colo_distance_km: Option<f64>,
+    /// TLS version and cipher suite of a separate handshake with speed.cloudflare.com, reqwest
+    /// doesn't expose the ones of the test connections. Only probed for the Cloudflare backend.
+    pub tls_probe: Option<TlsDetails>,
+    /// Cloudflare WARP status from the trace endpoint: `off`, `on` or `plus`
+    pub warp: Option<String>,
+    /// PTR record of the public IP, only looked up with --reverse-dns
+    pub reverse_dns: Option<String>,
+}
+
+impl Display for Metadata {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "City: {}\nCountry: {}\nIp: {}",
+            self.city, self.country, self.ip
+        )?;
+        if let Some(family) = self.ip_family() {
+            write!(f, " (tests use {family})")?;
+        }
+        if self.ipv4.is_some() || self.ipv6.is_some() {
+            write!(
+                f,
+                "\nIpv4: {}\nIpv6: {}",
+                self.ipv4.as_deref().unwrap_or("not available"),
+                self.ipv6.as_deref().unwrap_or("not available")
+            )?;
+        }
+        if let Some(name) = &self.reverse_dns {
+            write!(f, "\nReverse DNS: {name}")?;
+        }
+        write!(f, "\nAsn: {}", self.asn)?;
+        if let Some(isp) = &self.isp {
+            write!(f, "\nIsp: {isp}")?;
+        }
+        write!(f, "\nColo: {}", self.colo)?;
+        if let Some(location) = &self.colo_location {
+            write!(f, " ({}, {}", location.city, location.country)?;
+            if let Some(distance) = self.colo_distance_km {
+                write!(f, ", ~{distance:.0} km away")?;
+            }
+            write!(f, ")")?;
+        }
+        if let Some(warp) = &self.warp {
+            write!(f, "\nWarp: {warp}")?;
+        }
+        if let Some(tls) = &self.tls_probe {
+            write!(f, "\nTls probe: {tls}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Subset of the JSON returned by the meta endpoint
+#[derive(Deserialize)]
+struct MetaResponse {
+    #[serde(rename = "asOrganization")]
+    as_organization: Option<String>,
+}
+
+pub fn fetch_metadata(client: &Client, retry_policy: &RetryPolicy) -> Result<Metadata, String> {
+    let url = &format!("{}/{}{}", BASE_URL, DOWNLOAD_URL, 0);
+    let (response, _) = retry_policy.run(|| client.get(url).send());
+    let headers = response
+        .map_err(|e| format!("failed to get response: {e}"))?
+        .headers()
+        .to_owned();
+    let mut metadata = Metadata::from_headers(&headers);
+    metadata.as_organization = fetch_as_organization(client);
+    metadata.warp = fetch_trace(client).and_then(|trace| trace_value(&trace, "warp"));
+    metadata.isp = metadata
+        .as_organization
+        .clone()
+        .or_else(|| isp_name(&metadata.asn).map(str::to_owned));
+    Ok(metadata)
+}
+
+/// Metadata fetched by an earlier run that is younger than `ttl`, together with its age
+pub(crate) fn cached_metadata(ttl: Duration) -> Option<(Metadata, Duration)> {
+    let cache = METADATA_CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    let (fetched, metadata) = cache.as_ref()?;
+    let age = fetched.elapsed();
+    (age < ttl).then(|| (metadata.clone(), age))
+}
+
+pub(crate) fn cache_metadata(metadata: &Metadata) {
+    let mut cache = METADATA_CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    *cache = Some((Instant::now(), metadata.clone()));
+}
+
+/// Body of the trace endpoint, optional like the meta endpoint so failures are ignored
+fn fetch_trace(client: &Client) -> Option<String> {
+    let url = format!("{BASE_URL}/{TRACE_URL}");
+    client
+        .get(&url)
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.text())
+        .map_err(|e| log::debug!("failed to fetch {url}: {e}"))
+        .ok()
+}
+
+/// Value of a `key=value` line of a trace body
+fn trace_value(trace: &str, key: &str) -> Option<String> {
+    trace
+        .lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+        .map(str::to_owned)
+}
+
+/// The organization is only available from the meta endpoint, it's optional so failures are ignored
+fn fetch_as_organization(client: &Client) -> Option<String> {
+    let url = format!("{BASE_URL}/{META_URL}");
+    match client
+        .get(&url)
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.json::<MetaResponse>())
+    {
+        Ok(meta) => meta.as_organization.filter(|org| !org.is_empty()),
+        Err(e) => {
+            log::debug!("failed to fetch {url}: {e}");
+            None
+        }
+    }
+}
+
+impl Metadata {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let colo = extract_header_value(headers, "cf-meta-colo", "Colo N/A");
+        let latitude = parse_header_value(headers, "cf-meta-latitude");
+        let longitude = parse_header_value(headers, "cf-meta-longitude");
+        let colo_location = colos::lookup(&colo).copied();
+        let colo_distance_km = match (latitude, longitude, &colo_location) {
+            (Some(latitude), Some(longitude), Some(location)) => Some(colos::distance_km(
+                (latitude, longitude),
+                (location.latitude, location.longitude),
+            )),
+            _ => None,
+        };
+        Self {
+            city: extract_header_value(headers, "cf-meta-city", "City N/A"),
+            country: extract_header_value(headers, "cf-meta-country", "Country N/A"),
+            ip: extract_header_value(headers, "cf-meta-ip", "IP N/A"),
+            asn: extract_header_value(headers, "cf-meta-asn", "ASN N/A"),
+            colo,
+            as_organization: None,
+            isp: None,
+            ipv4: None,
+            ipv6: None,
+            latitude,
+            longitude,
+            colo_location,
+            colo_distance_km,
+            tls_probe: None,
+            warp: None,
+            reverse_dns: None,
+        }
+    }
+
+    /// IP family the test traffic used
+    pub fn ip_family(&self) -> Option<&'static str> {
+        match self.ip.parse::<IpAddr>().ok()? {
+            IpAddr::V4(_) => Some("IPv4"),
+            IpAddr::V6(_) => Some("IPv6"),
+        }
+    }
+
+    /// Looks up the PTR record of the public IP, it often names the ISP and region
+    pub fn resolve_reverse_dns(&mut self) {
+        self.reverse_dns = self.ip.parse().ok().and_then(reverse_lookup);
+    }
+
+    /// Masks the public addresses so that results can be shared, see `anonymize_ip`.
+    /// The reverse DNS name usually contains the address as well and is dropped, like the
+    /// coordinates. The distance to the colo is rounded to 100 km.
+    pub fn anonymize(&mut self) {
+        self.reverse_dns = None;
+        self.latitude = None;
+        self.longitude = None;
+        self.colo_distance_km = self
+            .colo_distance_km
+            .map(|distance| (distance / 100.0).round() * 100.0);
+        self.ip = anonymize_ip(&self.ip);
+        self.ipv4 = self.ipv4.as_deref().map(anonymize_ip);
+        self.ipv6 = self.ipv6.as_deref().map(anonymize_ip);
+    }
+
+    /// Whether the test traffic went through Cloudflare WARP instead of the plain ISP link
+    pub fn warp_active(&self) -> bool {
+        self.warp.as_deref().is_some_and(|warp| warp != "off")
+    }
+
+    /// Looks up the public address of both IP families, a family without connectivity is left empty
+    pub fn probe_dual_stack(&mut self) {
+        self.ipv4 = public_ip("0.0.0.0".parse().unwrap());
+        self.ipv6 = public_ip("::".parse().unwrap());
+    }
+
+    /// Placeholder used when the metadata request failed
+    pub(crate) fn unavailable() -> Self {
+        Self::from_headers(&HeaderMap::new())
+    }
+}
+
+/// Zeroes the last octet of an IPv4 and the last 80 bits of an IPv6 address, anything else is left as is
+pub fn anonymize_ip(ip: &str) -> String {
+    match ip.parse::<IpAddr>() {
+        Ok(ip) => anonymize_addr(ip).to_string(),
+        Err(_) => ip.to_owned(),
+    }
+}
+
+pub fn anonymize_addr(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => Ipv4Addr::from(ip.to_bits() & !0xff).into(),
+        IpAddr::V6(ip) => Ipv6Addr::from(ip.to_bits() & !((1u128 << 80) - 1)).into(),
+    }
+}
+
+/// Public address seen by Cloudflare when connecting from `local_address`
+fn public_ip(local_address: IpAddr) -> Option<String> {
+    let client = Client::builder()
+        .local_address(local_address)
+        .timeout(PROBE_TIMEOUT)
+        .build()
+        .ok()?;
+    let trace = client
+        .get(format!("{BASE_URL}/{TRACE_URL}"))
+        .send()
+        .and_then(|response| response.text())
+        .map_err(|e| log::debug!("no connectivity from {local_address}: {e}"))
+        .ok()?;
+    trace_value(&trace, "ip")
+}
+
+#[cfg(unix)]
+fn reverse_lookup(ip: IpAddr) -> Option<String> {
+    const MAX_HOST_LEN: usize = 1025;
+    let mut host = [0 as libc::c_char; MAX_HOST_LEN];
+    let status = match ip {
+        IpAddr::V4(ip) => {
+            let mut address: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+            address.sin_family = libc::AF_INET as libc::sa_family_t;
+            address.sin_addr.s_addr = u32::from_ne_bytes(ip.octets());
+            unsafe {
+                libc::getnameinfo(
+                    (&address as *const libc::sockaddr_in).cast(),
+                    std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                    host.as_mut_ptr(),
+                    MAX_HOST_LEN as libc::socklen_t,
+                    std::ptr::null_mut(),
+                    0,
+                    libc::NI_NAMEREQD,
+                )
+            }
+        }
+        IpAddr::V6(ip) => {
+            let mut address: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+            address.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            address.sin6_addr.s6_addr = ip.octets();
+            unsafe {
+                libc::getnameinfo(
+                    (&address as *const libc::sockaddr_in6).cast(),
+                    std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+                    host.as_mut_ptr(),
+                    MAX_HOST_LEN as libc::socklen_t,
+                    std::ptr::null_mut(),
+                    0,
+                    libc::NI_NAMEREQD,
+                )
+            }
+        }
+    };
+    if status != 0 {
+        log::debug!("no reverse DNS name for {ip} (getnameinfo error {status})");
+        return None;
+    }
+    let name = unsafe { std::ffi::CStr::from_ptr(host.as_ptr()) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+#[cfg(not(unix))]
+fn reverse_lookup(_ip: IpAddr) -> Option<String> {
+    None
+}
+
+fn extract_header_value(headers: &HeaderMap, header_name: &str, na_value: &str) -> String {
+    headers
+        .get(header_name)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or(na_value)
+        .to_owned()
+}
+
+fn parse_header_value(headers: &HeaderMap, header_name: &str) -> Option<f64> {
+    headers
+        .get(header_name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
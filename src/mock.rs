@@ -0,0 +1,177 @@
+use crate::SpeedTestCLIOptions;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Behaviour of the simulated link
+#[derive(Clone, Debug)]
+pub struct MockConfig {
+    pub download_mbit: f64,
+    pub upload_mbit: f64,
+    /// Delay before download responses, seen as the latency of the link. Uploads aren't
+    /// delayed as their timing includes the response.
+    pub latency: Duration,
+    /// Status of every response, e.g. 429 to exercise the rate limit handling
+    pub status: u16,
+}
+
+impl Default for MockConfig {
+    fn default() -> Self {
+        Self {
+            download_mbit: 100.0,
+            upload_mbit: 50.0,
+            latency: Duration::from_millis(20),
+            status: 200,
+        }
+    }
+}
+
+/// Local HTTP server that answers the test requests with synthetic byte streams at the
+/// configured speeds, so whole test runs work offline and give predictable results.
+/// The server stops when this is dropped.
+pub struct MockBackend {
+    address: SocketAddr,
+    stopped: Arc<AtomicBool>,
+}
+
+impl MockBackend {
+    pub fn start(config: MockConfig) -> Result<Self, String> {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .map_err(|e| format!("failed to start mock server: {e}"))?;
+        let address = listener.local_addr().map_err(|e| e.to_string())?;
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stop = Arc::clone(&stopped);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                let Ok(stream) = stream else { continue };
+                let config = config.clone();
+                thread::spawn(move || serve(stream, &config));
+            }
+        });
+        Ok(Self { address, stopped })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Points the download and upload requests of `options` at this server
+    pub fn apply(&self, options: &mut SpeedTestCLIOptions) {
+        options.download_url = Some(format!("http://{}/__down?bytes={{bytes}}", self.address));
+        options.upload_url = Some(format!("http://{}/__up", self.address));
+    }
+}
+
+impl Drop for MockBackend {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // wakes up the accept loop so it sees the stop flag
+        let _ = TcpStream::connect(self.address);
+    }
+}
+
+/// Answers the requests of one keep-alive connection until the client hangs up
+fn serve(stream: TcpStream, config: &MockConfig) {
+    // small writes must not wait for the ACK of the previous ones, that distorts the speed
+    if stream.set_nodelay(true).is_err() {
+        return;
+    }
+    let Ok(mut writer) = stream.try_clone() else {
+        return;
+    };
+    let mut reader = BufReader::new(stream);
+    while let Ok(Some((method, path, content_length))) = read_head(&mut reader) {
+        let status = config.status;
+        let result = match (method.as_str(), path.split_once('?')) {
+            ("GET", Some(("/__down", query))) => {
+                let bytes = query
+                    .strip_prefix("bytes=")
+                    .and_then(|bytes| bytes.parse::<u64>().ok())
+                    .unwrap_or(0);
+                thread::sleep(config.latency);
+                write!(
+                    writer,
+                    "HTTP/1.1 {status} \r\ncontent-length: {bytes}\r\n\r\n"
+                )
+                .and_then(|_| {
+                    let mut body = Paced::new(io::repeat(0).take(bytes), config.download_mbit);
+                    io::copy(&mut body, &mut writer)
+                })
+            }
+            ("POST", _) if path == "/__up" => {
+                let mut body = Paced::new((&mut reader).take(content_length), config.upload_mbit);
+                io::copy(&mut body, &mut io::sink()).and_then(|_| {
+                    write!(writer, "HTTP/1.1 {status} \r\ncontent-length: 0\r\n\r\n").map(|_| 0)
+                })
+            }
+            _ => write!(writer, "HTTP/1.1 404 \r\ncontent-length: 0\r\n\r\n").map(|_| 0),
+        };
+        // the client may hang up early, e.g. once its time budget is spent
+        if result.is_err() {
+            return;
+        }
+    }
+}
+
+/// Reads the request line and headers, `None` once the connection is closed
+fn read_head(reader: &mut impl BufRead) -> io::Result<Option<(String, String, u64)>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    Ok(Some((method, path, content_length)))
+}
+
+/// Reader that doesn't go faster than `mbit` megabits per second
+struct Paced<R> {
+    inner: R,
+    bytes_per_second: f64,
+    start: Instant,
+    transferred: usize,
+}
+
+impl<R: Read> Paced<R> {
+    fn new(inner: R, mbit: f64) -> Self {
+        Self {
+            inner,
+            bytes_per_second: mbit * 1_000_000.0 / 8.0,
+            start: Instant::now(),
+            transferred: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for Paced<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // small chunks keep the pace smooth
+        let len = buf.len().min(16 * 1024);
+        let read = self.inner.read(&mut buf[..len])?;
+        self.transferred += read;
+        let due = Duration::from_secs_f64(self.transferred as f64 / self.bytes_per_second);
+        if let Some(wait) = due.checked_sub(self.start.elapsed()) {
+            thread::sleep(wait);
+        }
+        Ok(read)
+    }
+}
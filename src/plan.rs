@@ -0,0 +1,172 @@
+use crate::speedtest::{PayloadSize, TestType};
+use crate::{Schedule, SpeedTestCLIOptions};
+use indexmap::IndexSet;
+
+/// What a phase of a test plan measures
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PhaseKind {
+    Latency,
+    Transfer(TestType),
+}
+
+/// A step of a test plan
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Phase {
+    pub kind: PhaseKind,
+    /// Bytes per request, unused for latency phases
+    pub payload_size: usize,
+    /// Number of samples taken
+    pub repetitions: u32,
+    /// Number of requests in flight at once. Transfers run this many requests per sample and
+    /// report their combined throughput.
+    pub concurrency: u32,
+}
+
+impl Phase {
+    pub fn latency(repetitions: u32, concurrency: u32) -> Self {
+        Self {
+            kind: PhaseKind::Latency,
+            payload_size: 0,
+            repetitions,
+            concurrency,
+        }
+    }
+
+    pub fn transfer(test_type: TestType, payload_size: usize, repetitions: u32) -> Self {
+        Self {
+            kind: PhaseKind::Transfer(test_type),
+            payload_size,
+            repetitions,
+            concurrency: 1,
+        }
+    }
+}
+
+/// Ordered list of phases a speed test runs. Larger payload sizes of a test type are still
+/// skipped once a payload size took too long, unless that is disabled in the options.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TestPlan {
+    pub phases: Vec<Phase>,
+}
+
+impl TestPlan {
+    /// The plan given with --plan, otherwise the phases implied by the other options
+    pub fn from_options(options: &SpeedTestCLIOptions) -> Self {
+        if let Some(plan) = &options.plan {
+            return plan.clone();
+        }
+        let payload_sizes = PayloadSize::sizes_from_max(options.max_payload_size.clone());
+        let mut test_types = Vec::new();
+        if options.should_download() {
+            test_types.push(TestType::Download);
+        }
+        if options.should_upload() {
+            test_types.push(TestType::Upload);
+        }
+        let transfer =
+            |test_type, payload_size| Phase::transfer(test_type, payload_size, options.nr_tests);
+        // the latency phase has always taken one sample more than requested
+        let latency = Phase::latency(options.nr_latency_tests + 1, options.latency_concurrency);
+
+        let mut phases = Vec::new();
+        match options.schedule {
+            Schedule::Sequential => {
+                phases.push(latency);
+                for &test_type in &test_types {
+                    for &payload_size in &payload_sizes {
+                        phases.push(transfer(test_type, payload_size));
+                    }
+                }
+            }
+            Schedule::Interleaved | Schedule::LatencyInterleaved => {
+                let spread = options.schedule == Schedule::LatencyInterleaved;
+                if !spread {
+                    phases.push(latency);
+                }
+                let rounds = payload_sizes.len() as u32;
+                for (round, &payload_size) in payload_sizes.iter().enumerate() {
+                    // the remainder goes to the first rounds
+                    let count = options.nr_latency_tests / rounds
+                        + u32::from((round as u32) < options.nr_latency_tests % rounds);
+                    if spread && count > 0 {
+                        phases.push(Phase::latency(count, options.latency_concurrency));
+                    }
+                    for &test_type in &test_types {
+                        phases.push(transfer(test_type, payload_size));
+                    }
+                }
+            }
+        }
+        Self { phases }
+    }
+
+    /// Latency followed by `nr_tests` samples at the payload size the ramp-up of each test type
+    /// settled on
+    pub fn ramped(options: &SpeedTestCLIOptions, payload_sizes: &[(TestType, usize)]) -> Self {
+        let mut phases = vec![Phase::latency(
+            options.nr_latency_tests + 1,
+            options.latency_concurrency,
+        )];
+        for &(test_type, payload_size) in payload_sizes {
+            phases.push(Phase::transfer(test_type, payload_size, options.nr_tests));
+        }
+        Self { phases }
+    }
+
+    /// Parses a comma separated list of phases: `latency:COUNT[:CONCURRENCY]`,
+    /// `down:SIZE:COUNT[:CONCURRENCY]` and `up:SIZE:COUNT[:CONCURRENCY]`
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let phases = spec
+            .split(',')
+            .map(|phase| parse_phase(phase.trim()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { phases })
+    }
+
+    /// Payload sizes of the transfer phases in the order they first appear
+    pub fn payload_sizes(&self) -> Vec<usize> {
+        self.phases
+            .iter()
+            .filter(|phase| matches!(phase.kind, PhaseKind::Transfer(_)))
+            .map(|phase| phase.payload_size)
+            .collect::<IndexSet<usize>>()
+            .into_iter()
+            .collect()
+    }
+}
+
+fn parse_phase(phase: &str) -> Result<Phase, String> {
+    let fields: Vec<&str> = phase.split(':').collect();
+    let number = |field: &str| {
+        field
+            .parse::<u32>()
+            .ok()
+            .filter(|n| *n > 0)
+            .ok_or_else(|| format!("invalid count {field:?} in phase {phase:?}"))
+    };
+    match fields.as_slice() {
+        ["latency", count] => Ok(Phase::latency(number(count)?, 1)),
+        ["latency", count, concurrency] => Ok(Phase::latency(number(count)?, number(concurrency)?)),
+        [kind @ ("down" | "up"), size, count, rest @ ..] if rest.len() <= 1 => {
+            let test_type = if *kind == "down" {
+                TestType::Download
+            } else {
+                TestType::Upload
+            };
+            let payload_size = PayloadSize::from(size.to_string())? as usize;
+            let repetitions = number(count)?;
+            // like --nr-tests, fewer samples are too few for the summary statistics
+            if repetitions < 4 {
+                return Err(format!("phase {phase:?} needs a count of at least 4"));
+            }
+            let mut phase = Phase::transfer(test_type, payload_size, repetitions);
+            if let Some(concurrency) = rest.first() {
+                phase.concurrency = number(concurrency)?;
+            }
+            Ok(phase)
+        }
+        _ => Err(format!(
+            "invalid phase {phase:?}, expected latency:COUNT[:CONCURRENCY], down:SIZE:COUNT[:CONCURRENCY] or up:SIZE:COUNT[:CONCURRENCY]"
+        )),
+    }
+}
@@ -0,0 +1,183 @@
+use crate::measurements::{print_rows, Measurement};
+use crate::speedtest::{test_download, test_upload, SampleError, BASE_URL};
+use crate::{tls, OutputFormat, SpeedTestCLIOptions};
+use log;
+use reqwest::{blocking::Client, tls::TlsInfo};
+use serde::Serialize;
+use std::error::Error;
+use std::net::ToSocketAddrs;
+use std::time::Instant;
+
+const TRACE_URL: &str = "cdn-cgi/trace";
+pub(crate) const EXPECTED_HOST: &str = "speed.cloudflare.com";
+
+/// Verifies that requests actually reach Cloudflare before any test is run.
+/// Fails if a captive portal or TLS interception is detected, other network errors are left to the test itself.
+pub fn check_connection(client: &Client) -> Result<(), String> {
+    let url = format!("{BASE_URL}/{TRACE_URL}");
+    let response = match client.get(&url).send() {
+        Ok(response) => response,
+        Err(e) if is_certificate_error(&e) => {
+            return Err(format!(
+                "captive portal / TLS interception detected: the certificate presented for {EXPECTED_HOST} is not trusted ({})",
+                error_chain(&e)
+            ));
+        }
+        Err(e) => {
+            log::warn!("connection check failed: {e}");
+            return Ok(());
+        }
+    };
+
+    if response.url().host_str() != Some(EXPECTED_HOST) {
+        return Err(format!(
+            "captive portal / TLS interception detected: {url} was redirected to {}",
+            response.url()
+        ));
+    }
+    if let Some(certificate) = response
+        .extensions()
+        .get::<TlsInfo>()
+        .and_then(|info| info.peer_certificate())
+    {
+        if !contains(certificate, b"cloudflare.com") {
+            return Err(format!(
+                "captive portal / TLS interception detected: the certificate presented for {EXPECTED_HOST} was not issued for cloudflare.com"
+            ));
+        }
+    }
+    let status = response.status();
+    let body = response.text().unwrap_or_default();
+    if !status.is_success() || !is_trace(&body) {
+        return Err(format!(
+            "captive portal / TLS interception detected: {url} answered with {status} and an unexpected body"
+        ));
+    }
+    Ok(())
+}
+
+/// Size of the download and upload probes of `--check`
+const PROBE_BYTES: usize = 10_000;
+
+/// Outcome of a single `--check` step
+#[derive(Serialize)]
+struct CheckResult {
+    check: &'static str,
+    passed: bool,
+    duration_ms: f64,
+    detail: String,
+}
+
+/// Verifies DNS, TLS, the trace endpoint and a tiny download and upload without running the test.
+/// Prints a pass/fail table and fails if any check failed.
+pub fn run_checks(client: &Client, options: &SpeedTestCLIOptions) -> Result<(), String> {
+    // the probes must not print their speed like test samples
+    let probe_options = SpeedTestCLIOptions {
+        output_format: OutputFormat::None,
+        ..options.clone()
+    };
+    let mut results = Vec::new();
+    let mut run = |check: &'static str, step: &dyn Fn() -> Result<String, String>| {
+        let start = Instant::now();
+        let outcome = step();
+        let duration_ms = start.elapsed().as_secs_f64() * 1000.0;
+        let (passed, detail) = match outcome {
+            Ok(detail) => (true, detail),
+            Err(e) => (false, e),
+        };
+        if options.output_format.is_human() {
+            println!(
+                "{check:<9} {:<5} {duration_ms:>8.0} ms  {detail}",
+                if passed { "pass" } else { "FAIL" }
+            );
+        }
+        results.push(CheckResult {
+            check,
+            passed,
+            duration_ms,
+            detail,
+        });
+    };
+    run("DNS", &check_dns);
+    run("TLS", &|| {
+        tls::negotiate(options).map(|tls| tls.to_string())
+    });
+    run("Trace", &|| check_trace(client));
+    run("Download", &|| {
+        check_probe(test_download(client, PROBE_BYTES, &probe_options))
+    });
+    run("Upload", &|| {
+        check_probe(test_upload(client, PROBE_BYTES, &probe_options))
+    });
+    print_rows(&results, "checks", options.output_format);
+
+    let failed = results.iter().filter(|result| !result.passed).count();
+    if failed > 0 {
+        return Err(format!("{failed} of {} checks failed", results.len()));
+    }
+    Ok(())
+}
+
+fn check_dns() -> Result<String, String> {
+    let addresses: Vec<String> = (EXPECTED_HOST, 443)
+        .to_socket_addrs()
+        .map_err(|e| e.to_string())?
+        .map(|address| address.ip().to_string())
+        .collect();
+    Ok(addresses.join(", "))
+}
+
+fn check_trace(client: &Client) -> Result<String, String> {
+    let url = format!("{BASE_URL}/{TRACE_URL}");
+    let body = client
+        .get(&url)
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.text())
+        .map_err(|e| error_chain(&e))?;
+    if !is_trace(&body) {
+        return Err(format!("{url} answered with an unexpected body"));
+    }
+    let colo = body.lines().find_map(|line| line.strip_prefix("colo="));
+    Ok(format!("colo {}", colo.unwrap_or("unknown")))
+}
+
+fn check_probe(outcome: Result<Measurement, SampleError>) -> Result<String, String> {
+    let measurement = outcome.map_err(|e| e.to_string())?;
+    if !measurement.is_valid() {
+        return Err(format!("server answered with {}", measurement.status));
+    }
+    Ok(format!(
+        "{PROBE_BYTES} bytes, status {}",
+        measurement.status
+    ))
+}
+
+/// The trace endpoint answers with plaintext `key=value` lines including the host and colo
+fn is_trace(body: &str) -> bool {
+    let lines: Vec<&str> = body.lines().filter(|line| !line.is_empty()).collect();
+    !lines.is_empty()
+        && lines.iter().all(|line| line.contains('='))
+        && lines.contains(&format!("h={EXPECTED_HOST}").as_str())
+        && lines.iter().any(|line| line.starts_with("colo="))
+}
+
+fn is_certificate_error(e: &reqwest::Error) -> bool {
+    error_chain(e).to_lowercase().contains("certificate")
+}
+
+fn error_chain(e: &dyn Error) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(e) = source {
+        message.push_str(&format!(": {e}"));
+        source = e.source();
+    }
+    message
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
@@ -0,0 +1,425 @@
+use crate::bufferbloat::Grade;
+use crate::history::HistoryRecord;
+use crate::measurements::{calc_jitter, calc_stats, format_bytes, headline_mbit};
+use crate::speedtest::{SpeedTestResult, TestType};
+use anstyle::{AnsiColor, Style};
+use indexmap::IndexSet;
+use std::io::IsTerminal;
+
+const GOOD: Style = AnsiColor::Green.on_default();
+const FAIR: Style = AnsiColor::Yellow.on_default();
+const POOR: Style = AnsiColor::Red.on_default();
+const HEADING: Style = Style::new().bold();
+const DIM: Style = Style::new().dimmed();
+
+#[derive(Clone, Copy)]
+enum Rating {
+    Good,
+    Fair,
+    Poor,
+}
+
+impl Rating {
+    fn style(self) -> Style {
+        match self {
+            Self::Good => GOOD,
+            Self::Fair => FAIR,
+            Self::Poor => POOR,
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            Self::Good => "✔",
+            Self::Fair => "~",
+            Self::Poor => "✘",
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::Good => "good",
+            Self::Fair => "fair",
+            Self::Poor => "poor",
+        }
+    }
+}
+
+/// Colors are only used on a terminal and can be turned off with NO_COLOR
+fn paint(style: Style, text: &str) -> String {
+    if std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none() {
+        format!("{style}{text}{style:#}")
+    } else {
+        text.to_string()
+    }
+}
+
+fn heading(title: &str) -> String {
+    paint(
+        HEADING,
+        &format!(
+            "── {title} {}",
+            "─".repeat(40usize.saturating_sub(title.len()))
+        ),
+    )
+}
+
+/// Static summary of a finished run for `--output-format pretty`, with the changes against
+/// `previous` if there is a stored run
+pub fn print_summary(result: &SpeedTestResult, previous: Option<&HistoryRecord>) {
+    let metadata = &result.metadata;
+    let latencies = result.latency.latencies();
+    let latency = Some(result.latency.avg_latency).filter(|l| l.is_finite());
+    let jitter = calc_jitter(&latencies);
+    let download = headline_mbit(&result.measurements, TestType::Download);
+    let upload = headline_mbit(&result.measurements, TestType::Upload);
+
+    println!("\n{}", heading("Result"));
+    if let Some(previous) = previous {
+        println!(
+            "  {}",
+            paint(
+                DIM,
+                &format!(
+                    "compared with the run of {}",
+                    previous.timestamp.format("%Y-%m-%d %H:%M:%S")
+                )
+            )
+        );
+    }
+    print_speed(
+        "↓ Download",
+        download,
+        previous.and_then(|p| p.download_mbit),
+    );
+    print_speed("↑ Upload", upload, previous.and_then(|p| p.upload_mbit));
+    match latency {
+        Some(latency) => println!(
+            "  {:<12}{:>10.2} ms  {}{}",
+            "⇄ Latency",
+            latency,
+            delta(latency, previous.and_then(|p| p.latency_ms), false),
+            paint(
+                DIM,
+                &format!(
+                    "jitter {}",
+                    jitter.map_or("-".to_string(), |j| format!("{j:.2} ms"))
+                )
+            )
+        ),
+        None => println!("  {:<12}{:>10}", "⇄ Latency", "-"),
+    }
+    if let Some(dns_lookup) = &result.dns_lookup {
+        println!(
+            "  {:<12}{:>10.2} ms  {}",
+            "DNS lookup",
+            dns_lookup.duration_ms,
+            paint(DIM, &dns_lookup.host)
+        );
+    }
+    if let Some(icmp) = &result.icmp {
+        println!(
+            "  {:<12}{:>10.2} ms  {}",
+            "ICMP RTT",
+            icmp.avg_ms,
+            paint(DIM, &format!("{} lost", icmp.lost))
+        );
+    }
+    if let Some(stun) = &result.stun {
+        println!(
+            "  {:<12}{:>10.2} ms  {}",
+            "UDP RTT",
+            stun.avg_ms,
+            paint(
+                DIM,
+                &format!(
+                    "jitter {}, {:.1}% lost",
+                    stun.jitter_ms
+                        .map_or("-".to_string(), |j| format!("{j:.2} ms")),
+                    stun.loss_percent()
+                )
+            )
+        );
+    }
+    if let Some(mos) = result.mos() {
+        let rating = match mos {
+            m if m >= 4.0 => Rating::Good,
+            m if m >= 3.6 => Rating::Fair,
+            _ => Rating::Poor,
+        };
+        println!(
+            "  {:<12}{}     {}",
+            "VoIP MOS",
+            paint(rating.style(), &format!("{mos:>10.1}")),
+            paint(DIM, "estimated call quality, 4.4 is the best")
+        );
+    }
+    if let Some(bufferbloat) = &result.bufferbloat {
+        let rating = match bufferbloat.grade {
+            Grade::A | Grade::B => Rating::Good,
+            Grade::C => Rating::Fair,
+            Grade::D | Grade::F => Rating::Poor,
+        };
+        let loaded = |latency: Option<f64>| latency.map_or("-".to_string(), |l| format!("{l:.0}"));
+        println!(
+            "  {:<12}{}     {}",
+            "Bufferbloat",
+            paint(rating.style(), &format!("{:>10}", bufferbloat.grade)),
+            paint(
+                DIM,
+                &format!(
+                    "loaded latency {} ms down, {} ms up",
+                    loaded(bufferbloat.download_ms),
+                    loaded(bufferbloat.upload_ms)
+                )
+            )
+        );
+    }
+    for capacity in &result.capacity {
+        println!(
+            "  {:<12}{:>10.2} Mbit/s  {}",
+            match capacity.test_type {
+                TestType::Download => "↓ Capacity",
+                TestType::Upload => "↑ Capacity",
+            },
+            capacity.mbit,
+            paint(
+                DIM,
+                &format!(
+                    "estimated, ±{:.2}, the largest payload reaches {:.0}%",
+                    capacity.uncertainty_mbit, capacity.saturation_percent
+                )
+            )
+        );
+    }
+    for slow_start in &result.slow_start {
+        println!(
+            "  {:<12}{:>10.0} ms  {}",
+            match slow_start.test_type {
+                TestType::Download => "↓ Ramp-up",
+                TestType::Upload => "↑ Ramp-up",
+            },
+            slow_start.ramp_ms,
+            paint(
+                DIM,
+                &format!(
+                    "to 90% of peak, {}",
+                    if slow_start.window_limited() {
+                        "limited by congestion window growth"
+                    } else {
+                        "limited by capacity"
+                    }
+                )
+            )
+        );
+    }
+    for sustained in &result.sustained {
+        let rating = if sustained.throttled() {
+            Rating::Poor
+        } else {
+            Rating::Good
+        };
+        println!(
+            "  {:<12}{} Mbit/s  {}",
+            match sustained.test_type {
+                TestType::Download => "↓ Sustained",
+                TestType::Upload => "↑ Sustained",
+            },
+            paint(rating.style(), &format!("{:>10.2}", sustained.steady_mbit)),
+            paint(
+                DIM,
+                &format!(
+                    "steady state, {:.2} initially, {}",
+                    sustained.initial_mbit,
+                    if sustained.throttled() {
+                        "throttling suspected"
+                    } else {
+                        "no throttling"
+                    }
+                )
+            )
+        );
+    }
+    println!(
+        "  {:<12}{} {}",
+        "Server",
+        metadata.colo,
+        paint(DIM, &format!("({}, {})", metadata.city, metadata.country))
+    );
+
+    if let Some(percentiles) = result.latency.percentiles {
+        println!("\n{}", heading("Latency percentiles"));
+        for (label, latency_ms) in percentiles.rows() {
+            println!("  {label:<8}{latency_ms:>10.2} ms");
+        }
+    }
+
+    for test_type in [TestType::Download, TestType::Upload] {
+        print_payload_sizes(result, test_type);
+    }
+
+    println!("\n{}", heading("Quality"));
+    for (use_case, rating, reason) in
+        quality_hints(sustained_download(result), upload, latency, jitter)
+    {
+        println!(
+            "  {} {:<18}{}  {}",
+            paint(rating.style(), rating.symbol()),
+            use_case,
+            paint(rating.style(), &format!("{:<4}", rating.label())),
+            paint(DIM, &format!("({reason})"))
+        );
+    }
+    if result.is_incomplete() {
+        println!(
+            "\n{}",
+            paint(FAIR, "⚠ Some phases were aborted, the result is incomplete")
+        );
+    }
+}
+
+fn print_speed(label: &str, mbit: Option<f64>, previous: Option<f64>) {
+    match mbit {
+        Some(mbit) => println!(
+            "{}",
+            format!(
+                "  {label:<12}{mbit:>10.2} Mbit/s  {}",
+                delta(mbit, previous, true)
+            )
+            .trim_end()
+        ),
+        None => println!("  {label:<12}{:>10}", "-"),
+    }
+}
+
+/// Change against the previous run e.g. `(▲ +6%) `, colored by whether it's an improvement
+fn delta(current: f64, previous: Option<f64>, higher_is_better: bool) -> String {
+    let Some(previous) = previous.filter(|p| *p > 0.0) else {
+        return String::new();
+    };
+    let percent = (current - previous) / previous * 100.0;
+    let (arrow, style) = match percent {
+        p if p.abs() < 0.5 => ("=", DIM),
+        p if (p > 0.0) == higher_is_better => (if p > 0.0 { "▲" } else { "▼" }, GOOD),
+        p => (if p > 0.0 { "▲" } else { "▼" }, POOR),
+    };
+    format!("{} ", paint(style, &format!("({arrow} {percent:+.0}%)")))
+}
+
+fn print_payload_sizes(result: &SpeedTestResult, test_type: TestType) {
+    let payload_sizes: IndexSet<usize> = result
+        .measurements
+        .iter()
+        .filter(|m| m.test_type == test_type && m.is_valid())
+        .map(|m| m.payload_size)
+        .collect();
+    if payload_sizes.is_empty() {
+        return;
+    }
+    println!("\n{}", heading(&format!("{test_type:?} by payload size")));
+    for payload_size in payload_sizes {
+        let mbits: Vec<f64> = result
+            .measurements
+            .iter()
+            .filter(|m| m.test_type == test_type && m.payload_size == payload_size)
+            .filter(|m| m.is_valid())
+            .map(|m| m.mbit)
+            .collect();
+        let samples = mbits.len();
+        match calc_stats(mbits) {
+            Some((min, _, median, _, max, avg)) => println!(
+                "  {:<8}{avg:>10.2} Mbit/s  {}",
+                format_bytes(payload_size),
+                paint(
+                    DIM,
+                    &format!("median {median:.2}, {min:.2} … {max:.2}, {samples} samples")
+                )
+            ),
+            None => println!(
+                "  {:<8}{:>10}  {}",
+                format_bytes(payload_size),
+                "-",
+                paint(DIM, &format!("{samples} samples, too few for stats"))
+            ),
+        }
+    }
+}
+
+/// Video bitrates per stream, best tier first
+const STREAM_TIERS: [(&str, f64); 3] = [("4K", 25.0), ("HD", 5.0), ("SD", 3.0)];
+
+/// Download speed that holds most of the time, the lower quartile of the largest payload size.
+/// With too few samples for quartiles it is the average.
+fn sustained_download(result: &SpeedTestResult) -> Option<f64> {
+    let largest_payload = result
+        .measurements
+        .iter()
+        .filter(|m| m.test_type == TestType::Download && m.is_valid())
+        .map(|m| m.payload_size)
+        .max()?;
+    let mbits: Vec<f64> = result
+        .measurements
+        .iter()
+        .filter(|m| m.test_type == TestType::Download && m.payload_size == largest_payload)
+        .filter(|m| m.is_valid())
+        .map(|m| m.mbit)
+        .collect();
+    let avg = mbits.iter().sum::<f64>() / mbits.len() as f64;
+    Some(calc_stats(mbits).map_or(avg, |(_, q1, _, _, _, _)| q1))
+}
+
+/// Rough suitability of the connection for common use cases
+fn quality_hints(
+    sustained_download: Option<f64>,
+    upload: Option<f64>,
+    latency: Option<f64>,
+    jitter: Option<f64>,
+) -> Vec<(&'static str, Rating, String)> {
+    let mut hints = Vec::new();
+    if let Some(download) = sustained_download {
+        let (rating, reason) = match STREAM_TIERS.iter().find(|(_, mbit)| download >= *mbit) {
+            Some((tier, mbit)) => (
+                match *tier {
+                    "4K" => Rating::Good,
+                    "HD" => Rating::Fair,
+                    _ => Rating::Poor,
+                },
+                format!(
+                    "{tier}, {} stream(s) at once, sustains {download:.0} Mbit/s",
+                    (download / mbit).floor()
+                ),
+            ),
+            None => (
+                Rating::Poor,
+                format!("too slow for SD video, sustains {download:.0} Mbit/s"),
+            ),
+        };
+        hints.push(("Streaming", rating, reason));
+    }
+    if let (Some(upload), Some(latency)) = (upload, latency) {
+        let rating = match (upload, latency) {
+            (u, l) if u >= 3.0 && l < 100.0 => Rating::Good,
+            (u, l) if u >= 1.0 && l < 200.0 => Rating::Fair,
+            _ => Rating::Poor,
+        };
+        hints.push((
+            "Video calls",
+            rating,
+            format!("needs ~3 Mbit/s up and <100 ms, has {upload:.0} Mbit/s and {latency:.0} ms"),
+        ));
+    }
+    if let Some(latency) = latency {
+        let jitter = jitter.unwrap_or(0.0);
+        let rating = match (latency, jitter) {
+            (l, j) if l < 30.0 && j < 10.0 => Rating::Good,
+            (l, j) if l < 60.0 && j < 20.0 => Rating::Fair,
+            _ => Rating::Poor,
+        };
+        hints.push((
+            "Online gaming",
+            rating,
+            format!("needs <30 ms and low jitter, has {latency:.0} ms ±{jitter:.0}"),
+        ));
+    }
+    hints
+}
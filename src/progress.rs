@@ -1,15 +1,92 @@
-use std::io::stdout;
-use std::io::Write;
-
-pub fn print_progress(name: &str, curr: u32, max: u32) {
-    const BAR_LEN: u32 = 30;
-    let progress_line = ((curr as f32 / max as f32) * BAR_LEN as f32) as u32;
-    let remaining_line = BAR_LEN - progress_line;
-    print!(
-        "\r{:<15} [{}{}]",
-        name,
-        (0..progress_line).map(|_| "=").collect::<String>(),
-        (0..remaining_line).map(|_| "-").collect::<String>(),
-    );
-    stdout().flush().expect("error printing progress bar");
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::sync::Mutex;
+use std::time::Duration;
+
+const TEMPLATE: &str = "{prefix:<15} [{bar:30}] {wide_msg}";
+
+/// Bar of the running phase, the bars of finished phases stay on screen above it
+static PHASE: Mutex<Option<PhaseBar>> = Mutex::new(None);
+
+struct PhaseBar {
+    name: String,
+    bar: ProgressBar,
+    /// Elapsed and remaining time, details are shown after it until the next update
+    time: String,
+}
+
+impl PhaseBar {
+    fn new(name: &str, max: u32) -> Self {
+        // hidden when stdout isn't a terminal, the message is cut to the terminal width and redraws
+        // clear the whole bar even when it wraps on narrow terminals
+        let bar = ProgressBar::with_draw_target(Some(max as u64), ProgressDrawTarget::stdout())
+            .with_style(
+                ProgressStyle::with_template(TEMPLATE)
+                    .expect("invalid progress template")
+                    .progress_chars("=-"),
+            )
+            .with_prefix(name.to_string());
+        Self {
+            name: name.to_string(),
+            bar,
+            time: String::new(),
+        }
+    }
+
+    fn finish(self) {
+        self.bar.finish();
+        if self.bar.is_hidden() {
+            // keeps the time and summary of the phase in piped output
+            println!("{:<15} {}", self.name, self.bar.message());
+        } else {
+            // the bar stays on the cursor's line
+            println!();
+        }
+    }
+}
+
+/// Draws the progress bar of a phase with its elapsed time and, while running, the estimated time remaining
+/// based on the average duration of the completed samples. A new `name` starts a new bar below the previous one.
+pub fn print_progress(name: &str, curr: u32, max: u32, elapsed: Duration) {
+    let eta = if curr > 0 && curr < max {
+        let remaining = elapsed.as_secs_f64() / curr as f64 * (max - curr) as f64;
+        format!(", ~{remaining:.1}s left")
+    } else {
+        String::new()
+    };
+    let mut phase = PHASE.lock().unwrap();
+    let phase = match phase.take() {
+        Some(current) if current.name == name => phase.insert(current),
+        previous => {
+            if let Some(previous) = previous {
+                previous.finish();
+            }
+            phase.insert(PhaseBar::new(name, max))
+        }
+    };
+    phase.time = format!("{:.1}s{eta}", elapsed.as_secs_f64());
+    phase.bar.set_length(max as u64);
+    phase.bar.set_position(curr as u64);
+    phase.bar.set_message(phase.time.clone());
+}
+
+/// Shows `detail` e.g. the speed of the last sample after the time of the current bar
+pub fn print_progress_detail(detail: &str) {
+    if let Some(phase) = PHASE.lock().unwrap().as_ref() {
+        phase.bar.set_message(format!("{}  {detail}", phase.time));
+    }
+}
+
+/// Leaves the bar of the finished phase on screen, following output starts on the next line
+pub fn finish_progress() {
+    if let Some(phase) = PHASE.lock().unwrap().take() {
+        phase.finish();
+    }
+}
+
+/// Prints `line` above the bar of the running phase instead of garbling it
+pub fn print_above_progress(line: &str) {
+    match PHASE.lock().unwrap().as_ref() {
+        Some(phase) => phase.bar.suspend(|| println!("{line}")),
+        None => println!("{line}"),
+    }
 }
@@ -0,0 +1,82 @@
+use crate::speedtest::TestType;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+/// CPU utilization of a single core above which the client is likely the bottleneck
+const BOTTLENECK_CPU_PERCENT: f64 = 90.0;
+
+/// CPU time and memory used by the process at a point in time
+#[derive(Clone, Copy, Debug)]
+pub struct ResourceSnapshot {
+    at: Instant,
+    cpu_time: Duration,
+}
+
+impl ResourceSnapshot {
+    /// Returns `None` on platforms where the usage can't be determined
+    pub fn now() -> Option<Self> {
+        Some(Self {
+            at: Instant::now(),
+            cpu_time: rusage()?.0,
+        })
+    }
+
+    /// Usage of the process between this snapshot and now
+    pub fn usage_since(&self, test_type: TestType, payload_size: usize) -> Option<PhaseUsage> {
+        let (cpu_time, peak_rss_kb) = rusage()?;
+        let cpu_time = cpu_time.saturating_sub(self.cpu_time);
+        let wall_time = self.at.elapsed();
+        Some(PhaseUsage {
+            test_type,
+            payload_size,
+            cpu_time_ms: cpu_time.as_secs_f64() * 1_000.0,
+            cpu_percent: cpu_time.as_secs_f64() / wall_time.as_secs_f64() * 100.0,
+            peak_rss_kb,
+        })
+    }
+}
+
+/// Resources used by the tool itself while testing one payload size
+#[derive(Clone, Debug, Serialize)]
+pub struct PhaseUsage {
+    pub test_type: TestType,
+    pub payload_size: usize,
+    pub cpu_time_ms: f64,
+    /// CPU time relative to wall time, 100% means one core was fully busy
+    pub cpu_percent: f64,
+    pub peak_rss_kb: u64,
+}
+
+impl PhaseUsage {
+    /// Returns whether the client machine rather than the network likely limited the measurement
+    pub fn is_cpu_bound(&self) -> bool {
+        self.cpu_percent >= BOTTLENECK_CPU_PERCENT
+    }
+}
+
+/// Total CPU time and peak resident set size in KB of the process
+#[cfg(unix)]
+fn rusage() -> Option<(Duration, u64)> {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return None;
+    }
+    let to_duration = |time: libc::timeval| {
+        Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64)
+    };
+    // ru_maxrss is reported in bytes on macOS and in KB everywhere else
+    let peak_rss_kb = if cfg!(target_os = "macos") {
+        usage.ru_maxrss as u64 / 1024
+    } else {
+        usage.ru_maxrss as u64
+    };
+    Some((
+        to_duration(usage.ru_utime) + to_duration(usage.ru_stime),
+        peak_rss_kb,
+    ))
+}
+
+#[cfg(not(unix))]
+fn rusage() -> Option<(Duration, u64)> {
+    None
+}
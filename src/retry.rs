@@ -0,0 +1,90 @@
+use crate::speedtest::{sleep_unless_stopped, stop_requested};
+use crate::SpeedTestCLIOptions;
+use log;
+use std::{fmt::Display, time::Duration};
+
+/// Retry behaviour for the individual requests of a speed test
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    /// Delay before the first retry, doubled for every further attempt
+    pub backoff: Duration,
+}
+
+impl RetryPolicy {
+    pub fn none() -> Self {
+        Self {
+            retries: 0,
+            backoff: Duration::ZERO,
+        }
+    }
+
+    pub fn from_options(options: &SpeedTestCLIOptions) -> Self {
+        Self {
+            retries: options.retries,
+            backoff: options.retry_backoff,
+        }
+    }
+
+    /// Runs `request` until it succeeds or all retries are used up.
+    /// Returns the outcome of the last attempt and the number of retries it took.
+    pub fn run<T, E: Display>(
+        &self,
+        mut request: impl FnMut() -> Result<T, E>,
+    ) -> (Result<T, E>, u32) {
+        let mut retries = 0;
+        loop {
+            match request() {
+                // a stopped test isn't retried
+                Err(e) if retries < self.retries && !stop_requested() => {
+                    let delay = self.backoff * 2u32.saturating_pow(retries);
+                    retries += 1;
+                    log::warn!(
+                        "request failed ({e}), retry {retries}/{} in {}ms",
+                        self.retries,
+                        delay.as_millis()
+                    );
+                    if !sleep_unless_stopped(delay) {
+                        return (Err(e), retries);
+                    }
+                }
+                outcome => return (outcome, retries),
+            }
+        }
+    }
+}
+
+const MIN_PACING_DELAY: Duration = Duration::from_millis(500);
+const MAX_PACING_DELAY: Duration = Duration::from_secs(10);
+
+/// Delay inserted between samples while the server is rate limiting or failing
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Pacer {
+    delay: Duration,
+}
+
+impl Pacer {
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    /// Waits for the current delay before the next sample is taken, `false` if a stop was
+    /// requested meanwhile
+    pub fn wait(&self) -> bool {
+        sleep_unless_stopped(self.delay)
+    }
+
+    /// Doubles the delay after a throttled sample and returns the new delay
+    pub fn slow_down(&mut self) -> Duration {
+        self.delay = (self.delay * 2).clamp(MIN_PACING_DELAY, MAX_PACING_DELAY);
+        self.delay
+    }
+
+    /// Gradually returns to full speed after a successful sample
+    pub fn recover(&mut self) {
+        self.delay = self.delay * 3 / 4;
+        if self.delay < MIN_PACING_DELAY / 4 {
+            self.delay = Duration::ZERO;
+        }
+    }
+}
@@ -0,0 +1,281 @@
+use crate::daemon::run_once;
+use crate::exporter::Metrics;
+use crate::history::{HistoryRecord, HistoryStore};
+use crate::integrations::Integrations;
+use crate::speedtest::{stop_requested, PayloadSize, SpeedTestEvent};
+use crate::{OutputFormat, SpeedTestCLIOptions};
+use chrono::Utc;
+use log;
+use reqwest::blocking::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    io::Write,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+pub const DEFAULT_ADDR: &str = "127.0.0.1:9798";
+/// How often the accept loop checks whether it should shut down
+const STOP_POLL: Duration = Duration::from_millis(250);
+
+/// Test configuration accepted by `POST /run` and the C API. Missing fields fall back to the
+/// CLI options.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct RunConfig {
+    nr_tests: Option<u32>,
+    nr_latency_tests: Option<u32>,
+    max_payload_size: Option<String>,
+    download_only: Option<bool>,
+    upload_only: Option<bool>,
+    disable_dynamic_max_payload_size: Option<bool>,
+}
+
+impl RunConfig {
+    pub(crate) fn apply(
+        self,
+        mut options: SpeedTestCLIOptions,
+    ) -> Result<SpeedTestCLIOptions, String> {
+        if let Some(nr_tests) = self.nr_tests {
+            if !(4..1000).contains(&nr_tests) {
+                return Err("nr_tests needs to be between 4 and 999".to_string());
+            }
+            options.nr_tests = nr_tests;
+        }
+        if let Some(nr_latency_tests) = self.nr_latency_tests {
+            if nr_latency_tests >= 1000 {
+                return Err("nr_latency_tests needs to be between 0 and 999".to_string());
+            }
+            options.nr_latency_tests = nr_latency_tests;
+        }
+        if let Some(max_payload_size) = self.max_payload_size {
+            options.max_payload_size = PayloadSize::from(max_payload_size)?;
+        }
+        if let Some(download_only) = self.download_only {
+            options.download_only = download_only;
+        }
+        if let Some(upload_only) = self.upload_only {
+            options.upload_only = upload_only;
+        }
+        if options.download_only && options.upload_only {
+            return Err("download_only and upload_only are mutually exclusive".to_string());
+        }
+        if let Some(disable) = self.disable_dynamic_max_payload_size {
+            options.disable_dynamic_max_payload_size = disable;
+        }
+        // runs started through the API never write test output to stdout
+        options.output_format = OutputFormat::None;
+        Ok(options)
+    }
+}
+
+struct ServerState {
+    client: Client,
+    options: SpeedTestCLIOptions,
+    history: HistoryStore,
+    running: AtomicBool,
+    subscribers: Mutex<Vec<Sender<String>>>,
+    metrics: Mutex<Metrics>,
+    integrations: Integrations,
+}
+
+impl ServerState {
+    /// Sends a server-sent event to all connected `/events` clients
+    fn broadcast(&self, data: &impl Serialize) {
+        let Ok(data) = serde_json::to_string(data) else {
+            return;
+        };
+        let message = format!("data: {data}\n\n");
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(message.clone()).is_ok());
+    }
+}
+
+/// Serves the HTTP API on `addr` until a stop is requested, a running test is cancelled and
+/// stored before returning
+pub fn run_server(
+    client: Client,
+    options: SpeedTestCLIOptions,
+    addr: SocketAddr,
+) -> Result<(), String> {
+    let history = HistoryStore::open(options.history_file.clone())?;
+    let integrations = Integrations::new(options.integrations.clone())?;
+    let server = Server::http(addr).map_err(|e| format!("Failed to listen on {addr}: {e}"))?;
+    println!("Serving cfspeedtest API on http://{addr}");
+    let state = Arc::new(ServerState {
+        client,
+        options,
+        history,
+        running: AtomicBool::new(false),
+        subscribers: Mutex::new(Vec::new()),
+        metrics: Mutex::new(Metrics::default()),
+        integrations,
+    });
+    loop {
+        if stop_requested() && !state.running.load(Ordering::SeqCst) {
+            log::info!("shutting down");
+            return Ok(());
+        }
+        let request = match server.recv_timeout(STOP_POLL) {
+            Ok(Some(request)) => request,
+            Ok(None) => continue,
+            Err(e) => return Err(format!("failed to accept request: {e}")),
+        };
+        let state = state.clone();
+        // event streams stay open, so every request gets its own thread
+        thread::spawn(move || handle_request(&state, request));
+    }
+}
+
+fn handle_request(state: &Arc<ServerState>, mut request: Request) {
+    let method = request.method().clone();
+    let path = request
+        .url()
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    log::debug!("{method} {path}");
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let response = match (&method, segments.as_slice()) {
+        (Method::Post, ["run"]) => {
+            let mut body = String::new();
+            if let Err(e) = request.as_reader().read_to_string(&mut body) {
+                error_response(400, &e.to_string())
+            } else {
+                start_run(state, &body)
+            }
+        }
+        (Method::Get, ["results", "latest"]) => match state.history.load() {
+            Ok(records) => match records.into_iter().max_by_key(|r| r.id) {
+                Some(record) => json_response(200, &record),
+                None => error_response(404, "no results stored yet"),
+            },
+            Err(e) => error_response(500, &e.to_string()),
+        },
+        (Method::Get, ["results", id]) => match id.parse::<u64>() {
+            Ok(id) => match state.history.load() {
+                Ok(records) => match records.into_iter().find(|r| r.id == id) {
+                    Some(record) => json_response(200, &record),
+                    None => error_response(404, &format!("no result with id {id}")),
+                },
+                Err(e) => error_response(500, &e.to_string()),
+            },
+            Err(_) => error_response(400, "result id needs to be a number"),
+        },
+        (Method::Get, ["events"]) => {
+            stream_events(state, request);
+            return;
+        }
+        (Method::Get, ["healthz"]) => json_response(200, &json!({ "status": "ok" })),
+        (Method::Get, ["readyz"]) if stop_requested() => {
+            json_response(503, &json!({ "status": "shutting down" }))
+        }
+        (Method::Get, ["readyz"]) => json_response(200, &json!({ "status": "ready" })),
+        (Method::Get, ["metrics"]) => {
+            let body = state.metrics.lock().unwrap().render();
+            Response::from_string(body).with_header(
+                Header::from_bytes("Content-Type", "text/plain; version=0.0.4").unwrap(),
+            )
+        }
+        _ => error_response(404, "not found"),
+    };
+    if let Err(e) = request.respond(response) {
+        log::warn!("failed to send response: {e}");
+    }
+}
+
+fn start_run(state: &Arc<ServerState>, body: &str) -> Response<std::io::Cursor<Vec<u8>>> {
+    let config: RunConfig = if body.trim().is_empty() {
+        RunConfig::default()
+    } else {
+        match serde_json::from_str(body) {
+            Ok(config) => config,
+            Err(e) => return error_response(400, &format!("invalid run config: {e}")),
+        }
+    };
+    let options = match config.apply(state.options.clone()) {
+        Ok(options) => options,
+        Err(e) => return error_response(400, &e),
+    };
+    if stop_requested() {
+        return error_response(503, "the server is shutting down");
+    }
+    if state.running.swap(true, Ordering::SeqCst) {
+        return error_response(409, "a speed test is already running");
+    }
+
+    let state = state.clone();
+    thread::spawn(move || {
+        let events = state.clone();
+        let outcome = run_once(&state.client, &options, move |event: SpeedTestEvent| {
+            events.broadcast(&event)
+        })
+        .map(|result| {
+            let record = HistoryRecord::from_result(&result, Utc::now());
+            match state.history.append(vec![record.clone()]) {
+                Ok(mut stored) => stored.remove(0),
+                Err(e) => {
+                    log::error!("failed to store result: {e}");
+                    record
+                }
+            }
+        });
+        match &outcome {
+            Ok(record) => state.broadcast(&json!({ "type": "result", "result": record })),
+            Err(e) => state.broadcast(&json!({ "type": "failed", "error": e })),
+        }
+        state.metrics.lock().unwrap().record_run(&outcome);
+        state.integrations.publish(&outcome);
+        state.running.store(false, Ordering::SeqCst);
+    });
+    json_response(202, &json!({ "status": "started" }))
+}
+
+/// Streams `SpeedTestEvent`s as server-sent events until the client disconnects
+fn stream_events(state: &Arc<ServerState>, request: Request) {
+    let (sender, receiver) = mpsc::channel();
+    state.subscribers.lock().unwrap().push(sender);
+    let mut writer = request.into_writer();
+    let header = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n";
+    if writer
+        .write_all(header.as_bytes())
+        .and_then(|_| writer.flush())
+        .is_err()
+    {
+        return;
+    }
+    for message in receiver {
+        if writer
+            .write_all(message.as_bytes())
+            .and_then(|_| writer.flush())
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+pub(crate) fn json_response(
+    status: u16,
+    body: &impl Serialize,
+) -> Response<std::io::Cursor<Vec<u8>>> {
+    let body = serde_json::to_vec(body).unwrap_or_default();
+    Response::from_data(body)
+        .with_status_code(status)
+        .with_header(Header::from_bytes("Content-Type", "application/json").unwrap())
+}
+
+pub(crate) fn error_response(status: u16, message: &str) -> Response<std::io::Cursor<Vec<u8>>> {
+    json_response(status, &json!({ "error": message }))
+}
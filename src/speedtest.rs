@@ -1,22 +1,114 @@
+use crate::bufferbloat::{Bufferbloat, LatencyProbe};
+use crate::capacity::Capacity;
+use crate::event_socket;
+use crate::fast;
+use crate::history::{HistoryRecord, HistoryStore};
+use crate::host::HostInfo;
+use crate::icmp::{self, IcmpResult};
+use crate::kv;
+use crate::measurements::confidence_interval;
 use crate::measurements::format_bytes;
-use crate::measurements::log_measurements;
 use crate::measurements::Measurement;
-use crate::progress::print_progress;
+use crate::measurements::PhaseError;
+use crate::measurements::{calc_jitter, estimate_mos, percentile};
+use crate::measurements::{log_measurements, print_run, LatencySummary, RunSummary};
+use crate::metadata::{cache_metadata, cached_metadata};
+pub use crate::metadata::{fetch_metadata, Metadata};
+use crate::plan::{Phase, PhaseKind, TestPlan};
+use crate::pretty;
+use crate::progress::{
+    finish_progress, print_above_progress, print_progress, print_progress_detail,
+};
+use crate::resources::{PhaseUsage, ResourceSnapshot};
+use crate::retry::{Pacer, RetryPolicy};
+use crate::stun::{run_stun_test, StunResult};
+use crate::sustained::{run_sustained, Sustained};
+use crate::throttle::Throttled;
+use crate::throughput::{
+    print_throughput, shared_series, take_points, time_to_peak, Metered, SlowStart,
+    ThroughputPoint, SLOW_START_MIN_PAYLOAD,
+};
+use crate::timings::{self, RequestTiming};
+use crate::tls;
+use crate::trace::{self, PathTrace};
+use crate::Backend;
 use crate::OutputFormat;
 use crate::SpeedTestCLIOptions;
+use chrono::Utc;
+use indexmap::IndexSet;
 use log;
 use regex::Regex;
-use reqwest::{blocking::Client, StatusCode};
-use serde::Serialize;
+use reqwest::{
+    blocking::{Body, Client, RequestBuilder, Response},
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
 use std::{
+    collections::BTreeMap,
     fmt::Display,
+    io::{self, Read},
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        mpsc, Arc, Mutex, OnceLock,
+    },
+    thread,
     time::{Duration, Instant},
 };
 
-const BASE_URL: &str = "https://speed.cloudflare.com";
-const DOWNLOAD_URL: &str = "__down?bytes=";
+pub(crate) const BASE_URL: &str = "https://speed.cloudflare.com";
+pub(crate) const DOWNLOAD_URL: &str = "__down?bytes=";
 const UPLOAD_URL: &str = "__up";
 
+/// Server the test requests are sent to, resolved from `--backend` at the start of a run
+#[derive(Clone, Debug, Default)]
+pub enum Endpoint {
+    #[default]
+    Cloudflare,
+    /// Netflix server picked by fast.com, `url` points at its `/speedtest` resource
+    Fast { url: String },
+    /// User supplied URL templates, `{bytes}` is replaced with the payload size
+    Custom {
+        download: Option<String>,
+        upload: Option<String>,
+    },
+}
+
+impl Endpoint {
+    pub fn download_url(&self, bytes: usize) -> String {
+        match self {
+            Self::Cloudflare => format!("{BASE_URL}/{DOWNLOAD_URL}{bytes}"),
+            Self::Fast { url } => fast::range_url(url, bytes),
+            Self::Custom { download, .. } => expand_template(download, bytes),
+        }
+    }
+
+    pub fn upload_url(&self, bytes: usize) -> String {
+        match self {
+            Self::Cloudflare => format!("{BASE_URL}/{UPLOAD_URL}"),
+            Self::Fast { url } => fast::range_url(url, bytes),
+            Self::Custom { upload, .. } => expand_template(upload, bytes),
+        }
+    }
+
+    /// Uses the custom URL templates if any was given
+    pub(crate) fn custom(options: &SpeedTestCLIOptions) -> Option<Self> {
+        if options.download_url.is_none() && options.upload_url.is_none() {
+            return None;
+        }
+        Some(Self::Custom {
+            download: options.download_url.clone(),
+            upload: options.upload_url.clone(),
+        })
+    }
+
+    /// Only Cloudflare reports its processing time, elsewhere latency includes it
+    fn reports_server_timing(&self) -> bool {
+        matches!(self, Self::Cloudflare)
+    }
+}
+
 #[derive(Clone, Copy, Debug, Hash, Serialize, Eq, PartialEq)]
 pub enum TestType {
     Download,
@@ -30,6 +122,9 @@ pub enum PayloadSize {
     M10 = 10_000_000,
     M25 = 25_000_000,
     M100 = 100_000_000,
+    M250 = 250_000_000,
+    M500 = 500_000_000,
+    G1 = 1_000_000_000,
 }
 
 impl Display for PayloadSize {
@@ -46,227 +141,1855 @@ impl PayloadSize {
             "10_000_000" | "10000000" | "10m" | "10mb" => Ok(Self::M10),
             "25_000_000" | "25000000" | "25m" | "25mb" => Ok(Self::M25),
             "100_000_000" | "100000000" | "100m" | "100mb" => Ok(Self::M100),
-            _ => Err("Value needs to be one of 100k, 1m, 10m, 25m or 100m".to_string()),
+            "250_000_000" | "250000000" | "250m" | "250mb" => Ok(Self::M250),
+            "500_000_000" | "500000000" | "500m" | "500mb" => Ok(Self::M500),
+            "1_000_000_000" | "1000000000" | "1g" | "1gb" => Ok(Self::G1),
+            _ => Err(
+                "Value needs to be one of 100k, 1m, 10m, 25m, 100m, 250m, 500m or 1g".to_string(),
+            ),
         }
     }
 
     pub fn sizes_from_max(max_payload_size: PayloadSize) -> Vec<usize> {
         log::debug!("getting payload iterations for max_payload_size {max_payload_size:?}");
-        let payload_bytes: Vec<usize> =
-            vec![100_000, 1_000_000, 10_000_000, 25_000_000, 100_000_000];
+        let payload_bytes: Vec<usize> = vec![
+            100_000,
+            1_000_000,
+            10_000_000,
+            25_000_000,
+            100_000_000,
+            250_000_000,
+            500_000_000,
+            1_000_000_000,
+        ];
         match max_payload_size {
             PayloadSize::K100 => payload_bytes[0..1].to_vec(),
             PayloadSize::M1 => payload_bytes[0..2].to_vec(),
             PayloadSize::M10 => payload_bytes[0..3].to_vec(),
             PayloadSize::M25 => payload_bytes[0..4].to_vec(),
             PayloadSize::M100 => payload_bytes[0..5].to_vec(),
+            PayloadSize::M250 => payload_bytes[0..6].to_vec(),
+            PayloadSize::M500 => payload_bytes[0..7].to_vec(),
+            PayloadSize::G1 => payload_bytes[0..8].to_vec(),
         }
     }
 }
 
-pub struct Metadata {
-    city: String,
-    country: String,
-    ip: String,
-    asn: String,
-    colo: String,
+/// Progress notifications emitted while a speed test is running
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SpeedTestEvent {
+    Metadata(Box<Metadata>),
+    DnsLookup(DnsLookup),
+    Latency(LatencySample),
+    PhaseStarted {
+        test_type: TestType,
+        payload_size: usize,
+    },
+    Measurement(Measurement),
+    /// The server answered with 429 or 5xx and samples are now taken with a delay
+    Throttled {
+        status: u16,
+        delay_ms: u64,
+    },
+    /// Percentiles of the idle latency once all latency samples are taken
+    LatencyPercentiles(LatencyPercentiles),
+    Bufferbloat(Bufferbloat),
+    /// Ramp-up of the large transfers of one direction
+    SlowStart(SlowStart),
+    Capacity(Capacity),
+    Sustained(Sustained),
+    Path(PathTrace),
+    Finished,
 }
 
-impl Display for Metadata {
+/// Why a single sample could not be measured
+#[derive(Debug)]
+pub enum SampleError {
+    /// The server is rate limiting (429) or failing (5xx)
+    Throttled(StatusCode),
+    Request(reqwest::Error),
+    Body(io::Error),
+    InvalidResponse(String),
+    /// The whole body was transferred with the first chunks, its transfer can't be timed without
+    /// the TTFB
+    Unmeasurable(usize),
+    /// The transfer was cancelled by `request_stop`
+    Interrupted,
+}
+
+impl Display for SampleError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "City: {}\nCountry: {}\nIp: {}\nAsn: {}\nColo: {}",
-            self.city, self.country, self.ip, self.asn, self.colo
-        )
+        match self {
+            Self::Throttled(status) => write!(f, "server responded with {status}"),
+            Self::Request(e) => write!(f, "failed to get response: {e}"),
+            Self::Body(e) => write!(f, "failed to read response body: {e}"),
+            Self::InvalidResponse(reason) => write!(f, "invalid response: {reason}"),
+            Self::Unmeasurable(bytes) => write!(
+                f,
+                "the {} payload fits into a single chunk, too small to time without the TTFB",
+                format_bytes(*bytes)
+            ),
+            Self::Interrupted => write!(f, "interrupted"),
+        }
+    }
+}
+
+impl From<reqwest::Error> for SampleError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Request(e)
+    }
+}
+
+/// Rejects responses that indicate rate limiting or server errors
+fn check_throttled(response: Response) -> Result<Response, SampleError> {
+    let status = response.status();
+    if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+        Err(SampleError::Throttled(status))
+    } else {
+        Ok(response)
+    }
+}
+
+/// A single latency measurement
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct LatencySample {
+    /// Round trip time excluding server processing time, or the total time for suspect samples
+    pub latency_ms: f64,
+    /// Time from sending the request until the response headers arrived
+    pub total_ms: f64,
+    /// Processing time reported by the server in `cfRequestDuration`
+    pub server_ms: f64,
+    /// Set if the reported server time exceeds the observed total, such samples are excluded from
+    /// the average and jitter
+    pub suspect: bool,
+}
+
+impl LatencySample {
+    fn new(total_ms: f64, server_ms: f64) -> Self {
+        let suspect = server_ms > total_ms;
+        Self {
+            latency_ms: if suspect {
+                total_ms
+            } else {
+                total_ms - server_ms
+            },
+            total_ms,
+            server_ms,
+            suspect,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct LatencyResult {
+    pub measurements: Vec<LatencySample>,
+    pub avg_latency: f64,
+    /// Tail latencies of the samples that are not suspect, `None` without samples
+    pub percentiles: Option<LatencyPercentiles>,
+    /// Number of retried latency requests
+    pub retries: u32,
+    /// Number of latency samples skipped because the request failed
+    pub failed: u32,
+}
+
+impl LatencyResult {
+    /// Latencies of all samples that are not suspect
+    pub fn latencies(&self) -> Vec<f64> {
+        self.measurements
+            .iter()
+            .filter(|sample| !sample.suspect)
+            .map(|sample| sample.latency_ms)
+            .collect()
+    }
+
+    pub fn suspect_samples(&self) -> usize {
+        self.measurements.iter().filter(|s| s.suspect).count()
+    }
+
+    fn update_avg(&mut self) {
+        let latencies = self.latencies();
+        self.avg_latency = latencies.iter().sum::<f64>() / latencies.len() as f64;
+        self.percentiles = LatencyPercentiles::of(latencies);
+    }
+
+    /// Adds the samples of a later latency phase
+    fn extend(&mut self, other: LatencyResult) {
+        self.measurements.extend(other.measurements);
+        self.retries += other.retries;
+        self.failed += other.failed;
+        self.update_avg();
+    }
+}
+
+/// Latency percentiles in ms, the tail is what makes a connection feel sluggish
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct LatencyPercentiles {
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    #[serde(rename = "p99.9")]
+    pub p999: f64,
+}
+
+impl LatencyPercentiles {
+    fn of(mut latencies: Vec<f64>) -> Option<Self> {
+        if latencies.is_empty() {
+            return None;
+        }
+        latencies.sort_by(f64::total_cmp);
+        Some(Self {
+            p50: percentile(&latencies, 50.0),
+            p90: percentile(&latencies, 90.0),
+            p99: percentile(&latencies, 99.0),
+            p999: percentile(&latencies, 99.9),
+        })
+    }
+
+    /// Label and value of every percentile
+    pub fn rows(&self) -> [(&'static str, f64); 4] {
+        [
+            ("p50", self.p50),
+            ("p90", self.p90),
+            ("p99", self.p99),
+            ("p99.9", self.p999),
+        ]
+    }
+}
+
+/// Everything collected during a single `speed_test` run
+pub struct SpeedTestResult {
+    pub metadata: Metadata,
+    pub latency: LatencyResult,
+    pub measurements: Vec<Measurement>,
+    /// Number of download and upload samples skipped because the request failed
+    pub failed_measurements: u32,
+    /// Phases that were aborted after repeated request failures
+    pub phase_errors: Vec<PhaseError>,
+    /// Colo switches observed during the run, samples from different colos are not comparable
+    pub colo_changes: Vec<ColoChange>,
+    pub resource_usage: Vec<PhaseUsage>,
+    /// Whether the connection was warmed up before measuring
+    pub prewarmed: bool,
+    /// Whether the metadata was reused from an earlier run instead of being fetched
+    pub metadata_cached: bool,
+    /// Machine that ran the test, only collected with --include-host-info
+    pub host_info: Option<HostInfo>,
+    /// Lookup of the test server, not done with --resolve
+    pub dns_lookup: Option<DnsLookup>,
+    /// ICMP round trips to the test server, only measured with --icmp
+    pub icmp: Option<IcmpResult>,
+    /// UDP round trips to a STUN server, only measured with --stun
+    pub stun: Option<StunResult>,
+    /// Latency increase under load, only measured with --bufferbloat
+    pub bufferbloat: Option<Bufferbloat>,
+    /// Ramp-up of the large transfers per direction
+    pub slow_start: Vec<SlowStart>,
+    /// Link capacity extrapolated from the payload sizes, needs at least three of them
+    pub capacity: Vec<Capacity>,
+    /// Initial and steady-state rates of long transfers, only measured with --sustained
+    pub sustained: Vec<Sustained>,
+    /// Routers on the way to the test server, only traced with --trace-path
+    pub path: Option<PathTrace>,
+    /// Key-value pairs given with --meta
+    pub meta: BTreeMap<String, String>,
+}
+
+/// A switch of the Cloudflare colo serving the test requests
+#[derive(Clone, Debug, Serialize)]
+pub struct ColoChange {
+    pub test_type: TestType,
+    pub payload_size: usize,
+    pub from: String,
+    pub to: String,
+}
+
+/// Tracks the colo serving the requests of a run, the first observed colo is the expected one
+#[derive(Default)]
+pub struct ColoTracker {
+    current: Option<String>,
+    pub changes: Vec<ColoChange>,
+}
+
+impl ColoTracker {
+    /// Records the colo of `measurement` and returns the change if it differs from the previous one
+    fn observe(&mut self, measurement: &Measurement) -> Option<&ColoChange> {
+        let colo = measurement.colo.as_ref()?;
+        let previous = self.current.replace(colo.clone());
+        match previous {
+            Some(previous) if previous != *colo => {
+                self.changes.push(ColoChange {
+                    test_type: measurement.test_type,
+                    payload_size: measurement.payload_size,
+                    from: previous,
+                    to: colo.clone(),
+                });
+                self.changes.last()
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Samples collected by `run_tests` for one test type
+pub struct TestTypeResult {
+    pub test_type: TestType,
+    pub measurements: Vec<Measurement>,
+    /// Number of samples skipped because the request failed
+    pub failed: u32,
+    /// Set if the remaining samples were aborted after repeated request failures
+    pub aborted: Option<PhaseError>,
+    /// Resources used by the tool per completed payload size
+    pub resource_usage: Vec<PhaseUsage>,
+    /// Latencies measured while the transfers were running, only with --bufferbloat
+    pub loaded_latency: Vec<f64>,
+}
+
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+/// How often interruptible sleeps check whether a stop was requested
+const STOP_POLL: Duration = Duration::from_millis(250);
+
+/// Makes a running test skip its remaining samples and report what was collected so far
+pub fn request_stop() {
+    STOP_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+pub fn stop_requested() -> bool {
+    STOP_REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Sleeps for `duration` in short slices, returns `false` as soon as a stop is requested
+pub fn sleep_unless_stopped(duration: Duration) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        if stop_requested() {
+            return false;
+        }
+        thread::sleep(STOP_POLL.min(duration.saturating_sub(start.elapsed())));
+    }
+    !stop_requested()
+}
+
+/// Consecutive failed requests after which the network is considered gone for the current test type
+const MAX_CONSECUTIVE_FAILURES: u32 = 3;
+
+impl SpeedTestResult {
+    /// Total number of retried requests during the run
+    pub fn retries(&self) -> u32 {
+        self.latency.retries + self.measurements.iter().map(|m| m.retries).sum::<u32>()
+    }
+
+    /// Returns whether at least one request of the run succeeded
+    pub fn has_samples(&self) -> bool {
+        !self.latency.measurements.is_empty() || self.measurements.iter().any(|m| m.is_valid())
+    }
+
+    /// Returns whether a phase was aborted before all samples were taken
+    pub fn is_incomplete(&self) -> bool {
+        !self.phase_errors.is_empty()
+    }
+
+    /// Result of a run that could not take any sample
+    fn empty(metadata: Metadata) -> Self {
+        Self {
+            metadata,
+            latency: LatencyResult {
+                measurements: Vec::new(),
+                avg_latency: f64::NAN,
+                percentiles: None,
+                retries: 0,
+                failed: 0,
+            },
+            measurements: Vec::new(),
+            failed_measurements: 0,
+            phase_errors: Vec::new(),
+            colo_changes: Vec::new(),
+            resource_usage: Vec::new(),
+            prewarmed: false,
+            metadata_cached: false,
+            host_info: None,
+            icmp: None,
+            stun: None,
+            bufferbloat: None,
+            dns_lookup: None,
+            slow_start: Vec::new(),
+            capacity: Vec::new(),
+            sustained: Vec::new(),
+            path: None,
+            meta: BTreeMap::new(),
+        }
+    }
+
+    /// Estimated VoIP call quality, from the STUN round trips if measured as calls use UDP
+    pub fn mos(&self) -> Option<f64> {
+        if let Some(stun) = &self.stun {
+            return Some(estimate_mos(
+                stun.avg_ms,
+                stun.jitter_ms.unwrap_or(0.0),
+                stun.loss_percent(),
+            ));
+        }
+        let latency = &self.latency;
+        if !latency.avg_latency.is_finite() {
+            return None;
+        }
+        let sent = latency.measurements.len() as f64 + latency.failed as f64;
+        Some(estimate_mos(
+            latency.avg_latency,
+            calc_jitter(&latency.latencies()).unwrap_or(0.0),
+            latency.failed as f64 / sent * 100.0,
+        ))
+    }
+
+    /// Total number of samples skipped because their request failed
+    pub fn failed_requests(&self) -> u32 {
+        self.latency.failed + self.failed_measurements
+    }
+}
+
+pub fn speed_test(client: Client, options: SpeedTestCLIOptions) -> SpeedTestResult {
+    speed_test_with_events(client, options, &|_| {})
+}
+
+/// Prints the machine readable tables of a finished run, used when the run itself printed the
+/// human readable output
+pub fn print_machine_output(
+    result: &SpeedTestResult,
+    output_format: OutputFormat,
+    include_raw: bool,
+) {
+    let payload_sizes: IndexSet<usize> = result
+        .measurements
+        .iter()
+        .map(|m| m.payload_size)
+        .chain(result.phase_errors.iter().map(|e| e.payload_size))
+        .collect();
+    let latency = &result.latency;
+    let run = RunSummary {
+        metadata: &result.metadata,
+        measurements: log_measurements(
+            &result.measurements,
+            &result.phase_errors,
+            &result.resource_usage,
+            payload_sizes.into_iter().collect(),
+            false,
+            output_format,
+        ),
+        latency: LatencySummary {
+            avg_ms: Some(latency.avg_latency).filter(|l| l.is_finite()),
+            jitter_ms: calc_jitter(&latency.latencies()),
+            percentiles: latency.percentiles,
+            retries: latency.retries,
+            failed: latency.failed,
+        },
+        prewarmed: result.prewarmed,
+        bufferbloat: result.bufferbloat.as_ref(),
+        path: result.path.as_ref(),
+        meta: &result.meta,
+    };
+    print_run(&run, &kv::kv_pairs(result), output_format);
+    if include_raw {
+        print_throughput(&result.measurements, output_format);
+    }
+    if output_format == OutputFormat::Kv {
+        kv::print_kv(result);
+    }
+}
+
+/// Prints `event` as a single JSON line for --output-format ndjson
+fn print_event(event: &SpeedTestEvent) {
+    match serde_json::to_string(event) {
+        Ok(line) => println!("{line}"),
+        Err(e) => log::error!("failed to serialize event: {e}"),
+    }
+}
+
+/// Reuses the metadata of an earlier run unless it expired or a refresh was requested,
+/// returns whether it came from the cache
+fn run_metadata(client: &Client, options: &SpeedTestCLIOptions) -> (Metadata, bool) {
+    if !options.refresh_metadata {
+        if let Some((metadata, age)) = cached_metadata(options.metadata_ttl) {
+            log::info!(
+                "reusing metadata fetched {} ago",
+                humantime::format_duration(Duration::from_secs(age.as_secs()))
+            );
+            return (metadata, true);
+        }
+    }
+    // the handshake runs alongside the metadata request so it doesn't delay the run
+    let tls_probe = options.uses_cloudflare().then(|| {
+        let options = options.clone();
+        thread::spawn(move || tls::negotiate(&options))
+    });
+    let fetched = fetch_metadata(client, &RetryPolicy::from_options(options));
+    let mut metadata = fetched.clone().unwrap_or_else(|e| {
+        warn(options, &format!("failed to fetch metadata: {e}"));
+        Metadata::unavailable()
+    });
+    if !options.ipv4 && !options.ipv6 {
+        metadata.probe_dual_stack();
+    }
+    if options.reverse_dns {
+        metadata.resolve_reverse_dns();
+    }
+    metadata.tls_probe = tls_probe
+        .and_then(|probe| probe.join().ok())
+        .and_then(|outcome| {
+            outcome
+                .map_err(|e| log::warn!("TLS probe failed: {e}"))
+                .ok()
+        });
+    if let Some(tls) = metadata.tls_probe.as_ref().filter(|tls| tls.is_legacy()) {
+        warn(
+            options,
+            &format!(
+                "a TLS probe to the test server negotiated {tls}, a middlebox that downgrades TLS can limit throughput"
+            ),
+        );
+    }
+    // placeholders are not cached so that the next run tries again
+    if fetched.is_ok() {
+        cache_metadata(&metadata);
     }
+    (metadata, false)
 }
 
-pub fn speed_test(client: Client, options: SpeedTestCLIOptions) -> Vec<Measurement> {
-    let metadata = fetch_metadata(&client);
-    if options.output_format == OutputFormat::StdOut {
+/// Most recent run in the history, the pretty output compares against it
+fn previous_run(options: &SpeedTestCLIOptions) -> Option<HistoryRecord> {
+    let history = HistoryStore::open(options.history_file.clone()).ok()?;
+    match history.load() {
+        Ok(records) => records.into_iter().max_by_key(|r| r.timestamp),
+        Err(e) => {
+            log::debug!("not comparing with the previous run: {e}");
+            None
+        }
+    }
+}
+
+/// A missing template expands to an empty URL so that its requests fail instead of going elsewhere
+fn expand_template(template: &Option<String>, bytes: usize) -> String {
+    template
+        .as_deref()
+        .map(|template| template.replace("{bytes}", &bytes.to_string()))
+        .unwrap_or_default()
+}
+
+/// Same as `speed_test` but reports progress to `on_event` while the test is running
+pub fn speed_test_with_events(
+    client: Client,
+    mut options: SpeedTestCLIOptions,
+    on_event: &dyn Fn(SpeedTestEvent),
+) -> SpeedTestResult {
+    if let Some(path) = &options.event_socket {
+        event_socket::listen(path);
+    }
+    let ndjson = options.output_format == OutputFormat::Ndjson;
+    let on_event = &|event: SpeedTestEvent| {
+        if ndjson {
+            print_event(&event);
+        }
+        if let Some(path) = &options.event_socket {
+            event_socket::publish(path, &event);
+        }
+        on_event(event)
+    };
+    if let Some(endpoint) = Endpoint::custom(&options) {
+        options.endpoint = endpoint;
+    }
+    let dns_lookup = dns_lookup(&options);
+    let (mut metadata, metadata_cached) = match options.backend {
+        // a custom server knows nothing about the client
+        _ if matches!(options.endpoint, Endpoint::Custom { .. }) => {
+            (Metadata::unavailable(), false)
+        }
+        Backend::Cloudflare => run_metadata(&client, &options),
+        Backend::Fast => match fast::discover(&client) {
+            Ok(target) => {
+                options.endpoint = Endpoint::Fast { url: target.url };
+                (target.metadata, false)
+            }
+            Err(e) => {
+                warn(&options, &format!("no fast.com test server available: {e}"));
+                return SpeedTestResult {
+                    meta: options.meta.iter().cloned().collect(),
+                    ..SpeedTestResult::empty(Metadata::unavailable())
+                };
+            }
+        },
+    };
+    let host_info = options.include_host_info.then(|| {
+        let mut host_info = HostInfo::collect(&metadata.ip);
+        if options.anonymize {
+            host_info.anonymize();
+        }
+        host_info
+    });
+    if options.anonymize {
+        metadata.anonymize();
+    }
+    if options.output_format.is_human() {
         println!("{metadata}");
+        if let Some(host_info) = &host_info {
+            println!("{host_info}");
+        }
+        if metadata_cached {
+            println!("(cached from an earlier run, use --refresh-metadata to fetch it again)");
+        }
+        if let Some(dns_lookup) = &dns_lookup {
+            println!("{dns_lookup}");
+        }
     }
-    run_latency_test(&client, options.nr_latency_tests, options.output_format);
-    let payload_sizes = PayloadSize::sizes_from_max(options.max_payload_size.clone());
+    if metadata.warp_active() {
+        warn(
+            &options,
+            "Cloudflare WARP is active, the results measure the WARP tunnel rather than the ISP link",
+        );
+    }
+    on_event(SpeedTestEvent::Metadata(Box::new(metadata.clone())));
+    if let Some(dns_lookup) = &dns_lookup {
+        on_event(SpeedTestEvent::DnsLookup(dns_lookup.clone()));
+    }
+    if options.prewarm {
+        prewarm(&client, &options);
+    }
+    let plan = if options.ramp {
+        ramp_plan(&client, &options)
+    } else {
+        TestPlan::from_options(&options)
+    };
+    let mut colos = ColoTracker::default();
+    let (latency, phases) = run_plan(&client, &options, &plan, &mut colos, on_event);
+    let icmp = options
+        .icmp
+        .then(|| icmp_test(&options, &latency))
+        .flatten();
+    let stun = options.stun.then(|| stun_test(&options)).flatten();
     let mut measurements = Vec::new();
-
-    if options.should_download() {
-        measurements.extend(run_tests(
-            &client,
-            test_download,
-            TestType::Download,
-            payload_sizes.clone(),
-            options.nr_tests,
-            options.output_format,
-            options.disable_dynamic_max_payload_size,
-        ));
-    }
-
-    if options.should_upload() {
-        measurements.extend(run_tests(
-            &client,
-            test_upload,
-            TestType::Upload,
-            payload_sizes.clone(),
-            options.nr_tests,
-            options.output_format,
-            options.disable_dynamic_max_payload_size,
-        ));
+    let mut failed_measurements = 0;
+    let mut phase_errors = Vec::new();
+    let mut resource_usage = Vec::new();
+    let (mut download_latency, mut upload_latency) = (Vec::new(), Vec::new());
+    for phase in phases {
+        measurements.extend(phase.measurements);
+        failed_measurements += phase.failed;
+        phase_errors.extend(phase.aborted);
+        resource_usage.extend(phase.resource_usage);
+        match phase.test_type {
+            TestType::Download => download_latency = phase.loaded_latency,
+            TestType::Upload => upload_latency = phase.loaded_latency,
+        }
     }
+    let bufferbloat = options
+        .bufferbloat
+        .then(|| Bufferbloat::grade(latency.avg_latency, download_latency, upload_latency))
+        .flatten();
 
     log_measurements(
         &measurements,
-        payload_sizes,
+        &phase_errors,
+        &resource_usage,
+        plan.payload_sizes(),
         options.verbose,
         options.output_format,
     );
-    measurements
+    let slow_start: Vec<SlowStart> = [TestType::Download, TestType::Upload]
+        .into_iter()
+        .filter_map(|test_type| SlowStart::of(&measurements, test_type))
+        .collect();
+    for slow_start in &slow_start {
+        if options.output_format == OutputFormat::StdOut {
+            println!("\n{slow_start}");
+        }
+        on_event(SpeedTestEvent::SlowStart(slow_start.clone()));
+    }
+    let capacity: Vec<Capacity> = [TestType::Download, TestType::Upload]
+        .into_iter()
+        .filter_map(|test_type| Capacity::estimate(&measurements, test_type))
+        .collect();
+    for capacity in &capacity {
+        if options.output_format == OutputFormat::StdOut {
+            println!("\n{capacity}");
+        }
+        on_event(SpeedTestEvent::Capacity(capacity.clone()));
+    }
+    let sustained = options
+        .sustained
+        .map(|duration| sustained_test(&client, &options, duration))
+        .unwrap_or_default();
+    for sustained in &sustained {
+        if options.output_format == OutputFormat::StdOut {
+            println!("\n{sustained}");
+        }
+        on_event(SpeedTestEvent::Sustained(sustained.clone()));
+    }
+    if let Some(percentiles) = latency.percentiles {
+        on_event(SpeedTestEvent::LatencyPercentiles(percentiles));
+    }
+    if let Some(bufferbloat) = &bufferbloat {
+        if options.output_format == OutputFormat::StdOut {
+            println!("\nBufferbloat grade {bufferbloat}");
+        }
+        on_event(SpeedTestEvent::Bufferbloat(bufferbloat.clone()));
+    }
+    let path = options.trace_path.then(|| trace_test(&options)).flatten();
+    if let Some(path) = &path {
+        on_event(SpeedTestEvent::Path(path.clone()));
+    }
+    on_event(SpeedTestEvent::Finished);
+    let result = SpeedTestResult {
+        metadata,
+        latency,
+        measurements,
+        failed_measurements,
+        phase_errors,
+        colo_changes: colos.changes,
+        resource_usage,
+        prewarmed: options.prewarm,
+        metadata_cached,
+        host_info,
+        icmp,
+        stun,
+        bufferbloat,
+        dns_lookup,
+        slow_start,
+        capacity,
+        sustained,
+        path,
+        meta: options.meta.iter().cloned().collect(),
+    };
+    match options.output_format {
+        OutputFormat::Pretty => pretty::print_summary(&result, previous_run(&options).as_ref()),
+        OutputFormat::StdOut => {}
+        output_format => print_machine_output(&result, output_format, options.include_raw),
+    }
+    if let Some(mos) = result
+        .mos()
+        .filter(|_| options.output_format == OutputFormat::StdOut)
+    {
+        println!("\nEstimated VoIP MOS {mos:.1} of 4.4");
+    }
+    if options.output_format.is_human() && result.retries() > 0 {
+        println!("{} request(s) had to be retried", result.retries());
+    }
+    if options.output_format.is_human() && result.failed_requests() > 0 {
+        println!(
+            "{} sample(s) skipped after failed requests",
+            result.failed_requests()
+        );
+    }
+    result
+}
+
+/// Finds the payload size of every enabled test type with a ramp-up
+fn ramp_plan(client: &Client, options: &SpeedTestCLIOptions) -> TestPlan {
+    let test_types: [(TestType, TestFn, bool); 2] = [
+        (TestType::Download, test_download, options.should_download()),
+        (TestType::Upload, test_upload, options.should_upload()),
+    ];
+    let payload_sizes: Vec<(TestType, usize)> = test_types
+        .into_iter()
+        .filter(|(_, _, enabled)| *enabled)
+        .map(|(test_type, test_fn, _)| (test_type, ramp_up(client, test_fn, test_type, options)))
+        .collect();
+    TestPlan::ramped(options, &payload_sizes)
 }
 
-pub fn run_latency_test(
+/// Returns the smallest payload size that takes at least `--ramp-target` to transfer, the
+/// largest allowed one if none does. The ramp-up transfers aren't part of the results.
+fn ramp_up(
     client: &Client,
-    nr_latency_tests: u32,
-    output_format: OutputFormat,
-) -> (Vec<f64>, f64) {
-    let mut measurements: Vec<f64> = Vec::new();
-    for i in 0..=nr_latency_tests {
+    test_fn: TestFn,
+    test_type: TestType,
+    options: &SpeedTestCLIOptions,
+) -> usize {
+    let quiet = SpeedTestCLIOptions {
+        output_format: OutputFormat::None,
+        ..options.clone()
+    };
+    let payload_sizes = PayloadSize::sizes_from_max(options.max_payload_size.clone());
+    let mut chosen = payload_sizes[0];
+    for payload_size in payload_sizes {
+        if stop_requested() {
+            break;
+        }
+        match test_fn(client, payload_size, &quiet) {
+            Ok(measurement) if measurement.is_valid() && measurement.mbit > 0.0 => {
+                chosen = payload_size;
+                let seconds = payload_size as f64 * 8.0 / (measurement.mbit * 1_000_000.0);
+                log::debug!(
+                    "{test_type:?} ramp-up: {} took {seconds:.2}s",
+                    format_bytes(payload_size)
+                );
+                if seconds >= options.ramp_target.as_secs_f64() {
+                    break;
+                }
+            }
+            Ok(measurement) => {
+                warn(
+                    options,
+                    &format!(
+                        "{test_type:?} ramp-up stopped at {}: status {}",
+                        format_bytes(payload_size),
+                        measurement.status
+                    ),
+                );
+                break;
+            }
+            Err(e) => {
+                warn(
+                    options,
+                    &format!(
+                        "{test_type:?} ramp-up stopped at {}: {e}",
+                        format_bytes(payload_size)
+                    ),
+                );
+                break;
+            }
+        }
+    }
+    if options.output_format.is_human() {
+        println!(
+            "{test_type:?} ramp-up settled on {} payloads",
+            format_bytes(chosen)
+        );
+    }
+    chosen
+}
+
+/// Runs the phases of `plan` in order, merging all latency phases into one result
+fn run_plan(
+    client: &Client,
+    options: &SpeedTestCLIOptions,
+    plan: &TestPlan,
+    colos: &mut ColoTracker,
+    on_event: &dyn Fn(SpeedTestEvent),
+) -> (LatencyResult, Vec<TestTypeResult>) {
+    let mut runners: Vec<PhaseRunner> = Vec::new();
+    for phase in &plan.phases {
+        if let PhaseKind::Transfer(test_type) = phase.kind {
+            if !runners.iter().any(|runner| runner.test_type == test_type) {
+                let test_fn: TestFn = match test_type {
+                    TestType::Download => test_download,
+                    TestType::Upload => test_upload,
+                };
+                runners.push(PhaseRunner::new(
+                    client, test_fn, test_type, options, on_event,
+                ));
+            }
+        }
+    }
+    let latency_phases = plan
+        .phases
+        .iter()
+        .filter(|phase| phase.kind == PhaseKind::Latency)
+        .count();
+
+    let mut latency = LatencyResult::default();
+    for phase in &plan.phases {
+        // with --strict-colo a colo switch ends the whole run
+        if (options.strict_colo && !colos.changes.is_empty()) || stop_requested() {
+            break;
+        }
+        match phase.kind {
+            PhaseKind::Latency => {
+                let result = collect_latency(
+                    client,
+                    options,
+                    phase.repetitions,
+                    phase.concurrency,
+                    on_event,
+                );
+                // a single latency phase is reported right away like it always was
+                if latency_phases == 1 {
+                    report_latency(options, &result);
+                } else if options.output_format.is_human() {
+                    println!();
+                }
+                latency.extend(result);
+            }
+            PhaseKind::Transfer(test_type) => {
+                let runner = runners
+                    .iter_mut()
+                    .find(|runner| runner.test_type == test_type)
+                    .unwrap();
+                if !runner.done {
+                    runner.run_phase(phase, colos);
+                }
+            }
+        }
+    }
+    if latency_phases > 1 {
+        latency.update_avg();
+        report_latency(options, &latency);
+    }
+    (
+        latency,
+        runners.into_iter().map(PhaseRunner::finish).collect(),
+    )
+}
+
+pub fn run_latency_test(client: &Client, options: &SpeedTestCLIOptions) -> LatencyResult {
+    latency_test(client, options, &|_| {})
+}
+
+fn latency_test(
+    client: &Client,
+    options: &SpeedTestCLIOptions,
+    on_event: &dyn Fn(SpeedTestEvent),
+) -> LatencyResult {
+    // the latency phase has always taken one sample more than requested
+    let result = collect_latency(
+        client,
+        options,
+        options.nr_latency_tests + 1,
+        options.latency_concurrency,
+        on_event,
+    );
+    report_latency(options, &result);
+    result
+}
+
+/// Takes `count` latency samples with up to `concurrency` in flight, the progress bar is left
+/// on the current line
+fn collect_latency(
+    client: &Client,
+    options: &SpeedTestCLIOptions,
+    count: u32,
+    concurrency: u32,
+    on_event: &dyn Fn(SpeedTestEvent),
+) -> LatencyResult {
+    let nr_latency_tests = count;
+    let output_format = options.output_format;
+    let retry_policy = RetryPolicy::from_options(options);
+    let mut measurements: Vec<LatencySample> = Vec::new();
+    let mut retries = 0;
+    let mut failed = 0;
+    let pacer = Mutex::new(Pacer::default());
+    let next_sample = AtomicU32::new(0);
+    let start = Instant::now();
+    let (sender, receiver) = mpsc::channel();
+    thread::scope(|scope| {
+        // results are processed on this thread
+        for _ in 0..concurrency.max(1) {
+            let sender = sender.clone();
+            let (pacer, next_sample, retry_policy) = (&pacer, &next_sample, &retry_policy);
+            scope.spawn(move || {
+                while next_sample.fetch_add(1, Ordering::SeqCst) < nr_latency_tests
+                    && !stop_requested()
+                {
+                    let delay = pacer.lock().unwrap().delay();
+                    thread::sleep(delay);
+                    if sender
+                        .send(measure_latency(
+                            client,
+                            &options.endpoint,
+                            retry_policy,
+                            options.debug_timings.as_deref(),
+                        ))
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+
+        if output_format.is_human() {
+            print_progress("latency test", 0, nr_latency_tests, Duration::ZERO);
+        }
+        for (i, outcome) in receiver.iter().enumerate() {
+            match outcome {
+                Ok((sample, sample_retries)) => {
+                    on_event(SpeedTestEvent::Latency(sample));
+                    measurements.push(sample);
+                    retries += sample_retries;
+                    pacer.lock().unwrap().recover();
+                }
+                Err(e) => {
+                    warn(options, &format!("skipping latency sample: {e}"));
+                    failed += 1;
+                    slow_down(&mut pacer.lock().unwrap(), &e, options, on_event);
+                }
+            }
+            if output_format.is_human() {
+                print_progress(
+                    "latency test",
+                    (i as u32 + 1).min(nr_latency_tests),
+                    nr_latency_tests,
+                    start.elapsed(),
+                );
+            }
+        }
+        if output_format.is_human() {
+            finish_progress();
+        }
+    });
+    let mut result = LatencyResult {
+        measurements,
+        avg_latency: 0.0,
+        percentiles: None,
+        retries,
+        failed,
+    };
+    result.update_avg();
+    result
+}
+
+fn report_latency(options: &SpeedTestCLIOptions, result: &LatencyResult) {
+    let output_format = options.output_format;
+    let avg_latency = result.avg_latency;
+
+    if output_format.is_human() {
+        println!(
+            "Avg GET request latency {avg_latency:.2} ms (RTT excluding server processing time)"
+        );
+    }
+    if let Some(percentiles) = result.percentiles.filter(|_| options.verbose) {
         if output_format == OutputFormat::StdOut {
-            print_progress("latency test", i, nr_latency_tests);
+            println!("Latency percentiles");
+            for (label, latency_ms) in percentiles.rows() {
+                println!("{label:>8}{latency_ms:>10.2} ms");
+            }
         }
-        let latency = test_latency(client);
-        measurements.push(latency);
     }
-    let avg_latency = measurements.iter().sum::<f64>() / measurements.len() as f64;
+    if result.suspect_samples() > 0 {
+        warn(
+            options,
+            &format!(
+                "{} latency sample(s) reported a server time above the measured round trip and were excluded",
+                result.suspect_samples()
+            ),
+        );
+    }
+    if output_format.is_human() {
+        println!();
+    }
+}
 
-    if output_format == OutputFormat::StdOut {
+/// Long transfers after the regular test, a failure only skips the comparison
+fn sustained_test(
+    client: &Client,
+    options: &SpeedTestCLIOptions,
+    duration: Duration,
+) -> Vec<Sustained> {
+    if options.output_format.is_human() {
+        println!();
+    }
+    run_sustained(client, options, duration).unwrap_or_else(|e| {
+        warn(
+            options,
+            &format!("skipping the sustained transfer test: {e}"),
+        );
+        Vec::new()
+    })
+}
+
+/// Pings the test server to show how much of the HTTP latency is protocol overhead
+fn icmp_test(options: &SpeedTestCLIOptions, latency: &LatencyResult) -> Option<IcmpResult> {
+    let outcome =
+        server_address(options).and_then(|target| icmp::ping(target, options.nr_latency_tests));
+    let icmp = match outcome {
+        Ok(icmp) => icmp,
+        Err(e) => {
+            warn(options, &format!("skipping the ICMP comparison: {e}"));
+            return None;
+        }
+    };
+    if options.output_format.is_human() {
+        print!(
+            "Avg ICMP echo RTT {:.2} ms to {} ({} lost)",
+            icmp.avg_ms, icmp.target, icmp.lost
+        );
+        if latency.avg_latency.is_finite() {
+            print!(", HTTP adds {:.2} ms", latency.avg_latency - icmp.avg_ms);
+        }
+        println!("\n");
+    }
+    Some(icmp)
+}
+
+/// UDP latency and jitter, which HTTP over TCP hides behind retransmissions
+fn stun_test(options: &SpeedTestCLIOptions) -> Option<StunResult> {
+    let stun = match run_stun_test(&options.stun_server, options.nr_latency_tests, options.ipv6) {
+        Ok(stun) => stun,
+        Err(e) => {
+            warn(options, &format!("skipping the UDP latency test: {e}"));
+            return None;
+        }
+    };
+    if options.output_format.is_human() {
         println!(
-            "\nAvg GET request latency {avg_latency:.2} ms (RTT excluding server processing time)\n"
+            "Avg UDP (STUN) RTT {:.2} ms to {}, jitter {}, {:.1}% lost\n",
+            stun.avg_ms,
+            stun.server,
+            stun.jitter_ms
+                .map_or("-".to_string(), |jitter| format!("{jitter:.2} ms")),
+            stun.loss_percent()
         );
     }
-    (measurements, avg_latency)
+    Some(stun)
 }
 
-pub fn test_latency(client: &Client) -> f64 {
-    let url = &format!("{}/{}{}", BASE_URL, DOWNLOAD_URL, 0);
-    let req_builder = client.get(url);
+/// Traces the route to the test server, the hops are only listed in verbose output
+fn trace_test(options: &SpeedTestCLIOptions) -> Option<PathTrace> {
+    let mut path = match server_address(options).and_then(trace::trace_path) {
+        Ok(path) => path,
+        Err(e) => {
+            warn(options, &format!("skipping the path trace: {e}"));
+            return None;
+        }
+    };
+    if options.anonymize {
+        path.anonymize();
+    }
+    if options.output_format.is_human() {
+        if options.verbose {
+            println!("\n{path}");
+        } else {
+            println!(
+                "\nPath to {}: {} hop(s){}",
+                path.target,
+                path.hops.len(),
+                if path.reached { "" } else { ", not reached" }
+            );
+        }
+    }
+    Some(path)
+}
 
-    let start = Instant::now();
-    let response = req_builder.send().expect("failed to get response");
-    let _status_code = response.status();
-    let duration = start.elapsed().as_secs_f64() * 1_000.0;
+/// Address of the test server in the IP family the test runs over
+fn server_address(options: &SpeedTestCLIOptions) -> Result<IpAddr, String> {
+    if let Some(address) = options.resolve {
+        return Ok(address);
+    }
+    let url = reqwest::Url::parse(&options.endpoint.download_url(0)).map_err(|e| e.to_string())?;
+    let host = url.host_str().ok_or("the test URL has no host")?;
+    let mut addresses: Vec<IpAddr> = (host, 0)
+        .to_socket_addrs()
+        .map_err(|e| format!("failed to resolve {host}: {e}"))?
+        .map(|address| address.ip())
+        .collect();
+    // like the test requests, IPv4 is preferred unless IPv6 was asked for
+    addresses.sort_by_key(|address| address.is_ipv4() == options.ipv6);
+    addresses
+        .into_iter()
+        .find(|address| !options.ipv4 || address.is_ipv4())
+        .ok_or_else(|| format!("no suitable address for {host}"))
+}
 
-    let re = Regex::new(r"cfRequestDuration;dur=([\d.]+)").unwrap();
-    let cf_req_duration: f64 = re
-        .captures(
-            response
-                .headers()
-                .get("Server-Timing")
-                .expect("No Server-Timing in response header")
-                .to_str()
-                .unwrap(),
+/// HTTP client for the test requests, honoring --no-keepalive, --resolve and --ipv4/--ipv6
+pub fn build_client(options: &SpeedTestCLIOptions) -> Client {
+    // tls info exposes the peer certificate for the connection check
+    let mut builder = Client::builder().tls_info(true);
+    if options.no_keepalive {
+        // without idle connections in the pool every request needs a new TCP and TLS handshake
+        builder = builder.pool_max_idle_per_host(0);
+    }
+    if let (Some(address), Some(host)) = (options.resolve, test_host(options)) {
+        // the port is ignored, requests keep using the one of the URL
+        builder = builder.resolve(&host, SocketAddr::new(address, 0));
+    }
+    let client;
+    if options.ipv4 {
+        client = builder
+            .local_address("0.0.0.0".parse::<IpAddr>().unwrap())
+            .build();
+    } else if options.ipv6 {
+        client = builder
+            .local_address("::1".parse::<IpAddr>().unwrap())
+            .build();
+    } else {
+        client = builder.build();
+    }
+    client.expect("Failed to initialize reqwest client")
+}
+
+/// Host of the test server, custom URLs take precedence over the backend
+pub fn test_host(options: &SpeedTestCLIOptions) -> Option<String> {
+    let endpoint = Endpoint::custom(options).unwrap_or_else(|| options.endpoint.clone());
+    let url = reqwest::Url::parse(&endpoint.download_url(0)).ok()?;
+    url.host_str().map(str::to_owned)
+}
+
+/// Time the system resolver took to look up the test server
+#[derive(Clone, Debug, Serialize)]
+pub struct DnsLookup {
+    pub host: String,
+    pub duration_ms: f64,
+    pub addresses: Vec<IpAddr>,
+}
+
+impl Display for DnsLookup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "DNS lookup of {} took {:.2} ms",
+            self.host, self.duration_ms
         )
-        .unwrap()
-        .get(1)
-        .unwrap()
-        .as_str()
-        .parse()
-        .unwrap();
-    let mut req_latency = duration - cf_req_duration;
-    if req_latency < 0.0 {
-        // TODO investigate negative latency values
-        req_latency = 0.0
     }
-    req_latency
+}
+
+/// Looks up the test server once up front so the lookup isn't hidden in the first request,
+/// `None` with --resolve or if the lookup fails
+fn dns_lookup(options: &SpeedTestCLIOptions) -> Option<DnsLookup> {
+    if options.resolve.is_some() {
+        return None;
+    }
+    let host = test_host(options)?;
+    let start = Instant::now();
+    let outcome = (host.as_str(), 0).to_socket_addrs();
+    let duration = start.elapsed();
+    let addresses = match outcome {
+        Ok(addresses) => addresses.map(|address| address.ip()).collect(),
+        Err(e) => {
+            warn(options, &format!("failed to resolve {host}: {e}"));
+            return None;
+        }
+    };
+    let duration_ms = duration.as_secs_f64() * 1_000.0;
+    if let Some(path) = &options.debug_timings {
+        let timing = RequestTiming {
+            timestamp: Utc::now(),
+            kind: "dns",
+            url: host.clone(),
+            bytes: 0,
+            status: None,
+            ttfb_ms: None,
+            body_ms: None,
+            total_ms: duration_ms,
+            retries: 0,
+            error: None,
+        };
+        timings::record(path, &timing);
+    }
+    Some(DnsLookup {
+        host,
+        duration_ms,
+        addresses,
+    })
+}
+
+/// Returns the latency sample and the number of retries it took to measure it
+pub fn test_latency(
+    client: &Client,
+    retry_policy: &RetryPolicy,
+) -> Result<(LatencySample, u32), SampleError> {
+    measure_latency(client, &Endpoint::Cloudflare, retry_policy, None)
+}
+
+pub(crate) fn measure_latency(
+    client: &Client,
+    endpoint: &Endpoint,
+    retry_policy: &RetryPolicy,
+    debug_timings: Option<&Path>,
+) -> Result<(LatencySample, u32), SampleError> {
+    let url = &endpoint.download_url(0);
+
+    let started = Instant::now();
+    let (outcome, retries) = retry_policy.run(|| {
+        let start = Instant::now();
+        let response = check_throttled(client.get(url).send()?)?;
+        Ok::<_, SampleError>((response, start.elapsed().as_secs_f64() * 1_000.0))
+    });
+    if let Some(path) = debug_timings {
+        let response = outcome.as_ref().ok();
+        timings::record(
+            path,
+            &RequestTiming {
+                timestamp: Utc::now(),
+                kind: "latency",
+                url: url.clone(),
+                bytes: 0,
+                status: response.map(|(response, _)| response.status().as_u16()),
+                ttfb_ms: response.map(|(_, duration)| *duration),
+                body_ms: None,
+                total_ms: started.elapsed().as_secs_f64() * 1_000.0,
+                retries,
+                error: outcome.as_ref().err().map(ToString::to_string),
+            },
+        );
+    }
+    let (response, duration) = outcome?;
+    if !endpoint.reports_server_timing() {
+        return Ok((LatencySample::new(duration, 0.0), retries));
+    }
+
+    let re = Regex::new(r"cfRequestDuration;dur=([\d.]+)").unwrap();
+    let cf_req_duration: f64 = response
+        .headers()
+        .get("Server-Timing")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| re.captures(value))
+        .and_then(|captures| captures.get(1))
+        .and_then(|duration| duration.as_str().parse().ok())
+        .ok_or_else(|| {
+            SampleError::InvalidResponse("no valid Server-Timing in response header".to_string())
+        })?;
+    Ok((LatencySample::new(duration, cf_req_duration), retries))
 }
 
 const TIME_THRESHOLD: Duration = Duration::from_secs(5);
 
 pub fn run_tests(
     client: &Client,
-    test_fn: fn(&Client, usize, OutputFormat) -> f64,
+    test_fn: TestFn,
     test_type: TestType,
     payload_sizes: Vec<usize>,
-    nr_tests: u32,
-    output_format: OutputFormat,
-    disable_dynamic_max_payload_size: bool,
-) -> Vec<Measurement> {
-    let mut measurements: Vec<Measurement> = Vec::new();
+    options: &SpeedTestCLIOptions,
+    colos: &mut ColoTracker,
+    on_event: &dyn Fn(SpeedTestEvent),
+) -> TestTypeResult {
+    let mut runner = PhaseRunner::new(client, test_fn, test_type, options, on_event);
     for payload_size in payload_sizes {
+        if runner.done {
+            break;
+        }
+        runner.run_phase(
+            &Phase::transfer(test_type, payload_size, options.nr_tests),
+            colos,
+        );
+    }
+    runner.finish()
+}
+
+type TestFn = fn(&Client, usize, &SpeedTestCLIOptions) -> Result<Measurement, SampleError>;
+
+/// Takes the samples of one test type a phase at a time, so the phases of download and
+/// upload can be interleaved by the test plan
+struct PhaseRunner<'a> {
+    client: &'a Client,
+    test_fn: TestFn,
+    test_type: TestType,
+    options: &'a SpeedTestCLIOptions,
+    on_event: &'a dyn Fn(SpeedTestEvent),
+    measurements: Vec<Measurement>,
+    failed: u32,
+    consecutive_failures: u32,
+    aborted: Option<PhaseError>,
+    resource_usage: Vec<PhaseUsage>,
+    loaded_latency: Vec<f64>,
+    pacer: Pacer,
+    /// Set once no larger payload size should be tested
+    done: bool,
+}
+
+impl<'a> PhaseRunner<'a> {
+    fn new(
+        client: &'a Client,
+        test_fn: TestFn,
+        test_type: TestType,
+        options: &'a SpeedTestCLIOptions,
+        on_event: &'a dyn Fn(SpeedTestEvent),
+    ) -> Self {
+        Self {
+            client,
+            test_fn,
+            test_type,
+            options,
+            on_event,
+            measurements: Vec::new(),
+            failed: 0,
+            consecutive_failures: 0,
+            aborted: None,
+            resource_usage: Vec::new(),
+            loaded_latency: Vec::new(),
+            pacer: Pacer::default(),
+            done: false,
+        }
+    }
+
+    fn run_phase(&mut self, phase: &Phase, colos: &mut ColoTracker) {
+        let probe = self
+            .options
+            .bufferbloat
+            .then(|| LatencyProbe::start(self.client, &self.options.endpoint));
+        self.take_samples(phase, colos);
+        if let Some(probe) = probe {
+            self.loaded_latency.extend(probe.finish());
+        }
+    }
+
+    fn take_samples(&mut self, phase: &Phase, colos: &mut ColoTracker) {
+        let (test_type, options, on_event) = (self.test_type, self.options, self.on_event);
+        let (payload_size, nr_tests) = (phase.payload_size, phase.repetitions);
+        let output_format = options.output_format;
+        if stop_requested() {
+            self.done = true;
+            return;
+        }
         log::debug!("running tests for payload_size {payload_size}");
+        on_event(SpeedTestEvent::PhaseStarted {
+            test_type,
+            payload_size,
+        });
         let start = Instant::now();
+        let usage_start = ResourceSnapshot::now();
         for i in 0..nr_tests {
-            if output_format == OutputFormat::StdOut {
+            if stop_requested() {
+                self.abort(payload_size, "interrupted".to_string());
+                return;
+            }
+            if let Some(budget) = options
+                .per_size_timeout
+                .filter(|budget| start.elapsed() >= *budget)
+            {
+                warn(
+                    options,
+                    &format!(
+                        "{test_type:?} {} exceeded its time budget of {}, skipping {} remaining sample(s)",
+                        format_bytes(payload_size),
+                        humantime::format_duration(budget),
+                        nr_tests - i
+                    ),
+                );
+                break;
+            }
+            if output_format.is_human() {
                 print_progress(
                     &format!("{:?} {:<5}", test_type, format_bytes(payload_size)),
                     i,
                     nr_tests,
+                    start.elapsed(),
                 );
             }
-            let mbit = test_fn(client, payload_size, output_format);
-            measurements.push(Measurement {
-                test_type,
-                payload_size,
-                mbit,
-            });
+            if !self.pacer.wait() {
+                self.abort(payload_size, "interrupted".to_string());
+                return;
+            }
+            let outcome = if phase.concurrency > 1 {
+                run_concurrent(
+                    self.client,
+                    self.test_fn,
+                    payload_size,
+                    phase.concurrency,
+                    options,
+                )
+            } else {
+                (self.test_fn)(self.client, payload_size, options)
+            };
+            match outcome {
+                Ok(measurement) => {
+                    if !measurement.is_valid() {
+                        warn(
+                            options,
+                            &format!(
+                                "{test_type:?} sample answered with status {}, excluding it from stats",
+                                measurement.status
+                            ),
+                        );
+                    }
+                    on_event(SpeedTestEvent::Measurement(measurement.clone()));
+                    let colo_change = colos.observe(&measurement).cloned();
+                    self.measurements.push(measurement);
+                    self.pacer.recover();
+                    self.consecutive_failures = 0;
+                    if let Some(change) = colo_change {
+                        let error = format!(
+                            "Cloudflare colo changed from {} to {} during the run",
+                            change.from, change.to
+                        );
+                        warn(options, &error);
+                        if options.strict_colo {
+                            self.abort(payload_size, error);
+                            return;
+                        }
+                    }
+                }
+                Err(SampleError::Interrupted) => {
+                    self.abort(payload_size, "interrupted".to_string());
+                    return;
+                }
+                Err(e) => {
+                    warn(options, &format!("skipping {test_type:?} sample: {e}"));
+                    self.failed += 1;
+                    slow_down(&mut self.pacer, &e, options, on_event);
+                    if matches!(e, SampleError::Request(_) | SampleError::Body(_)) {
+                        self.consecutive_failures += 1;
+                    }
+                    if self.consecutive_failures >= MAX_CONSECUTIVE_FAILURES {
+                        warn(
+                            options,
+                            &format!(
+                                "aborting {test_type:?} tests after {} failed requests",
+                                self.consecutive_failures
+                            ),
+                        );
+                        self.abort(payload_size, e.to_string());
+                        return;
+                    }
+                }
+            }
+            if options.adaptive
+                && has_converged(&self.measurements, test_type, payload_size, options)
+            {
+                log::info!(
+                    "{test_type:?} {} converged after {} samples",
+                    format_bytes(payload_size),
+                    i + 1
+                );
+                break;
+            }
         }
-        if output_format == OutputFormat::StdOut {
+        if output_format.is_human() {
             print_progress(
                 &format!("{:?} {:<5}", test_type, format_bytes(payload_size)),
                 nr_tests,
                 nr_tests,
+                start.elapsed(),
             );
-            println!()
+            if let Some(summary) = phase_summary(&self.measurements, test_type, payload_size) {
+                print_progress_detail(&summary);
+            }
+            finish_progress();
         }
         let duration = start.elapsed();
+        if let Some(usage) = usage_start.and_then(|u| u.usage_since(test_type, payload_size)) {
+            if usage.is_cpu_bound() {
+                warn(
+                    options,
+                    &format!(
+                        "cfspeedtest used {:.0}% CPU during {test_type:?} {}, the client machine is likely the bottleneck",
+                        usage.cpu_percent,
+                        format_bytes(payload_size)
+                    ),
+                );
+            }
+            self.resource_usage.push(usage);
+        }
 
         // only check TIME_THRESHOLD if dynamic max payload sizing is not disabled
-        if !disable_dynamic_max_payload_size && duration > TIME_THRESHOLD {
+        if !options.disable_dynamic_max_payload_size && duration > TIME_THRESHOLD {
             log::info!("Exceeded threshold");
-            break;
+            self.done = true;
+        }
+    }
+
+    /// Ends the phase, no further payload sizes are tested
+    fn abort(&mut self, payload_size: usize, error: String) {
+        self.aborted = Some(PhaseError {
+            test_type: self.test_type,
+            payload_size,
+            error,
+        });
+        self.done = true;
+    }
+
+    fn finish(self) -> TestTypeResult {
+        TestTypeResult {
+            test_type: self.test_type,
+            measurements: self.measurements,
+            failed: self.failed,
+            aborted: self.aborted,
+            resource_usage: self.resource_usage,
+            loaded_latency: self.loaded_latency,
         }
     }
-    measurements
 }
 
-pub fn test_upload(client: &Client, payload_size_bytes: usize, output_format: OutputFormat) -> f64 {
-    let url = &format!("{BASE_URL}/{UPLOAD_URL}");
-    let payload: Vec<u8> = vec![1; payload_size_bytes];
-    let req_builder = client.post(url).body(payload);
-    let (status_code, mbits, duration) = {
-        let start = Instant::now();
-        let response = req_builder.send().expect("failed to get response");
-        let status_code = response.status();
-        let duration = start.elapsed();
-        let mbits = (payload_size_bytes as f64 * 8.0 / 1_000_000.0) / duration.as_secs_f64();
-        (status_code, mbits, duration)
-    };
-    if output_format == OutputFormat::StdOut {
-        print_current_speed(mbits, duration, status_code, payload_size_bytes);
+/// Runs `concurrency` requests at once and combines them into one sample of their total
+/// throughput. The sample fails if any of the requests failed.
+fn run_concurrent(
+    client: &Client,
+    test_fn: TestFn,
+    payload_size: usize,
+    concurrency: u32,
+    options: &SpeedTestCLIOptions,
+) -> Result<Measurement, SampleError> {
+    // the speed of the single streams isn't printed, only the combined one
+    let mut stream_options = options.clone();
+    stream_options.output_format = OutputFormat::None;
+    let stream_options = &stream_options;
+    let start = Instant::now();
+    let outcomes: Vec<_> = thread::scope(|scope| {
+        let streams: Vec<_> = (0..concurrency)
+            .map(|_| scope.spawn(move || test_fn(client, payload_size, stream_options)))
+            .collect();
+        streams
+            .into_iter()
+            .map(|stream| stream.join().unwrap())
+            .collect()
+    });
+    let streams = outcomes.into_iter().collect::<Result<Vec<_>, _>>()?;
+    let mut combined = streams
+        .iter()
+        .find(|measurement| !measurement.is_valid())
+        .unwrap_or(&streams[0])
+        .clone();
+    combined.mbit = streams.iter().map(|measurement| measurement.mbit).sum();
+    combined.retries = streams.iter().map(|measurement| measurement.retries).sum();
+    if options.output_format.is_human() {
+        print_current_speed(
+            combined.mbit,
+            start.elapsed(),
+            StatusCode::from_u16(combined.status).unwrap_or_default(),
+            payload_size,
+        );
+    }
+    Ok(combined)
+}
+
+pub fn test_upload(
+    client: &Client,
+    payload_size_bytes: usize,
+    options: &SpeedTestCLIOptions,
+) -> Result<Measurement, SampleError> {
+    let url = &options.endpoint.upload_url(payload_size_bytes);
+    let started = Instant::now();
+    let (outcome, retries) = RetryPolicy::from_options(options).run(|| {
+        let write_started: Arc<OnceLock<(Instant, usize)>> = Arc::new(OnceLock::new());
+        let series =
+            shared_series(options.include_raw || payload_size_bytes >= SLOW_START_MIN_PAYLOAD);
+        let payload = Body::sized(
+            Metered::new(
+                Throttled::new(
+                    Interruptible(UploadPayload::new(
+                        payload_size_bytes,
+                        write_started.clone(),
+                    )),
+                    options.max_rate,
+                ),
+                series.clone(),
+            ),
+            payload_size_bytes as u64,
+        );
+        let req_builder = with_budget(client.post(url).body(payload), options);
+        let request_start = Instant::now();
+        let response = check_throttled(req_builder.send()?)?;
+        // the clock starts once the body is being written so connection setup doesn't count,
+        // without that point nothing is counted and the sample is dropped below
+        let (start, sent) = write_started
+            .get()
+            .copied()
+            .unwrap_or((request_start, payload_size_bytes));
+        Ok::<_, SampleError>(Transfer {
+            status_code: response.status(),
+            duration: start.elapsed(),
+            bytes: payload_size_bytes - sent,
+            ttfb: start - request_start,
+            colo: response_colo(&response),
+            throughput: take_points(&series),
+        })
+    });
+    if let Some(path) = &options.debug_timings {
+        let timing = transfer_timing(
+            &outcome,
+            "upload",
+            url,
+            payload_size_bytes,
+            retries,
+            started,
+        );
+        timings::record(path, &timing);
+    }
+    if outcome.is_err() && stop_requested() {
+        return Err(SampleError::Interrupted);
+    }
+    let transfer = outcome?;
+    if transfer.bytes == 0 {
+        return Err(SampleError::Unmeasurable(payload_size_bytes));
+    }
+    Ok(transfer.into_measurement(TestType::Upload, payload_size_bytes, retries, options))
+}
+
+/// Fails reads once a stop was requested, so a running transfer ends right away instead of
+/// moving the rest of a large payload
+struct Interruptible<R>(R);
+
+impl<R: Read> Read for Interruptible<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if stop_requested() {
+            return Err(io::Error::other("interrupted"));
+        }
+        self.0.read(buf)
+    }
+}
+
+/// Upload body that generates its bytes while being sent so memory use stays flat for large payloads
+struct UploadPayload {
+    remaining: usize,
+    first_chunk: Option<usize>,
+    /// Time the connection took the first chunk and its size. reqwest reads the first chunk
+    /// before connecting, the second read waits until the first one was taken for sending.
+    started: Arc<OnceLock<(Instant, usize)>>,
+}
+
+impl UploadPayload {
+    fn new(size: usize, started: Arc<OnceLock<(Instant, usize)>>) -> Self {
+        Self {
+            remaining: size,
+            first_chunk: None,
+            started,
+        }
+    }
+}
+
+impl Read for UploadPayload {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.first_chunk {
+            Some(first_chunk) => {
+                self.started.get_or_init(|| (Instant::now(), first_chunk));
+            }
+            None => self.first_chunk = Some(buf.len().min(self.remaining)),
+        }
+        let len = buf.len().min(self.remaining);
+        buf[..len].fill(1);
+        self.remaining -= len;
+        Ok(len)
     }
-    mbits
 }
 
 pub fn test_download(
     client: &Client,
     payload_size_bytes: usize,
-    output_format: OutputFormat,
-) -> f64 {
-    let url = &format!("{BASE_URL}/{DOWNLOAD_URL}{payload_size_bytes}");
-    let req_builder = client.get(url);
-    let (status_code, mbits, duration) = {
-        let response = req_builder.send().expect("failed to get response");
+    options: &SpeedTestCLIOptions,
+) -> Result<Measurement, SampleError> {
+    let url = &options.endpoint.download_url(payload_size_bytes);
+    let started = Instant::now();
+    let (outcome, retries) = RetryPolicy::from_options(options).run(|| {
+        let request_start = Instant::now();
+        let response = check_throttled(with_budget(client.get(url), options).send()?)?;
         let status_code = response.status();
+        let colo = response_colo(&response);
+        let series =
+            shared_series(options.include_raw || payload_size_bytes >= SLOW_START_MIN_PAYLOAD);
+        let mut response = Metered::new(
+            Throttled::new(Interruptible(response), options.max_rate),
+            series.clone(),
+        );
+        // the clock starts with the first body chunk so TTFB doesn't count towards throughput
+        let mut first_chunk = [0u8; 16 * 1024];
+        if response.read(&mut first_chunk).map_err(SampleError::Body)? == 0 {
+            return Err(SampleError::InvalidResponse("empty body".to_string()));
+        }
         let start = Instant::now();
-        let _res_bytes = response.bytes();
-        let duration = start.elapsed();
-        let mbits = (payload_size_bytes as f64 * 8.0 / 1_000_000.0) / duration.as_secs_f64();
-        (status_code, mbits, duration)
-    };
-    if output_format == OutputFormat::StdOut {
-        print_current_speed(mbits, duration, status_code, payload_size_bytes);
+        // the body is discarded while reading so large payloads are never buffered in memory
+        let bytes = io::copy(&mut response, &mut io::sink()).map_err(SampleError::Body)? as usize;
+        Ok::<_, SampleError>(Transfer {
+            status_code,
+            duration: start.elapsed(),
+            bytes,
+            ttfb: start - request_start,
+            colo,
+            throughput: take_points(&series),
+        })
+    });
+    if let Some(path) = &options.debug_timings {
+        let timing = transfer_timing(
+            &outcome,
+            "download",
+            url,
+            payload_size_bytes,
+            retries,
+            started,
+        );
+        timings::record(path, &timing);
+    }
+    if outcome.is_err() && stop_requested() {
+        return Err(SampleError::Interrupted);
+    }
+    let transfer = outcome?;
+    // the whole body arrived with the first chunk, timing it would have to include the TTFB
+    if transfer.bytes == 0 {
+        return Err(SampleError::Unmeasurable(payload_size_bytes));
+    }
+    Ok(transfer.into_measurement(TestType::Download, payload_size_bytes, retries, options))
+}
+
+/// Timing of a single upload or download request
+struct Transfer {
+    status_code: StatusCode,
+    /// Time spent transferring `bytes`
+    duration: Duration,
+    bytes: usize,
+    /// Time until the first byte of the body was transferred
+    ttfb: Duration,
+    colo: Option<String>,
+    throughput: Vec<ThroughputPoint>,
+}
+
+impl Transfer {
+    fn into_measurement(
+        self,
+        test_type: TestType,
+        payload_size: usize,
+        retries: u32,
+        options: &SpeedTestCLIOptions,
+    ) -> Measurement {
+        let mbits = (self.bytes as f64 * 8.0 / 1_000_000.0) / self.duration.as_secs_f64();
+        if options.output_format.is_human() {
+            print_current_speed(mbits, self.duration, self.status_code, payload_size);
+        }
+        Measurement {
+            test_type,
+            payload_size,
+            mbit: mbits,
+            retries,
+            status: self.status_code.as_u16(),
+            colo: self.colo,
+            ttfb_ms: self.ttfb.as_secs_f64() * 1_000.0,
+            ramp_ms: time_to_peak(&self.throughput),
+            // large transfers always record a series for the ramp, it is only kept on request
+            throughput: if options.include_raw {
+                self.throughput
+            } else {
+                Vec::new()
+            },
+        }
+    }
+}
+
+/// Timing of a transfer for --debug-timings, `started` is taken before the first attempt
+fn transfer_timing(
+    outcome: &Result<Transfer, SampleError>,
+    kind: &'static str,
+    url: &str,
+    bytes: usize,
+    retries: u32,
+    started: Instant,
+) -> RequestTiming {
+    let transfer = outcome.as_ref().ok();
+    RequestTiming {
+        timestamp: Utc::now(),
+        kind,
+        url: url.to_string(),
+        bytes,
+        status: transfer.map(|t| t.status_code.as_u16()),
+        ttfb_ms: transfer.map(|t| t.ttfb.as_secs_f64() * 1_000.0),
+        body_ms: transfer.map(|t| t.duration.as_secs_f64() * 1_000.0),
+        total_ms: started.elapsed().as_secs_f64() * 1_000.0,
+        retries,
+        error: outcome.as_ref().err().map(ToString::to_string),
+    }
+}
+
+/// Cloudflare colo (data center) that served the response
+fn response_colo(response: &Response) -> Option<String> {
+    response
+        .headers()
+        .get("cf-meta-colo")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned)
+}
+
+/// Average, min and max of a finished payload size, shown before the summary statistics
+fn phase_summary(
+    measurements: &[Measurement],
+    test_type: TestType,
+    payload_size: usize,
+) -> Option<String> {
+    let mbits: Vec<f64> = measurements
+        .iter()
+        .filter(|m| m.test_type == test_type && m.payload_size == payload_size && m.is_valid())
+        .map(|m| m.mbit)
+        .collect();
+    if mbits.is_empty() {
+        return None;
     }
-    mbits
+    let min = mbits.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = mbits.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let avg = mbits.iter().sum::<f64>() / mbits.len() as f64;
+    Some(format!("avg {avg:.2} mbit/s, min {min:.2}, max {max:.2}"))
 }
 
 fn print_current_speed(
@@ -275,40 +1998,87 @@ fn print_current_speed(
     status_code: StatusCode,
     payload_size_bytes: usize,
 ) {
-    print!(
-        "  {:>6.2} mbit/s | {:>5} in {:>4}ms -> status: {}  ",
+    print_progress_detail(&format!(
+        "{:>6.2} mbit/s | {:>5} in {:>4}ms -> status: {}",
         mbits,
         format_bytes(payload_size_bytes),
         duration.as_millis(),
         status_code
+    ));
+}
+
+const PREWARM_PAYLOAD_SIZE: usize = 1_000_000;
+
+/// Exercises the pooled connection with one download and upload so the first samples don't pay for
+/// the TLS handshake and TCP slow start
+fn prewarm(client: &Client, options: &SpeedTestCLIOptions) {
+    if options.output_format.is_human() {
+        println!("Warming up connection");
+    }
+    let quiet = SpeedTestCLIOptions {
+        output_format: OutputFormat::None,
+        ..options.clone()
+    };
+    let warmup = test_download(client, PREWARM_PAYLOAD_SIZE, &quiet)
+        .and_then(|_| test_upload(client, PREWARM_PAYLOAD_SIZE, &quiet));
+    if let Err(e) = warmup {
+        warn(options, &format!("connection warmup failed: {e}"));
+    }
+}
+
+/// Returns whether the confidence interval of the mean for `payload_size` is within the adaptive target
+fn has_converged(
+    measurements: &[Measurement],
+    test_type: TestType,
+    payload_size: usize,
+    options: &SpeedTestCLIOptions,
+) -> bool {
+    let mbits: Vec<f64> = measurements
+        .iter()
+        .filter(|m| m.test_type == test_type && m.payload_size == payload_size && m.is_valid())
+        .map(|m| m.mbit)
+        .collect();
+    let mean = mbits.iter().sum::<f64>() / mbits.len() as f64;
+    confidence_interval(&mbits).is_some_and(|width| width <= mean * options.adaptive_target / 100.0)
+}
+
+/// Limits a single request to the per payload size time budget
+fn with_budget(request: RequestBuilder, options: &SpeedTestCLIOptions) -> RequestBuilder {
+    match options.per_size_timeout {
+        Some(budget) => request.timeout(budget),
+        None => request,
+    }
+}
+
+/// Increases the delay between samples if `error` indicates the server is throttling us
+fn slow_down(
+    pacer: &mut Pacer,
+    error: &SampleError,
+    options: &SpeedTestCLIOptions,
+    on_event: &dyn Fn(SpeedTestEvent),
+) {
+    let SampleError::Throttled(status) = error else {
+        return;
+    };
+    let delay = pacer.slow_down();
+    warn(
+        options,
+        &format!(
+            "server is throttling requests, waiting {}ms between samples",
+            delay.as_millis()
+        ),
     );
+    on_event(SpeedTestEvent::Throttled {
+        status: status.as_u16(),
+        delay_ms: delay.as_millis() as u64,
+    });
 }
 
-pub fn fetch_metadata(client: &Client) -> Metadata {
-    let url = &format!("{}/{}{}", BASE_URL, DOWNLOAD_URL, 0);
-    let headers = client
-        .get(url)
-        .send()
-        .expect("failed to get response")
-        .headers()
-        .to_owned();
-    Metadata {
-        city: extract_header_value(&headers, "cf-meta-city", "City N/A"),
-        country: extract_header_value(&headers, "cf-meta-country", "Country N/A"),
-        ip: extract_header_value(&headers, "cf-meta-ip", "IP N/A"),
-        asn: extract_header_value(&headers, "cf-meta-asn", "ASN N/A"),
-        colo: extract_header_value(&headers, "cf-meta-colo", "Colo N/A"),
-    }
-}
-
-fn extract_header_value(
-    headers: &reqwest::header::HeaderMap,
-    header_name: &str,
-    na_value: &str,
-) -> String {
-    headers
-        .get(header_name)
-        .and_then(|value| value.to_str().ok())
-        .unwrap_or(na_value)
-        .to_owned()
+/// Reports a recoverable error without interrupting the run
+fn warn(options: &SpeedTestCLIOptions, message: &str) {
+    if options.output_format.is_human() {
+        print_above_progress(&format!("Warning: {message}"));
+    } else {
+        log::warn!("{message}");
+    }
 }
@@ -0,0 +1,121 @@
+use crate::measurements::calc_jitter;
+use serde::Serialize;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_SERVER: &str = "stun.cloudflare.com:3478";
+
+const TIMEOUT: Duration = Duration::from_secs(1);
+/// Requests are spaced out like the packets of a real-time stream
+const INTERVAL: Duration = Duration::from_millis(20);
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+
+/// Round trips of STUN binding requests, a UDP view of latency and jitter
+#[derive(Clone, Debug, Serialize)]
+pub struct StunResult {
+    pub server: SocketAddr,
+    pub rtts_ms: Vec<f64>,
+    pub avg_ms: f64,
+    pub jitter_ms: Option<f64>,
+    /// Number of requests without an answer in time
+    pub lost: u32,
+}
+
+impl StunResult {
+    pub fn loss_percent(&self) -> f64 {
+        let sent = self.rtts_ms.len() as f64 + self.lost as f64;
+        self.lost as f64 / sent * 100.0
+    }
+}
+
+/// Sends `count` binding requests to `server` and measures how long each answer takes
+pub fn run_stun_test(server: &str, count: u32, ipv6: bool) -> Result<StunResult, String> {
+    let server = server
+        .to_socket_addrs()
+        .map_err(|e| format!("failed to resolve {server}: {e}"))?
+        .find(|address| address.is_ipv6() == ipv6)
+        .ok_or_else(|| format!("no IPv{} address for {server}", if ipv6 { 6 } else { 4 }))?;
+    let bind: SocketAddr = if ipv6 {
+        "[::]:0".parse().unwrap()
+    } else {
+        "0.0.0.0:0".parse().unwrap()
+    };
+    let socket = UdpSocket::bind(bind).map_err(|e| e.to_string())?;
+    socket.connect(server).map_err(|e| e.to_string())?;
+    socket
+        .set_read_timeout(Some(TIMEOUT))
+        .map_err(|e| e.to_string())?;
+
+    let mut rtts_ms = Vec::new();
+    let mut lost = 0;
+    for i in 0..count {
+        if i > 0 {
+            thread::sleep(INTERVAL);
+        }
+        match binding_request(&socket) {
+            Ok(Some(rtt)) => rtts_ms.push(rtt.as_secs_f64() * 1000.0),
+            Ok(None) => lost += 1,
+            Err(e) => return Err(format!("STUN request to {server} failed: {e}")),
+        }
+    }
+    if rtts_ms.is_empty() {
+        return Err(format!("no STUN answer from {server}"));
+    }
+    let avg_ms = rtts_ms.iter().sum::<f64>() / rtts_ms.len() as f64;
+    Ok(StunResult {
+        server,
+        jitter_ms: calc_jitter(&rtts_ms),
+        rtts_ms,
+        avg_ms,
+        lost,
+    })
+}
+
+/// Returns the round trip time, `None` if no answer arrived in time
+fn binding_request(socket: &UdpSocket) -> std::io::Result<Option<Duration>> {
+    let transaction_id = transaction_id();
+    let mut request = Vec::with_capacity(20);
+    request.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    // no attributes
+    request.extend_from_slice(&0u16.to_be_bytes());
+    request.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    request.extend_from_slice(&transaction_id);
+
+    let start = Instant::now();
+    socket.send(&request)?;
+    let mut response = [0u8; 512];
+    while start.elapsed() < TIMEOUT {
+        let len = match socket.recv(&mut response) {
+            Ok(len) => len,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Ok(None)
+            }
+            Err(e) => return Err(e),
+        };
+        // late answers to earlier requests are skipped
+        if len >= 20
+            && response[..2] == BINDING_SUCCESS.to_be_bytes()
+            && response[8..20] == transaction_id
+        {
+            return Ok(Some(start.elapsed()));
+        }
+    }
+    Ok(None)
+}
+
+fn transaction_id() -> [u8; 12] {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |t| t.as_nanos());
+    let mut id = [0u8; 12];
+    id.copy_from_slice(&nanos.to_be_bytes()[4..]);
+    id
+}
@@ -0,0 +1,205 @@
+use crate::progress::{finish_progress, print_progress};
+use crate::speedtest::{stop_requested, TestType};
+use crate::throttle::Throttled;
+use crate::throughput::{shared_series, take_points, Metered, SharedSeries, ThroughputPoint};
+use crate::SpeedTestCLIOptions;
+use reqwest::blocking::{Body, Client, RequestBuilder, Response};
+use serde::Serialize;
+use std::fmt::Display;
+use std::io::{self, Read};
+use std::time::{Duration, Instant};
+
+/// Requested per download, the transfer is cut off at the end of the test anyway
+const DOWNLOAD_PAYLOAD: usize = 1_000_000_000;
+/// Uploads are split into requests of about this length, sized by the rate of the previous one
+const UPLOAD_REQUEST: Duration = Duration::from_secs(1);
+const MIN_UPLOAD_PAYLOAD: usize = 1_000_000;
+const MAX_UPLOAD_PAYLOAD: usize = 100_000_000;
+/// Extra time for a request on top of the test duration before it times out
+const TIMEOUT_MARGIN: Duration = Duration::from_secs(10);
+/// A steady-state rate this much below the initial one is reported as throttling
+const THROTTLED_DECAY_PERCENT: f64 = 20.0;
+
+/// Initial and steady-state throughput of one long transfer
+#[derive(Clone, Debug, Serialize)]
+pub struct Sustained {
+    pub test_type: TestType,
+    pub duration_ms: f64,
+    /// Average of the first fifth of the transfer
+    pub initial_mbit: f64,
+    /// Average of the second half of the transfer
+    pub steady_mbit: f64,
+    /// How much lower the steady-state rate is than the initial one
+    pub decay_percent: f64,
+}
+
+impl Sustained {
+    /// `None` for series too short to compare the start with the end
+    fn analyze(test_type: TestType, points: &[ThroughputPoint]) -> Option<Self> {
+        let duration_ms = points.last()?.elapsed_ms;
+        if points.len() < 10 {
+            return None;
+        }
+        let average = |points: Vec<f64>| points.iter().sum::<f64>() / points.len() as f64;
+        let initial_mbit = average(
+            points
+                .iter()
+                .filter(|p| p.elapsed_ms <= duration_ms * 0.2)
+                .map(|p| p.mbit)
+                .collect(),
+        );
+        let steady_mbit = average(
+            points
+                .iter()
+                .filter(|p| p.elapsed_ms > duration_ms * 0.5)
+                .map(|p| p.mbit)
+                .collect(),
+        );
+        Some(Self {
+            test_type,
+            duration_ms,
+            initial_mbit,
+            steady_mbit,
+            decay_percent: ((initial_mbit - steady_mbit) / initial_mbit * 100.0).max(0.0),
+        })
+    }
+
+    /// Whether the rate dropped like it does with the burst buckets some ISPs use
+    pub fn throttled(&self) -> bool {
+        self.decay_percent >= THROTTLED_DECAY_PERCENT
+    }
+}
+
+impl Display for Sustained {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Sustained {} over {:.1}s: {:.2} mbit/s initially, {:.2} mbit/s steady (-{:.0}%), {}",
+            format!("{:?}", self.test_type).to_lowercase(),
+            self.duration_ms / 1_000.0,
+            self.initial_mbit,
+            self.steady_mbit,
+            self.decay_percent,
+            if self.throttled() {
+                "throttling suspected"
+            } else {
+                "no throttling"
+            }
+        )
+    }
+}
+
+/// Runs one continuous transfer per enabled direction for `duration`
+pub fn run_sustained(
+    client: &Client,
+    options: &SpeedTestCLIOptions,
+    duration: Duration,
+) -> Result<Vec<Sustained>, String> {
+    let mut results = Vec::new();
+    for (test_type, enabled) in [
+        (TestType::Download, options.should_download()),
+        (TestType::Upload, options.should_upload()),
+    ] {
+        if !enabled || stop_requested() {
+            continue;
+        }
+        let series = shared_series(true);
+        match test_type {
+            TestType::Download => download(client, options, duration, &series)?,
+            TestType::Upload => upload(client, options, duration, &series)?,
+        }
+        if options.output_format.is_human() {
+            finish_progress();
+        }
+        if let Some(sustained) = Sustained::analyze(test_type, &take_points(&series)) {
+            results.push(sustained);
+        }
+    }
+    Ok(results)
+}
+
+fn download(
+    client: &Client,
+    options: &SpeedTestCLIOptions,
+    duration: Duration,
+    series: &SharedSeries,
+) -> Result<(), String> {
+    let url = options.endpoint.download_url(DOWNLOAD_PAYLOAD);
+    let start = Instant::now();
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut shown_second = None;
+    // payloads that end early are requested again so the link stays busy
+    while start.elapsed() < duration && !stop_requested() {
+        let response = send(client.get(&url).timeout(duration + TIMEOUT_MARGIN))?;
+        let mut body = Metered::new(Throttled::new(response, options.max_rate), series.clone());
+        while start.elapsed() < duration && !stop_requested() {
+            let read = body.read(&mut buf).map_err(|e| e.to_string())?;
+            if read == 0 {
+                break;
+            }
+            // the progress is only redrawn once per second, reads are far more frequent
+            if shown_second != Some(start.elapsed().as_secs()) {
+                shown_second = Some(start.elapsed().as_secs());
+                progress(TestType::Download, start, duration, options);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn upload(
+    client: &Client,
+    options: &SpeedTestCLIOptions,
+    duration: Duration,
+    series: &SharedSeries,
+) -> Result<(), String> {
+    let start = Instant::now();
+    let mut payload_size = MIN_UPLOAD_PAYLOAD;
+    while start.elapsed() < duration && !stop_requested() {
+        let url = options.endpoint.upload_url(payload_size);
+        let body = Body::sized(
+            Metered::new(
+                Throttled::new(io::repeat(1).take(payload_size as u64), options.max_rate),
+                series.clone(),
+            ),
+            payload_size as u64,
+        );
+        let request_start = Instant::now();
+        send(
+            client
+                .post(&url)
+                .body(body)
+                .timeout(duration + TIMEOUT_MARGIN),
+        )?;
+        let bytes_per_second = payload_size as f64 / request_start.elapsed().as_secs_f64();
+        payload_size = ((bytes_per_second * UPLOAD_REQUEST.as_secs_f64()) as usize)
+            .clamp(MIN_UPLOAD_PAYLOAD, MAX_UPLOAD_PAYLOAD);
+        progress(TestType::Upload, start, duration, options);
+    }
+    Ok(())
+}
+
+fn send(request: RequestBuilder) -> Result<Response, String> {
+    let response = request.send().map_err(|e| e.to_string())?;
+    if response.status().is_success() {
+        Ok(response)
+    } else {
+        Err(format!("server responded with {}", response.status()))
+    }
+}
+
+fn progress(
+    test_type: TestType,
+    start: Instant,
+    duration: Duration,
+    options: &SpeedTestCLIOptions,
+) {
+    if options.output_format.is_human() {
+        print_progress(
+            &format!("Sustained {test_type:?}"),
+            (start.elapsed().as_secs() as u32).min(duration.as_secs() as u32),
+            duration.as_secs().max(1) as u32,
+            start.elapsed(),
+        );
+    }
+}
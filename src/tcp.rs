@@ -0,0 +1,180 @@
+use crate::measurements::{format_bytes, log_measurements, print_rows, Measurement};
+use crate::progress::{finish_progress, print_progress};
+use crate::speedtest::{PayloadSize, TestType};
+use crate::throttle::{throttle, Throttled};
+use crate::SpeedTestCLIOptions;
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    thread,
+    time::{Duration, Instant},
+};
+
+pub const DEFAULT_PORT: u16 = 9799;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// Every request is a 9 byte header, the mode followed by the payload size as a big endian u64.
+// For `D` the server sends the requested number of bytes, for `U` the client sends them and the
+// server answers with a single byte once everything arrived. A connection carries any number of
+// requests, so only the first sample pays for the TCP handshake.
+const DOWNLOAD: u8 = b'D';
+const UPLOAD: u8 = b'U';
+const ACK: u8 = b'A';
+
+/// Accepts raw TCP test clients on `addr` until the process is stopped
+pub fn run_tcp_server(addr: SocketAddr) -> Result<(), String> {
+    let listener =
+        TcpListener::bind(addr).map_err(|e| format!("failed to listen on {addr}: {e}"))?;
+    println!("Serving raw TCP tests on {addr}");
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                thread::spawn(move || {
+                    let peer = stream.peer_addr().ok();
+                    if let Err(e) = serve_client(stream) {
+                        log::warn!("TCP test client {peer:?} failed: {e}");
+                    }
+                });
+            }
+            Err(e) => log::warn!("failed to accept TCP test client: {e}"),
+        }
+    }
+    Ok(())
+}
+
+fn serve_client(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut header = [0u8; 9];
+    loop {
+        match stream.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        let size = u64::from_be_bytes(header[1..].try_into().unwrap());
+        match header[0] {
+            DOWNLOAD => io::copy(&mut io::repeat(0).take(size), &mut stream).map(|_| ())?,
+            UPLOAD => {
+                let received = io::copy(&mut (&mut stream).take(size), &mut io::sink())?;
+                if received < size {
+                    return Ok(());
+                }
+                stream.write_all(&[ACK])?;
+            }
+            other => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown request {other:#x}"),
+                ))
+            }
+        }
+    }
+}
+
+/// Measures download and upload throughput against a `tcp-server` at `server`
+pub fn run_tcp_test(server: &str, options: &SpeedTestCLIOptions) -> Result<(), String> {
+    let server = if server.contains(':') {
+        server.to_string()
+    } else {
+        format!("{server}:{DEFAULT_PORT}")
+    };
+    let mut stream =
+        TcpStream::connect(&server).map_err(|e| format!("failed to connect to {server}: {e}"))?;
+    stream.set_nodelay(true).map_err(|e| e.to_string())?;
+    if options.output_format.is_human() {
+        println!("Testing raw TCP throughput against {server}");
+    }
+
+    let payload_sizes = PayloadSize::sizes_from_max(options.max_payload_size.clone());
+    let mut test_types = Vec::new();
+    if options.should_download() {
+        test_types.push(TestType::Download);
+    }
+    if options.should_upload() {
+        test_types.push(TestType::Upload);
+    }
+    let mut measurements = Vec::new();
+    for test_type in test_types {
+        for &payload_size in &payload_sizes {
+            let name = format!("{test_type:?} {:<5}", format_bytes(payload_size));
+            let start = Instant::now();
+            for i in 0..options.nr_tests {
+                if options.output_format.is_human() {
+                    print_progress(&name, i, options.nr_tests, start.elapsed());
+                }
+                let duration = transfer(&mut stream, test_type, payload_size, options.max_rate)
+                    .map_err(|e| format!("{test_type:?} to {server} failed: {e}"))?;
+                measurements.push(Measurement {
+                    test_type,
+                    payload_size,
+                    mbit: (payload_size as f64 * 8.0 / 1_000_000.0) / duration.as_secs_f64(),
+                    retries: 0,
+                    status: 200,
+                    colo: None,
+                    ttfb_ms: 0.0,
+                    throughput: Vec::new(),
+                    ramp_ms: None,
+                });
+            }
+            if options.output_format.is_human() {
+                print_progress(&name, options.nr_tests, options.nr_tests, start.elapsed());
+                finish_progress();
+            }
+        }
+    }
+    let rows = log_measurements(
+        &measurements,
+        &[],
+        &[],
+        payload_sizes,
+        options.verbose,
+        options.output_format,
+    );
+    print_rows(&rows, "measurements", options.output_format);
+    Ok(())
+}
+
+/// Runs a single request and returns the time spent moving the payload
+fn transfer(
+    stream: &mut TcpStream,
+    test_type: TestType,
+    size: usize,
+    max_rate: Option<f64>,
+) -> io::Result<Duration> {
+    let mode = match test_type {
+        TestType::Download => DOWNLOAD,
+        TestType::Upload => UPLOAD,
+    };
+    let mut header = [mode; 9];
+    header[1..].copy_from_slice(&(size as u64).to_be_bytes());
+    stream.write_all(&header)?;
+    match test_type {
+        TestType::Download => {
+            // the clock starts with the first byte so the request round trip doesn't count
+            let mut first = [0u8; 1];
+            stream.read_exact(&mut first)?;
+            let start = Instant::now();
+            let mut body = Throttled::new(stream.take(size as u64 - 1), max_rate);
+            let received = io::copy(&mut body, &mut io::sink())?;
+            if received < size as u64 - 1 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            Ok(start.elapsed())
+        }
+        TestType::Upload => {
+            let start = Instant::now();
+            let chunk = [0u8; CHUNK_SIZE];
+            let mut remaining = size;
+            while remaining > 0 {
+                let len = remaining.min(CHUNK_SIZE);
+                throttle(max_rate, len);
+                stream.write_all(&chunk[..len])?;
+                remaining -= len;
+            }
+            let mut ack = [0u8; 1];
+            stream.read_exact(&mut ack)?;
+            Ok(start.elapsed())
+        }
+    }
+}
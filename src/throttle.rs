@@ -0,0 +1,88 @@
+use std::io::{self, Read};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Shared by all transfers of the process, so concurrent requests stay below the limit together
+static BUCKET: Mutex<Option<Bucket>> = Mutex::new(None);
+
+/// Bytes that may be sent in a burst after an idle period
+const BURST: Duration = Duration::from_millis(50);
+/// Chunk size, smaller chunks keep the rate smooth
+const CHUNK: usize = 16 * 1024;
+
+struct Bucket {
+    bytes_per_second: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// Waits until `bytes` may be transferred without exceeding `max_rate` megabits per second,
+/// returns right away without a limit
+pub fn throttle(max_rate: Option<f64>, bytes: usize) {
+    if let Some(mbit) = max_rate {
+        consume(mbit, bytes);
+    }
+}
+
+fn consume(mbit: f64, bytes: usize) {
+    let wait = {
+        let mut bucket = BUCKET.lock().unwrap();
+        let bytes_per_second = mbit * 1_000_000.0 / 8.0;
+        let bucket = match bucket.as_mut() {
+            Some(bucket) if bucket.bytes_per_second == bytes_per_second => bucket,
+            _ => bucket.insert(Bucket {
+                bytes_per_second,
+                tokens: 0.0,
+                refilled: Instant::now(),
+            }),
+        };
+        let now = Instant::now();
+        let capacity = bytes_per_second * BURST.as_secs_f64();
+        bucket.tokens = (bucket.tokens + (now - bucket.refilled).as_secs_f64() * bytes_per_second)
+            .min(capacity);
+        bucket.refilled = now;
+        // tokens may go negative, later transfers then wait for the debt as well
+        bucket.tokens -= bytes as f64;
+        Duration::from_secs_f64((-bucket.tokens).max(0.0) / bytes_per_second)
+    };
+    thread::sleep(wait);
+}
+
+/// Reader that is limited to `max_rate` megabits per second, unlimited if that is `None`
+pub struct Throttled<R> {
+    inner: R,
+    max_rate: Option<f64>,
+}
+
+impl<R: Read> Throttled<R> {
+    pub fn new(inner: R, max_rate: Option<f64>) -> Self {
+        Self { inner, max_rate }
+    }
+}
+
+impl<R: Read> Read for Throttled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(max_rate) = self.max_rate else {
+            return self.inner.read(buf);
+        };
+        let len = buf.len().min(CHUNK);
+        let read = self.inner.read(&mut buf[..len])?;
+        consume(max_rate, read);
+        Ok(read)
+    }
+}
+
+/// Parses a rate like 50mbps, 500kbps or 1gbps into megabits per second, plain numbers are
+/// megabits
+pub fn parse_rate(input: &str) -> Result<f64, String> {
+    let input = input.trim().to_lowercase();
+    let (number, factor) = [("gbps", 1000.0), ("mbps", 1.0), ("kbps", 0.001)]
+        .iter()
+        .find_map(|(unit, factor)| input.strip_suffix(unit).map(|number| (number, *factor)))
+        .unwrap_or((&input, 1.0));
+    match number.trim().parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate * factor),
+        _ => Err("expected a rate like 50mbps, 500kbps or 1gbps".to_string()),
+    }
+}
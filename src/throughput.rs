@@ -0,0 +1,210 @@
+use crate::measurements::{percentile, print_rows, Measurement};
+use crate::speedtest::TestType;
+use crate::OutputFormat;
+use serde::Serialize;
+use std::fmt::Display;
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Bytes are summed up over this interval, single reads are too short to give a useful rate
+const INTERVAL: Duration = Duration::from_millis(50);
+/// Smaller transfers are over before the congestion window could grow
+pub const SLOW_START_MIN_PAYLOAD: usize = 10_000_000;
+/// Transfers that spend more than this share ramping up are limited by the congestion window
+const WINDOW_LIMITED_SHARE: f64 = 0.25;
+
+/// Throughput of one interval of a transfer
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct ThroughputPoint {
+    /// End of the interval since the first chunk of the body
+    pub elapsed_ms: f64,
+    pub mbit: f64,
+}
+
+/// Collects the throughput of a transfer while its body is read
+#[derive(Debug, Default)]
+pub struct ThroughputSeries {
+    start: Option<Instant>,
+    interval_start: Option<Instant>,
+    interval_bytes: usize,
+    points: Vec<ThroughputPoint>,
+}
+
+impl ThroughputSeries {
+    fn record(&mut self, bytes: usize) {
+        let now = Instant::now();
+        let (Some(start), Some(interval_start)) = (self.start, self.interval_start) else {
+            // the first chunk only starts the clock, like for the measured speed
+            self.start = Some(now);
+            self.interval_start = Some(now);
+            return;
+        };
+        self.interval_bytes += bytes;
+        if now - interval_start >= INTERVAL {
+            self.push(start, interval_start, now);
+        }
+    }
+
+    fn push(&mut self, start: Instant, interval_start: Instant, now: Instant) {
+        let seconds = (now - interval_start).as_secs_f64();
+        self.points.push(ThroughputPoint {
+            elapsed_ms: (now - start).as_secs_f64() * 1_000.0,
+            mbit: self.interval_bytes as f64 * 8.0 / 1_000_000.0 / seconds,
+        });
+        self.interval_start = Some(now);
+        self.interval_bytes = 0;
+    }
+
+    /// Ends the last interval and returns all points
+    pub fn finish(mut self) -> Vec<ThroughputPoint> {
+        if let (Some(start), Some(interval_start)) = (self.start, self.interval_start) {
+            let now = Instant::now();
+            if self.interval_bytes > 0 && now > interval_start {
+                self.push(start, interval_start, now);
+            }
+        }
+        self.points
+    }
+}
+
+/// Series shared with a reader that is handed over to the HTTP client, `None` when not recording
+pub type SharedSeries = Option<Arc<Mutex<ThroughputSeries>>>;
+
+/// Starts a shared series if `record` is set
+pub fn shared_series(record: bool) -> SharedSeries {
+    record.then(|| Arc::new(Mutex::new(ThroughputSeries::default())))
+}
+
+/// Takes the points out of a shared series, empty if there is none
+pub fn take_points(series: &SharedSeries) -> Vec<ThroughputPoint> {
+    series
+        .as_ref()
+        .map(|series| std::mem::take(&mut *series.lock().unwrap()).finish())
+        .unwrap_or_default()
+}
+
+/// Reader that records the throughput of everything read through it
+pub struct Metered<R> {
+    inner: R,
+    series: SharedSeries,
+}
+
+impl<R: Read> Metered<R> {
+    pub fn new(inner: R, series: SharedSeries) -> Self {
+        Self { inner, series }
+    }
+}
+
+impl<R: Read> Read for Metered<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if let Some(series) = &self.series {
+            if read > 0 {
+                series.lock().unwrap().record(read);
+            }
+        }
+        Ok(read)
+    }
+}
+
+/// Time from the first chunk until the throughput first reached 90% of its peak, `None` for
+/// series too short to tell
+pub fn time_to_peak(points: &[ThroughputPoint]) -> Option<f64> {
+    if points.len() < 4 {
+        return None;
+    }
+    let peak = points.iter().map(|p| p.mbit).fold(0.0, f64::max);
+    points
+        .iter()
+        .find(|p| p.mbit >= peak * 0.9)
+        .map(|p| p.elapsed_ms)
+}
+
+/// How long the large transfers of one direction took to reach their peak throughput
+#[derive(Clone, Debug, Serialize)]
+pub struct SlowStart {
+    pub test_type: TestType,
+    /// Median time to 90% of the peak
+    pub ramp_ms: f64,
+    /// Median share of the transfer time spent ramping up
+    pub share: f64,
+}
+
+impl SlowStart {
+    /// `None` without large transfers of `test_type`
+    pub fn of(measurements: &[Measurement], test_type: TestType) -> Option<Self> {
+        let (mut ramps, mut shares): (Vec<f64>, Vec<f64>) = measurements
+            .iter()
+            .filter(|m| m.test_type == test_type && m.is_valid())
+            .filter_map(|m| {
+                let ramp_ms = m.ramp_ms?;
+                let transfer_ms = m.payload_size as f64 * 8.0 / 1_000.0 / m.mbit;
+                Some((ramp_ms, ramp_ms / transfer_ms))
+            })
+            .unzip();
+        if ramps.is_empty() {
+            return None;
+        }
+        ramps.sort_by(f64::total_cmp);
+        shares.sort_by(f64::total_cmp);
+        Some(Self {
+            test_type,
+            ramp_ms: percentile(&ramps, 50.0),
+            share: percentile(&shares, 50.0).min(1.0),
+        })
+    }
+
+    /// Whether the transfers spent long enough ramping up that the window, not the link, limits them
+    pub fn window_limited(&self) -> bool {
+        self.share > WINDOW_LIMITED_SHARE
+    }
+}
+
+impl Display for SlowStart {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?} reaches 90% of its peak after {:.0} ms ({:.0}% of a transfer), limited by {}",
+            self.test_type,
+            self.ramp_ms,
+            self.share * 100.0,
+            if self.window_limited() {
+                "congestion window growth"
+            } else {
+                "capacity"
+            }
+        )
+    }
+}
+
+/// One point of a sample's series for the machine readable output formats
+#[derive(Serialize)]
+struct ThroughputRow {
+    test_type: TestType,
+    payload_size: usize,
+    /// Index of the sample within all samples of the run
+    sample: usize,
+    elapsed_ms: f64,
+    mbit: f64,
+}
+
+/// Prints the series of all samples as one flat table, for --include-raw
+pub fn print_throughput(measurements: &[Measurement], output_format: OutputFormat) {
+    let rows: Vec<ThroughputRow> = measurements
+        .iter()
+        .enumerate()
+        .flat_map(|(sample, m)| {
+            m.throughput.iter().map(move |point| ThroughputRow {
+                test_type: m.test_type,
+                payload_size: m.payload_size,
+                sample,
+                elapsed_ms: point.elapsed_ms,
+                mbit: point.mbit,
+            })
+        })
+        .collect();
+    if !rows.is_empty() {
+        print_rows(&rows, "throughput", output_format);
+    }
+}
@@ -0,0 +1,47 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Keeps lines of concurrent requests from interleaving
+static WRITE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Timing of a single test request as written by --debug-timings. Connection setup isn't exposed
+/// by the HTTP client, it is part of `ttfb_ms`.
+#[derive(Debug, Serialize)]
+pub struct RequestTiming {
+    pub timestamp: DateTime<Utc>,
+    /// dns, latency, download or upload
+    pub kind: &'static str,
+    /// Requested URL, the looked up host for dns
+    pub url: String,
+    pub bytes: usize,
+    pub status: Option<u16>,
+    /// Time until the first body byte was received, for uploads until the body was being sent
+    pub ttfb_ms: Option<f64>,
+    /// Time spent transferring the body
+    pub body_ms: Option<f64>,
+    /// Time of the whole request including retries
+    pub total_ms: f64,
+    pub retries: u32,
+    pub error: Option<String>,
+}
+
+/// Appends `timing` as a JSON line to `path`, failures are only logged so they can't break a test
+pub fn record(path: &Path, timing: &RequestTiming) {
+    let Ok(mut line) = serde_json::to_string(timing) else {
+        return;
+    };
+    line.push('\n');
+    let _guard = WRITE_LOCK.lock().unwrap();
+    let written = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(line.as_bytes()));
+    if let Err(e) = written {
+        log::warn!("failed to write request timing to {}: {e}", path.display());
+    }
+}
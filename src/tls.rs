@@ -0,0 +1,105 @@
+use crate::preflight::EXPECTED_HOST;
+use crate::SpeedTestCLIOptions;
+use rustls::{
+    pki_types::ServerName, ClientConfig, ClientConnection, ProtocolVersion, RootCertStore,
+};
+use serde::Serialize;
+use std::{
+    fmt::Display,
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    sync::Arc,
+    time::Duration,
+};
+
+const TLS_PORT: u16 = 443;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// TLS parameters negotiated by a separate handshake with the test server
+#[derive(Clone, Debug, Serialize)]
+pub struct TlsDetails {
+    /// e.g. `TLS 1.3`
+    pub version: String,
+    /// IANA name of the cipher suite e.g. `TLS13_AES_256_GCM_SHA384`
+    pub cipher_suite: String,
+}
+
+impl TlsDetails {
+    /// TLS 1.2 usually means a middlebox terminates the connection, Cloudflare itself offers 1.3
+    pub fn is_legacy(&self) -> bool {
+        self.version != "TLS 1.3"
+    }
+}
+
+impl Display for TlsDetails {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.version, self.cipher_suite)
+    }
+}
+
+/// Performs a TLS handshake with speed.cloudflare.com and returns the negotiated parameters.
+/// reqwest does not expose the parameters of the test connections, so this is a separate probe
+/// with the same rustls defaults as the test client. It honours --resolve, --ipv4 and --ipv6.
+pub fn negotiate(options: &SpeedTestCLIOptions) -> Result<TlsDetails, String> {
+    let address = match options.resolve {
+        Some(ip) => SocketAddr::new(ip, TLS_PORT),
+        None => (EXPECTED_HOST, TLS_PORT)
+            .to_socket_addrs()
+            .map_err(|e| format!("failed to resolve {EXPECTED_HOST}: {e}"))?
+            .find(|address| match address {
+                SocketAddr::V4(_) => !options.ipv6,
+                SocketAddr::V6(_) => !options.ipv4,
+            })
+            .ok_or_else(|| format!("{EXPECTED_HOST} has no address of the selected IP family"))?,
+    };
+    let mut socket = TcpStream::connect_timeout(&address, HANDSHAKE_TIMEOUT)
+        .map_err(|e| format!("failed to connect to {address}: {e}"))?;
+    socket
+        .set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+        .and_then(|_| socket.set_write_timeout(Some(HANDSHAKE_TIMEOUT)))
+        .map_err(|e| e.to_string())?;
+
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+    let server_name = ServerName::try_from(EXPECTED_HOST).map_err(|e| e.to_string())?;
+    let mut connection =
+        ClientConnection::new(Arc::new(config), server_name).map_err(|e| e.to_string())?;
+    while connection.is_handshaking() {
+        connection
+            .complete_io(&mut socket)
+            .map_err(|e| format!("TLS handshake failed: {e}"))?;
+    }
+
+    let version = connection
+        .protocol_version()
+        .map(format_version)
+        .ok_or("no TLS version negotiated")?;
+    let cipher_suite = connection
+        .negotiated_cipher_suite()
+        .map(|suite| {
+            let suite = suite.suite();
+            suite
+                .as_str()
+                .map(str::to_owned)
+                .unwrap_or_else(|| format!("{suite:?}"))
+        })
+        .ok_or("no cipher suite negotiated")?;
+    Ok(TlsDetails {
+        version,
+        cipher_suite,
+    })
+}
+
+fn format_version(version: ProtocolVersion) -> String {
+    match version {
+        ProtocolVersion::TLSv1_3 => "TLS 1.3".to_string(),
+        ProtocolVersion::TLSv1_2 => "TLS 1.2".to_string(),
+        other => format!("{other:?}"),
+    }
+}
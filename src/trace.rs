@@ -0,0 +1,114 @@
+use crate::metadata::anonymize_addr;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::net::IpAddr;
+
+const MAX_HOPS: u32 = 30;
+const PROBES_PER_HOP: u32 = 3;
+/// Firewalls often drop the probes for good, so the trace ends after this many silent hops
+const MAX_SILENT_HOPS: u32 = 5;
+
+/// Router on the path to the test server
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Hop {
+    pub ttl: u32,
+    /// Router that answered, `None` if no probe was answered
+    pub address: Option<IpAddr>,
+    /// Round trip times of the answered probes
+    pub rtts_ms: Vec<f64>,
+}
+
+/// Routers between this machine and the test server found by probes with an increasing TTL
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PathTrace {
+    pub target: IpAddr,
+    pub hops: Vec<Hop>,
+    /// Whether the last hop is the test server itself
+    pub reached: bool,
+}
+
+impl PathTrace {
+    /// Masks the router addresses, they reveal the local and ISP network
+    pub fn anonymize(&mut self) {
+        for hop in &mut self.hops {
+            hop.address = hop.address.map(anonymize_addr);
+        }
+    }
+}
+
+impl Display for PathTrace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Path to {}", self.target)?;
+        for hop in &self.hops {
+            write!(f, "\n{:>3}  ", hop.ttl)?;
+            match hop.address {
+                Some(address) => write!(f, "{address:<39}")?,
+                None => write!(f, "{:<39}", "*")?,
+            }
+            for rtt in &hop.rtts_ms {
+                write!(f, " {rtt:>8.2} ms")?;
+            }
+        }
+        if !self.reached {
+            write!(f, "\n(the test server did not answer)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Sends ICMP echo requests with an increasing TTL to `target` until it answers
+#[cfg(unix)]
+pub fn trace_path(target: IpAddr) -> Result<PathTrace, String> {
+    use crate::icmp::imp;
+    use std::time::Instant;
+
+    let socket = imp::open(target)?;
+    let token = std::process::id().to_be_bytes();
+    let mut hops = Vec::new();
+    let mut reached = false;
+    let mut silent_hops = 0;
+    let mut seq: u16 = 0;
+    for ttl in 1..=MAX_HOPS {
+        imp::set_hop_limit(&socket, target, ttl)
+            .map_err(|e| format!("failed to set the TTL: {e}"))?;
+        let mut hop = Hop {
+            ttl,
+            address: None,
+            rtts_ms: Vec::new(),
+        };
+        for _ in 0..PROBES_PER_HOP {
+            seq = seq.wrapping_add(1);
+            let start = Instant::now();
+            let reply = imp::echo(&socket, target, seq, &token)
+                .map_err(|e| format!("probe with TTL {ttl} failed: {e}"))?;
+            if let Some(reply) = reply {
+                hop.rtts_ms.push(start.elapsed().as_secs_f64() * 1000.0);
+                hop.address.get_or_insert(reply.from);
+                reached |= reply.reached;
+            }
+        }
+        silent_hops = if hop.address.is_some() {
+            0
+        } else {
+            silent_hops + 1
+        };
+        hops.push(hop);
+        if reached || silent_hops == MAX_SILENT_HOPS {
+            break;
+        }
+    }
+    // the trailing silent hops carry no information
+    while hops.last().is_some_and(|hop| hop.address.is_none()) {
+        hops.pop();
+    }
+    Ok(PathTrace {
+        target,
+        hops,
+        reached,
+    })
+}
+
+#[cfg(not(unix))]
+pub fn trace_path(_target: IpAddr) -> Result<PathTrace, String> {
+    Err("path tracing is only supported on unix".to_string())
+}
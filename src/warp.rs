@@ -0,0 +1,138 @@
+use crate::measurements::{calc_jitter, headline_mbit};
+use crate::speedtest::{speed_test, SpeedTestResult, TestType};
+use crate::SpeedTestCLIOptions;
+use reqwest::blocking::Client;
+use std::io::BufRead;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+/// Time the tunnel gets to come up or down after `warp-cli` returned
+const SETTLE_TIME: Duration = Duration::from_secs(5);
+
+/// Runs the test suite with WARP in its current state and again with WARP toggled, then prints
+/// both results side by side. The toggle uses `warp-cli` if it is installed unless `manual` is
+/// set, otherwise the user is asked to toggle WARP. A new client is used for every run so no
+/// pooled connection outlives the toggle.
+pub fn run_warp_comparison(
+    new_client: impl Fn() -> Client,
+    options: SpeedTestCLIOptions,
+    manual: bool,
+) -> Result<(), String> {
+    let first = speed_test(new_client(), options.clone());
+    let first_on = warp_state(&first)?;
+    let target = if first_on { "off" } else { "on" };
+
+    let automatic = !manual && warp_cli_available();
+    if automatic {
+        println!("\nTurning WARP {target} with warp-cli");
+        set_warp(!first_on)?;
+        thread::sleep(SETTLE_TIME);
+    } else {
+        println!("\nTurn WARP {target} and press Enter to run the second test");
+        std::io::stdin()
+            .lock()
+            .read_line(&mut String::new())
+            .map_err(|e| format!("failed to read from stdin: {e}"))?;
+    }
+
+    // the WARP status comes from the metadata, a cached copy would still show the old state
+    let second_options = SpeedTestCLIOptions {
+        refresh_metadata: true,
+        ..options
+    };
+    let second = speed_test(new_client(), second_options);
+    if automatic {
+        if let Err(e) = set_warp(first_on) {
+            log::warn!("failed to restore the WARP state: {e}");
+        }
+    }
+    let second_on = warp_state(&second)?;
+    if first_on == second_on {
+        return Err(format!(
+            "WARP was {} during both runs, nothing to compare",
+            if first_on { "on" } else { "off" }
+        ));
+    }
+
+    let (off, on) = if first_on {
+        (&second, &first)
+    } else {
+        (&first, &second)
+    };
+    print_comparison(off, on);
+    Ok(())
+}
+
+fn warp_state(result: &SpeedTestResult) -> Result<bool, String> {
+    if result.metadata.warp.is_none() {
+        return Err("failed to detect whether WARP is active".to_string());
+    }
+    Ok(result.metadata.warp_active())
+}
+
+fn warp_cli_available() -> bool {
+    Command::new("warp-cli")
+        .arg("--version")
+        .output()
+        .is_ok_and(|output| output.status.success())
+}
+
+fn set_warp(on: bool) -> Result<(), String> {
+    let action = if on { "connect" } else { "disconnect" };
+    let output = Command::new("warp-cli")
+        .arg(action)
+        .output()
+        .map_err(|e| format!("failed to run warp-cli {action}: {e}"))?;
+    if !output.status.success() {
+        return Err(format!(
+            "warp-cli {action} failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+fn print_comparison(off: &SpeedTestResult, on: &SpeedTestResult) {
+    println!("\nWARP comparison");
+    println!(
+        "{:<10} {:>14} {:>14} {:>10}",
+        "", "WARP off", "WARP on", "change"
+    );
+    let mbit = |result: &SpeedTestResult, test_type| headline_mbit(&result.measurements, test_type);
+    print_row(
+        "Download",
+        "Mbit/s",
+        mbit(off, TestType::Download),
+        mbit(on, TestType::Download),
+    );
+    print_row(
+        "Upload",
+        "Mbit/s",
+        mbit(off, TestType::Upload),
+        mbit(on, TestType::Upload),
+    );
+    let latency = |result: &SpeedTestResult| {
+        Some(result.latency.avg_latency).filter(|latency| latency.is_finite())
+    };
+    print_row("Latency", "ms", latency(off), latency(on));
+    let jitter = |result: &SpeedTestResult| calc_jitter(&result.latency.latencies());
+    print_row("Jitter", "ms", jitter(off), jitter(on));
+    println!(
+        "{:<10} {:>14} {:>14}",
+        "Server", off.metadata.colo, on.metadata.colo
+    );
+}
+
+fn print_row(name: &str, unit: &str, off: Option<f64>, on: Option<f64>) {
+    let format = |value: Option<f64>| value.map_or("-".to_string(), |v| format!("{v:.2} {unit}"));
+    let change = match (off, on) {
+        (Some(off), Some(on)) if off > 0.0 => format!("{:+.1}%", (on - off) / off * 100.0),
+        _ => "-".to_string(),
+    };
+    println!(
+        "{name:<10} {:>14} {:>14} {change:>10}",
+        format(off),
+        format(on)
+    );
+}
@@ -0,0 +1,18 @@
+use cfspeedtest::exporter::{spawn_exporter, Metrics};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+
+#[test]
+fn routes_ignore_the_query_string() {
+    // a free port, released again for the exporter
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    spawn_exporter(addr, Arc::new(Mutex::new(Metrics::default()))).unwrap();
+
+    for path in ["/metrics?ts=1", "/healthz?probe=liveness"] {
+        let response = reqwest::blocking::get(format!("http://{addr}{path}")).unwrap();
+        assert_eq!(response.status(), 200, "{path}");
+    }
+}
@@ -0,0 +1,124 @@
+use cfspeedtest::history::HistoryRecord;
+use cfspeedtest::integrations::influx::{line_protocol, InfluxWriter};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+/// Answers every request with the next of `statuses` and passes on its request line and body
+fn stub_influx(statuses: Vec<u16>) -> (SocketAddr, Receiver<(String, String)>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let (requests, received) = mpsc::channel();
+    thread::spawn(move || {
+        for (stream, status) in listener.incoming().zip(statuses) {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut content_length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            write!(
+                stream,
+                "HTTP/1.1 {status} \r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+            )
+            .unwrap();
+            let body = String::from_utf8(body).unwrap();
+            requests.send((request_line, body)).unwrap();
+        }
+    });
+    (address, received)
+}
+
+fn record(download_mbit: f64) -> HistoryRecord {
+    let mut record: HistoryRecord = serde_json::from_value(serde_json::json!({
+        "id": 0,
+        "timestamp": "2024-01-01T00:00:00Z",
+        "source": "cfspeedtest",
+    }))
+    .unwrap();
+    record.download_mbit = Some(download_mbit);
+    record
+}
+
+#[test]
+fn write_url_keeps_the_path_prefix() {
+    let (address, requests) = stub_influx(vec![204]);
+    let writer = InfluxWriter::new(
+        &format!("http://{address}/influx"),
+        "org",
+        "bucket",
+        "token",
+    )
+    .unwrap();
+
+    writer.write(&record(100.0)).unwrap();
+
+    let (request_line, _) = requests.recv().unwrap();
+    assert!(
+        request_line.starts_with("POST /influx/api/v2/write?"),
+        "{request_line}"
+    );
+}
+
+#[test]
+fn rejected_points_are_dropped() {
+    let (address, requests) = stub_influx(vec![400, 204]);
+    let writer = InfluxWriter::new(&format!("http://{address}"), "org", "bucket", "token").unwrap();
+
+    assert!(writer.write(&record(100.0)).is_err());
+    writer.write(&record(200.0)).unwrap();
+
+    let (_, rejected) = requests.recv().unwrap();
+    assert!(rejected.contains("download_mbit=100"), "{rejected}");
+    let (_, written) = requests.recv().unwrap();
+    assert_eq!(written.lines().count(), 1, "{written}");
+    assert!(written.contains("download_mbit=200"), "{written}");
+}
+
+#[test]
+fn unavailable_points_are_kept() {
+    // three attempts of the first write, then the second write sends both points
+    let (address, requests) = stub_influx(vec![503, 503, 503, 204]);
+    let writer = InfluxWriter::new(&format!("http://{address}"), "org", "bucket", "token").unwrap();
+
+    assert!(writer.write(&record(100.0)).is_err());
+    writer.write(&record(200.0)).unwrap();
+
+    let (_, written) = requests.iter().nth(3).unwrap();
+    assert_eq!(written.lines().count(), 2, "{written}");
+}
+
+#[test]
+fn line_protocol_skips_non_finite_fields() {
+    let mut record = record(100.0);
+    record.latency_ms = Some(f64::NAN);
+    record.upload_mbit = Some(f64::INFINITY);
+
+    let line = line_protocol(&record).unwrap();
+
+    assert_eq!(
+        line,
+        "cfspeedtest,source=cfspeedtest download_mbit=100 1704067200"
+    );
+}
+
+#[test]
+fn line_protocol_skips_points_without_fields() {
+    let record = record(f64::NAN);
+
+    assert_eq!(line_protocol(&record), None);
+}
@@ -0,0 +1,202 @@
+use cfspeedtest::measurements::headline_mbit;
+use cfspeedtest::mock::{MockBackend, MockConfig};
+use cfspeedtest::speedtest::{speed_test, test_upload, Endpoint, PayloadSize, TestType};
+use cfspeedtest::{OutputFormat, SpeedTestCLIOptions};
+use reqwest::blocking::Client;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::process::Command;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const SETUP_DELAY: Duration = Duration::from_millis(200);
+
+/// Resolves every name to the mock backend after `SETUP_DELAY`, standing in for a slow
+/// connection setup
+struct SlowResolver(SocketAddr);
+
+impl Resolve for SlowResolver {
+    fn resolve(&self, _: Name) -> Resolving {
+        let addr = self.0;
+        Box::pin(async move {
+            thread::sleep(SETUP_DELAY);
+            Ok(Box::new(std::iter::once(addr)) as Addrs)
+        })
+    }
+}
+
+fn options(backend: &MockBackend) -> SpeedTestCLIOptions {
+    let mut options = SpeedTestCLIOptions {
+        output_format: OutputFormat::None,
+        nr_tests: 4,
+        nr_latency_tests: 10,
+        ..Default::default()
+    };
+    backend.apply(&mut options);
+    options
+}
+
+#[test]
+fn upload_ttfb_includes_connection_setup() {
+    let backend = MockBackend::start(MockConfig::default()).unwrap();
+    let mut options = options(&backend);
+    options.endpoint = Endpoint::Custom {
+        download: None,
+        upload: Some(format!(
+            "http://mock.test:{}/__up",
+            backend.address().port()
+        )),
+    };
+    let client = Client::builder()
+        .dns_resolver(Arc::new(SlowResolver(backend.address())))
+        .build()
+        .unwrap();
+
+    let measurement = test_upload(&client, 1_000_000, &options).unwrap();
+
+    // the body is read ahead before connecting, a clock started then would give a TTFB near 0
+    assert!(
+        measurement.ttfb_ms >= SETUP_DELAY.as_millis() as f64,
+        "ttfb {:.1}ms doesn't include the connection setup",
+        measurement.ttfb_ms
+    );
+    assert!(
+        (40.0..60.0).contains(&measurement.mbit),
+        "{:.1} mbit/s on a 50 mbit/s link",
+        measurement.mbit
+    );
+}
+
+#[test]
+fn speed_test_measures_the_mock_link() {
+    let config = MockConfig::default();
+    let backend = MockBackend::start(config.clone()).unwrap();
+    let mut options = options(&backend);
+    options.max_payload_size = PayloadSize::M1;
+
+    let result = speed_test(Client::new(), options);
+
+    // 100KB and 1MB, 4 samples each way
+    assert_eq!(result.measurements.len(), 16);
+    assert_eq!(result.failed_measurements, 0);
+    let download = headline_mbit(&result.measurements, TestType::Download).unwrap();
+    assert!(
+        (0.8 * config.download_mbit..1.1 * config.download_mbit).contains(&download),
+        "{download:.1} mbit/s download on a {} mbit/s link",
+        config.download_mbit
+    );
+    let upload = headline_mbit(&result.measurements, TestType::Upload).unwrap();
+    assert!(
+        (0.8 * config.upload_mbit..1.2 * config.upload_mbit).contains(&upload),
+        "{upload:.1} mbit/s upload on a {} mbit/s link",
+        config.upload_mbit
+    );
+    let latency_ms = result.latency.avg_latency;
+    assert!(
+        latency_ms >= config.latency.as_secs_f64() * 1000.0,
+        "{latency_ms:.1}ms latency"
+    );
+}
+
+/// Runs the binary against `backend` and parses its output, which has to be a single JSON value
+fn run_json(backend: &MockBackend, args: &[&str]) -> Value {
+    let output = Command::new(env!("CARGO_BIN_EXE_cfspeedtest"))
+        .args([
+            "--download-url",
+            &format!("http://{}/__down?bytes={{bytes}}", backend.address()),
+            "--upload-url",
+            &format!("http://{}/__up", backend.address()),
+            "--max-payload-size",
+            "100k",
+            "--nr-tests",
+            "4",
+            "--output-format",
+            "json",
+        ])
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+#[test]
+fn json_output_includes_latency_percentiles() {
+    let backend = MockBackend::start(MockConfig::default()).unwrap();
+
+    let output = run_json(&backend, &[]);
+
+    let percentiles = &output["latency"]["percentiles"];
+    for percentile in ["p50", "p90", "p99", "p99.9"] {
+        let latency_ms = percentiles[percentile].as_f64().unwrap();
+        // the mock delays every download response by 20ms
+        assert!(latency_ms >= 20.0, "{percentile} {latency_ms}ms");
+    }
+    assert!(output["latency"]["avg_ms"].as_f64().unwrap() >= 20.0);
+    assert_eq!(output["measurements"].as_array().unwrap().len(), 2);
+}
+
+#[test]
+fn json_output_includes_bufferbloat_grade() {
+    let backend = MockBackend::start(MockConfig::default()).unwrap();
+
+    let output = run_json(&backend, &["--bufferbloat"]);
+
+    let bufferbloat = &output["bufferbloat"];
+    assert!(bufferbloat["grade"].is_string(), "{bufferbloat}");
+    assert!(bufferbloat["idle_ms"].as_f64().unwrap() >= 20.0);
+}
+
+#[test]
+fn json_output_includes_meta() {
+    let backend = MockBackend::start(MockConfig::default()).unwrap();
+
+    let output = run_json(&backend, &["--meta", "device=router-1"]);
+
+    assert_eq!(output["meta"]["device"], "router-1");
+}
+
+#[test]
+fn json_output_includes_metadata() {
+    let backend = MockBackend::start(MockConfig::default()).unwrap();
+
+    let output = run_json(&backend, &[]);
+
+    let metadata = output["metadata"].as_object().unwrap();
+    for key in [
+        "colo",
+        "ip",
+        "asn",
+        "as_organization",
+        "isp",
+        "colo_location",
+        "tls_probe",
+    ] {
+        assert!(metadata.contains_key(key), "{key} missing in {metadata:?}");
+    }
+}
+
+#[test]
+fn json_output_includes_path_rtts() {
+    let backend = MockBackend::start(MockConfig::default()).unwrap();
+
+    let output = run_json(&backend, &["--trace-path"]);
+
+    let path = &output["path"];
+    // tracing needs ICMP sockets, which unprivileged users may not be allowed to open
+    if !path.is_null() {
+        let hop = &path["hops"][0];
+        assert_eq!(hop["address"], "127.0.0.1");
+        assert!(!hop["rtts_ms"].as_array().unwrap().is_empty(), "{path}");
+    }
+}
+
+#[test]
+fn json_output_marks_prewarmed_runs() {
+    let backend = MockBackend::start(MockConfig::default()).unwrap();
+
+    assert_eq!(run_json(&backend, &[])["prewarmed"], false);
+    assert_eq!(run_json(&backend, &["--prewarm"])["prewarmed"], true);
+}
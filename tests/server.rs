@@ -0,0 +1,45 @@
+use cfspeedtest::server::run_server;
+use cfspeedtest::SpeedTestCLIOptions;
+use reqwest::blocking::Client;
+use std::net::{SocketAddr, TcpListener};
+use std::thread;
+use std::time::Duration;
+
+/// Serves the API from a background thread with its history in the temp directory
+fn start_server() -> SocketAddr {
+    // a free port, released again for the server
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let options = SpeedTestCLIOptions {
+        history_file: Some(std::env::temp_dir().join(format!("cfspeedtest-{}.jsonl", addr.port()))),
+        ..Default::default()
+    };
+    thread::spawn(move || run_server(Client::new(), options, addr));
+    for _ in 0..50 {
+        if Client::new()
+            .get(format!("http://{addr}/healthz"))
+            .send()
+            .is_ok()
+        {
+            return addr;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("server didn't start on {addr}");
+}
+
+#[test]
+fn run_rejects_too_many_latency_tests() {
+    let addr = start_server();
+
+    let response = Client::new()
+        .post(format!("http://{addr}/run"))
+        .body(r#"{"nr_latency_tests": 4000000000}"#)
+        .send()
+        .unwrap();
+
+    assert_eq!(response.status(), 400);
+    assert!(response.text().unwrap().contains("nr_latency_tests"));
+}